@@ -44,7 +44,7 @@ pub fn make_analysis<'a>() -> NodePool<'a, ExpressionLattice> {
     NodePool::new(
         true,
         Box::new(|_label, ir, lattice| match ir {
-            IR::Let(_, _var, op, args, _body) => ExpressionLattice::join(
+            IR::Let(_, _var, op, args, _body) if op.is_pure() => ExpressionLattice::join(
                 &lattice,
                 &ExpressionLattice::from(op.clone(), args.clone()),
             ),
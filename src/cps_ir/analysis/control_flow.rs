@@ -0,0 +1,350 @@
+// Control-Flow Analysis (k-CFA), which lambdas and continuations may flow to
+// every variable and every call site.
+// Contexts are call strings of the last k call sites, k = 0 gives 0-CFA.
+// Variables are identified by their names: binders sharing a name are merged,
+// which is less precise but stays sound.
+// Values stored into tuples, constructors, references or arrays flow into a single
+// abstract heap, every read of a field or a cell may produce any of them.
+
+// the continuation a function returns to and the handler it raises to are bound like
+// variables, under the names the interpreter uses
+use crate::cps_ir::interp::{HANDLER_CONT, RETURN_CONT};
+use crate::cps_ir::{Atom, Cont, IR, builtin_call::BuiltinOp};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+type Context = Vec<usize>;
+// the context each variable in scope was bound in
+type BEnv<'a> = BTreeMap<&'a str, Context>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum AbsVal<'a> {
+    Clo(usize, BEnv<'a>),
+    Cont(&'a str, BEnv<'a>),
+    // leaving the program, either by returning or by an uncaught exception
+    Halt,
+    Basic,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AbstractValue {
+    Lam(usize),
+    Cont(String),
+    Basic,
+}
+
+pub struct ControlFlow {
+    callees: HashMap<usize, HashSet<usize>>,
+    cont_calls: HashSet<usize>,
+    values: HashMap<String, HashSet<AbstractValue>>,
+}
+
+impl ControlFlow {
+    // labels of the lambdas possibly called at the App labeled `label`
+    pub fn callees(&self, label: usize) -> HashSet<usize> {
+        self.callees.get(&label).cloned().unwrap_or_default()
+    }
+
+    // whether the App labeled `label` may invoke a continuation captured as a value
+    pub fn may_call_cont(&self, label: usize) -> bool {
+        self.cont_calls.contains(&label)
+    }
+
+    pub fn values(&self, var: &str) -> HashSet<AbstractValue> {
+        self.values.get(var).cloned().unwrap_or_default()
+    }
+
+    // the callees of every reached call site which only calls lambdas
+    pub fn call_graph(&self) -> HashMap<usize, HashSet<usize>> {
+        self.callees
+            .iter()
+            .filter(|(label, _)| !self.cont_calls.contains(label))
+            .map(|(label, callees)| (*label, callees.clone()))
+            .collect()
+    }
+}
+
+struct Cfa<'a> {
+    k: usize,
+    lams: HashMap<usize, (&'a Vec<String>, &'a IR)>,
+    conts: HashMap<&'a str, (&'a Vec<String>, &'a IR)>,
+    store: HashMap<(&'a str, Context), HashSet<AbsVal<'a>>>,
+    heap: HashSet<AbsVal<'a>>,
+    // states are identified by the label of their IR
+    seen: HashSet<(usize, BEnv<'a>, Context)>,
+    states: Vec<(&'a IR, BEnv<'a>, Context)>,
+    changed: bool,
+    callees: HashMap<usize, HashSet<usize>>,
+    cont_calls: HashSet<usize>,
+}
+
+impl<'a> Cfa<'a> {
+    fn collect_atom(&mut self, atom: &'a Atom) {
+        if let Atom::Lam(label, args, body) = atom {
+            self.lams.insert(*label, (args, body));
+            self.collect(body);
+        }
+    }
+
+    fn collect(&mut self, ir: &'a IR) {
+        match ir {
+            IR::LetCont(_, name, args, cont_body, body) => {
+                self.conts.insert(name, (args, cont_body));
+                self.collect(cont_body);
+                self.collect(body);
+            }
+            IR::Let(_, _, _, args, body) => {
+                args.iter().for_each(|arg| self.collect_atom(arg));
+                self.collect(body);
+            }
+            IR::LetVal(_, _, val, body) => {
+                self.collect_atom(val);
+                self.collect(body);
+            }
+            IR::If(_, test, then_, else_) => {
+                self.collect_atom(test);
+                self.collect(then_);
+                self.collect(else_);
+            }
+            IR::App(_, f, args, _, _) => {
+                self.collect_atom(f);
+                args.iter().for_each(|arg| self.collect_atom(arg));
+            }
+            IR::Fix(_, _, vals, body) => {
+                vals.iter().for_each(|val| self.collect_atom(val));
+                self.collect(body);
+            }
+            IR::AppCont(_, _, args) => args.iter().for_each(|arg| self.collect_atom(arg)),
+            IR::Case(_, scrutinee, _, _) => self.collect_atom(scrutinee),
+        }
+    }
+
+    fn lookup(&self, var: &str, benv: &BEnv<'a>) -> HashSet<AbsVal<'a>> {
+        match benv.get_key_value(var) {
+            Some((var, ctx)) => self
+                .store
+                .get(&(*var, ctx.clone()))
+                .cloned()
+                .unwrap_or_default(),
+            None => HashSet::new(),
+        }
+    }
+
+    fn bind(&mut self, var: &'a str, ctx: &Context, values: HashSet<AbsVal<'a>>) {
+        let entry = self.store.entry((var, ctx.clone())).or_default();
+        for val in values {
+            self.changed |= entry.insert(val);
+        }
+    }
+
+    fn push(&mut self, ir: &'a IR, benv: BEnv<'a>, ctx: Context) {
+        if self
+            .seen
+            .insert((ir.get_label(), benv.clone(), ctx.clone()))
+        {
+            self.changed = true;
+            self.states.push((ir, benv, ctx));
+        }
+    }
+
+    fn eval(&self, atom: &'a Atom, benv: &BEnv<'a>) -> HashSet<AbsVal<'a>> {
+        match atom {
+            Atom::Var(v) | Atom::Cont(v) => self.lookup(v, benv),
+            Atom::Lam(label, _, _) => HashSet::from([AbsVal::Clo(*label, benv.clone())]),
+            _ => HashSet::from([AbsVal::Basic]),
+        }
+    }
+
+    fn eval_cont(&self, cont: &'a Cont, benv: &BEnv<'a>) -> HashSet<AbsVal<'a>> {
+        match cont {
+            Cont::Named(name) => self.lookup(name, benv),
+            Cont::Return => self.lookup(RETURN_CONT, benv),
+            Cont::Handler => self.lookup(HANDLER_CONT, benv),
+        }
+    }
+
+    // parameters without a corresponding argument receive anything from the heap
+    fn apply_cont(&mut self, cont: &AbsVal<'a>, args: &[HashSet<AbsVal<'a>>], ctx: &Context) {
+        let AbsVal::Cont(name, cont_benv) = cont else {
+            return;
+        };
+        let Some((params, body)) = self.conts.get(name).copied() else {
+            return;
+        };
+        let mut new_benv = cont_benv.clone();
+        for (i, param) in params.iter().enumerate() {
+            let values = match args.get(i) {
+                Some(values) => values.clone(),
+                None => self.heap_read(),
+            };
+            self.bind(param, ctx, values);
+            new_benv.insert(param, ctx.clone());
+        }
+        self.push(body, new_benv, ctx.clone());
+    }
+
+    fn heap_read(&self) -> HashSet<AbsVal<'a>> {
+        let mut values = self.heap.clone();
+        values.insert(AbsVal::Basic);
+        values
+    }
+
+    fn step(&mut self, ir: &'a IR, benv: BEnv<'a>, ctx: Context) {
+        match ir {
+            IR::LetCont(_, name, _, _, body) => {
+                // the continuation is recursive, its body sees its own binding
+                let mut new_benv = benv;
+                new_benv.insert(name, ctx.clone());
+                self.bind(
+                    name,
+                    &ctx,
+                    HashSet::from([AbsVal::Cont(name, new_benv.clone())]),
+                );
+                self.push(body, new_benv, ctx);
+            }
+            IR::Let(_, var, op, args, body) => {
+                let values: Vec<_> = args.iter().flat_map(|arg| self.eval(arg, &benv)).collect();
+                let result = match op {
+                    BuiltinOp::Tuple
+                    | BuiltinOp::Construct(_)
+                    | BuiltinOp::Ref
+                    | BuiltinOp::Assign
+                    | BuiltinOp::ArrayAlloc
+                    | BuiltinOp::ArraySet => {
+                        for val in values.into_iter().filter(|val| val != &AbsVal::Basic) {
+                            self.changed |= self.heap.insert(val);
+                        }
+                        HashSet::from([AbsVal::Basic])
+                    }
+                    BuiltinOp::Proj(_) | BuiltinOp::Deref | BuiltinOp::ArrayGet => self.heap_read(),
+                    _ => HashSet::from([AbsVal::Basic]),
+                };
+                self.bind(var, &ctx, result);
+                let mut new_benv = benv;
+                new_benv.insert(var, ctx.clone());
+                self.push(body, new_benv, ctx);
+            }
+            IR::LetVal(_, var, val, body) => {
+                let values = self.eval(val, &benv);
+                self.bind(var, &ctx, values);
+                let mut new_benv = benv;
+                new_benv.insert(var, ctx.clone());
+                self.push(body, new_benv, ctx);
+            }
+            IR::If(_, _, then_, else_) => {
+                self.push(then_, benv.clone(), ctx.clone());
+                self.push(else_, benv, ctx);
+            }
+            IR::App(label, f, args, cont, handler) => {
+                let args_values: Vec<_> = args.iter().map(|arg| self.eval(arg, &benv)).collect();
+                let return_values = self.eval_cont(cont, &benv);
+                let handler_values = self.eval_cont(handler, &benv);
+                self.callees.entry(*label).or_default();
+                for f_value in self.eval(f, &benv) {
+                    match f_value {
+                        AbsVal::Clo(lam, clo_benv) => {
+                            self.callees.entry(*label).or_default().insert(lam);
+                            let (params, body) = self.lams[&lam];
+                            let mut new_ctx = ctx.clone();
+                            new_ctx.push(*label);
+                            if new_ctx.len() > self.k {
+                                new_ctx.remove(0);
+                            }
+                            let mut new_benv = clo_benv;
+                            for (param, values) in params.iter().zip(&args_values) {
+                                self.bind(param, &new_ctx, values.clone());
+                                new_benv.insert(param, new_ctx.clone());
+                            }
+                            self.bind(RETURN_CONT, &new_ctx, return_values.clone());
+                            self.bind(HANDLER_CONT, &new_ctx, handler_values.clone());
+                            new_benv.insert(RETURN_CONT, new_ctx.clone());
+                            new_benv.insert(HANDLER_CONT, new_ctx.clone());
+                            self.push(body, new_benv, new_ctx);
+                        }
+                        AbsVal::Cont(..) => {
+                            self.cont_calls.insert(*label);
+                            self.apply_cont(&f_value, &args_values, &ctx);
+                        }
+                        AbsVal::Halt | AbsVal::Basic => (),
+                    }
+                }
+            }
+            IR::Fix(_, vars, vals, body) => {
+                let mut new_benv = benv;
+                for var in vars {
+                    new_benv.insert(var, ctx.clone());
+                }
+                for (var, val) in vars.iter().zip(vals) {
+                    let values = self.eval(val, &new_benv);
+                    self.bind(var, &ctx, values);
+                }
+                self.push(body, new_benv, ctx);
+            }
+            IR::Case(_, _, branches, default) => {
+                for cont in branches.iter().map(|(_tag, cont)| cont).chain(default) {
+                    for cont_value in self.eval_cont(cont, &benv) {
+                        self.apply_cont(&cont_value, &[], &ctx);
+                    }
+                }
+            }
+            IR::AppCont(_, cont, args) => {
+                let args_values: Vec<_> = args.iter().map(|arg| self.eval(arg, &benv)).collect();
+                for cont_value in self.eval_cont(cont, &benv) {
+                    self.apply_cont(&cont_value, &args_values, &ctx);
+                }
+            }
+        }
+    }
+}
+
+pub fn analyze(ir: &IR, k: usize) -> ControlFlow {
+    let mut cfa = Cfa {
+        k,
+        lams: HashMap::new(),
+        conts: HashMap::new(),
+        store: HashMap::new(),
+        heap: HashSet::new(),
+        seen: HashSet::new(),
+        states: vec![],
+        changed: false,
+        callees: HashMap::new(),
+        cont_calls: HashSet::new(),
+    };
+    cfa.collect(ir);
+
+    let top = vec![];
+    cfa.bind(RETURN_CONT, &top, HashSet::from([AbsVal::Halt]));
+    cfa.bind(HANDLER_CONT, &top, HashSet::from([AbsVal::Halt]));
+    let benv = BEnv::from([(RETURN_CONT, top.clone()), (HANDLER_CONT, top.clone())]);
+    cfa.push(ir, benv, top);
+
+    // states are revisited until neither the store nor the set of states grows
+    while cfa.changed {
+        cfa.changed = false;
+        let mut i = 0;
+        while i < cfa.states.len() {
+            let (ir, benv, ctx) = cfa.states[i].clone();
+            cfa.step(ir, benv, ctx);
+            i += 1;
+        }
+    }
+
+    let mut values: HashMap<String, HashSet<AbstractValue>> = HashMap::new();
+    for ((var, _ctx), abs_values) in &cfa.store {
+        if var.starts_with('%') {
+            continue;
+        }
+        let entry = values.entry(var.to_string()).or_default();
+        for val in abs_values {
+            entry.insert(match val {
+                AbsVal::Clo(label, _) => AbstractValue::Lam(*label),
+                AbsVal::Cont(name, _) => AbstractValue::Cont(name.to_string()),
+                AbsVal::Halt | AbsVal::Basic => AbstractValue::Basic,
+            });
+        }
+    }
+    ControlFlow {
+        callees: cfa.callees,
+        cont_calls: cfa.cont_calls,
+        values,
+    }
+}
@@ -0,0 +1,254 @@
+// Escape Analysis of closures and continuations.
+// A closure escapes when it may be used other than by being called: passed to a
+// function, stored by an operation, returned, or captured by an escaping closure or
+// continuation.
+// Copies by LetVal and jumps to local continuations only rename it.
+// A continuation escapes when it is captured as a value, passed to a function as its
+// return or handler continuation, or jumped to from another function.
+// The others can be allocated on the stack or become plain jumps.
+// Variables are identified by their names, as in the control-flow analysis.
+
+use crate::cps_ir::{Atom, Cont, IR};
+use std::collections::{HashMap, HashSet};
+
+pub struct Escape {
+    closures: HashSet<usize>,
+    conts: HashSet<usize>,
+    escaping: HashSet<usize>,
+}
+
+impl Escape {
+    // whether the lambda or the LetCont labeled `label` escapes
+    pub fn escapes(&self, label: usize) -> bool {
+        if !self.closures.contains(&label) && !self.conts.contains(&label) {
+            panic!("unrecognized label {label}");
+        }
+        self.escaping.contains(&label)
+    }
+
+    // labels of the lambdas
+    pub fn closures(&self) -> &HashSet<usize> {
+        &self.closures
+    }
+
+    // labels of the LetConts
+    pub fn continuations(&self) -> &HashSet<usize> {
+        &self.conts
+    }
+}
+
+struct Collector {
+    // lambda label => free variables
+    closures: HashMap<usize, HashSet<String>>,
+    // variables bound to each lambda
+    bound: HashMap<usize, Vec<String>>,
+    // source variable => variables it is copied to
+    copies: HashMap<String, Vec<String>>,
+    escaping_vars: HashSet<String>,
+    escaping: HashSet<usize>,
+    // LetCont label => free variables of its body
+    conts: HashMap<usize, HashSet<String>>,
+}
+
+// a continuation in scope: its name, label, parameters and the lambda it belongs to
+type ContScope<'a> = Vec<(&'a str, usize, &'a [String], Option<usize>)>;
+
+fn find_cont<'s, 'a>(
+    conts: &'s ContScope<'a>,
+    cont: &Cont,
+) -> Option<&'s (&'a str, usize, &'a [String], Option<usize>)> {
+    match cont {
+        Cont::Named(name) => conts.iter().rev().find(|(cont, ..)| cont == name),
+        Cont::Return | Cont::Handler => None,
+    }
+}
+
+impl Collector {
+    // an atom used other than by a call
+    fn escape_atom<'a>(&mut self, atom: &'a Atom, conts: &mut ContScope<'a>) {
+        match atom {
+            Atom::Var(var) => {
+                self.escaping_vars.insert(var.clone());
+            }
+            Atom::Lam(label, _, _) => {
+                self.escaping.insert(*label);
+            }
+            Atom::Cont(name) => {
+                if let Some((_, label, ..)) = find_cont(conts, &Cont::Named(name.clone())) {
+                    self.escaping.insert(*label);
+                }
+            }
+            _ => (),
+        }
+        self.collect_atom(None, atom, conts);
+    }
+
+    fn collect_atom<'a>(
+        &mut self,
+        var: Option<&String>,
+        atom: &'a Atom,
+        conts: &mut ContScope<'a>,
+    ) {
+        if let Atom::Lam(label, _, body) = atom {
+            self.closures.insert(*label, atom.free_vars());
+            self.bound.entry(*label).or_default().extend(var.cloned());
+            self.collect(body, conts, Some(*label));
+        }
+    }
+
+    // a jump to `cont` passing `args` from the lambda `fun`
+    fn jump<'a>(
+        &mut self,
+        cont: &Cont,
+        args: &'a [Atom],
+        conts: &mut ContScope<'a>,
+        fun: Option<usize>,
+    ) {
+        match find_cont(conts, cont).copied() {
+            Some((_, _, params, owner)) if owner == fun => {
+                for (arg, param) in args.iter().zip(params) {
+                    match arg {
+                        Atom::Var(var) => self
+                            .copies
+                            .entry(var.clone())
+                            .or_default()
+                            .push(param.clone()),
+                        _ => self.escape_atom(arg, conts),
+                    }
+                }
+            }
+            target => {
+                if let Some((_, label, ..)) = target {
+                    self.escaping.insert(label);
+                }
+                for arg in args {
+                    self.escape_atom(arg, conts);
+                }
+            }
+        }
+    }
+
+    // a continuation passed to a function
+    fn pass_cont(&mut self, cont: &Cont, conts: &ContScope) {
+        if let Some((_, label, ..)) = find_cont(conts, cont) {
+            self.escaping.insert(*label);
+        }
+    }
+
+    fn collect<'a>(&mut self, ir: &'a IR, conts: &mut ContScope<'a>, fun: Option<usize>) {
+        let depth = conts.len();
+        match ir {
+            IR::LetCont(label, name, args, cont_body, body) => {
+                let mut free_vars = cont_body.free_vars();
+                free_vars.retain(|var| !args.contains(var));
+                self.conts.insert(*label, free_vars);
+                conts.push((name, *label, args, fun));
+                self.collect(cont_body, conts, fun);
+                self.collect(body, conts, fun);
+            }
+            IR::Let(_, _, _, args, body) => {
+                for arg in args {
+                    self.escape_atom(arg, conts);
+                }
+                self.collect(body, conts, fun);
+            }
+            IR::LetVal(_, var, val, body) => {
+                match val {
+                    Atom::Var(source) => self
+                        .copies
+                        .entry(source.clone())
+                        .or_default()
+                        .push(var.clone()),
+                    Atom::Cont(_) => self.escape_atom(val, conts),
+                    _ => self.collect_atom(Some(var), val, conts),
+                }
+                self.collect(body, conts, fun);
+            }
+            IR::Fix(_, vars, vals, body) => {
+                for (var, val) in vars.iter().zip(vals) {
+                    self.collect_atom(Some(var), val, conts);
+                }
+                self.collect(body, conts, fun);
+            }
+            IR::If(_, test, then_, else_) => {
+                self.collect_atom(None, test, conts);
+                self.collect(then_, conts, fun);
+                self.collect(else_, conts, fun);
+            }
+            IR::App(_, f, args, cont, handler) => {
+                self.collect_atom(None, f, conts);
+                for arg in args {
+                    self.escape_atom(arg, conts);
+                }
+                self.pass_cont(cont, conts);
+                self.pass_cont(handler, conts);
+            }
+            IR::AppCont(_, cont, args) => self.jump(cont, args, conts, fun),
+            IR::Case(_, scrutinee, branches, default) => {
+                self.collect_atom(None, scrutinee, conts);
+                for cont in branches.iter().map(|(_, cont)| cont).chain(default) {
+                    self.jump(cont, &[], conts, fun);
+                }
+            }
+        }
+        conts.truncate(depth);
+    }
+}
+
+pub fn analyze(ir: &IR) -> Escape {
+    let mut collector = Collector {
+        closures: HashMap::new(),
+        bound: HashMap::new(),
+        copies: HashMap::new(),
+        escaping_vars: HashSet::new(),
+        escaping: HashSet::new(),
+        conts: HashMap::new(),
+    };
+    collector.collect(ir, &mut vec![], None);
+    let Collector {
+        closures,
+        bound,
+        copies,
+        mut escaping_vars,
+        mut escaping,
+        conts,
+    } = collector;
+
+    // a variable escapes if a copy of it escapes, a closure if a variable bound to it
+    // escapes, and the free variables of an escaping closure or continuation escape
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (source, targets) in &copies {
+            if !escaping_vars.contains(source)
+                && targets.iter().any(|target| escaping_vars.contains(target))
+            {
+                escaping_vars.insert(source.clone());
+                changed = true;
+            }
+        }
+        for (label, free_vars) in &closures {
+            let bound_escapes = bound[label].iter().any(|var| escaping_vars.contains(var));
+            if bound_escapes && escaping.insert(*label) {
+                changed = true;
+            }
+            if escaping.contains(label) {
+                for var in free_vars {
+                    changed |= escaping_vars.insert(var.clone());
+                }
+            }
+        }
+        for (label, free_vars) in &conts {
+            if escaping.contains(label) {
+                for var in free_vars {
+                    changed |= escaping_vars.insert(var.clone());
+                }
+            }
+        }
+    }
+    Escape {
+        closures: closures.keys().copied().collect(),
+        conts: conts.keys().copied().collect(),
+        escaping,
+    }
+}
@@ -0,0 +1,595 @@
+// Interval Analysis of I32, I64, U32 and U64 variables, a forward analysis
+// Option(Variable -> Interval) , \/
+// Intervals have infinite height, so the analysis widens at loop heads and narrows
+// afterwards. Loops are recursive functions, so a call flows into the entries of its
+// callees, binding their parameters, and a jump binds those of its continuation.
+// A call flows into its continuations with the environment of the caller: the callee
+// binds the same names in its own frame, its environment does not flow back.
+// As in SCCP, None marks code that is not reachable yet. The program starts with an empty
+// environment, and a variable missing from an environment may have any value.
+// The branches of an If on the result of a comparison refine the compared variables,
+// when they are bound once, and the interval of an array variable is the range of its
+// length.
+
+use crate::cps_ir::builtin_call::BuiltinOp;
+use crate::cps_ir::{
+    Atom, Cont, IR,
+    cfg::{self, NodeInfo, NodePool},
+};
+use std::{collections::HashMap, rc::Rc};
+
+pub const NEG_INF: i128 = i128::MIN;
+pub const POS_INF: i128 = i128::MAX;
+
+// bounds beyond any 64 bit integer are infinite
+fn bound(v: i128) -> i128 {
+    if v <= -(1 << 100) {
+        NEG_INF
+    } else if v >= 1 << 100 {
+        POS_INF
+    } else {
+        v
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interval {
+    pub lo: i128,
+    pub hi: i128,
+}
+
+impl Interval {
+    pub const TOP: Interval = Interval {
+        lo: NEG_INF,
+        hi: POS_INF,
+    };
+
+    pub fn new(lo: i128, hi: i128) -> Self {
+        Interval { lo, hi }
+    }
+
+    pub fn constant(v: i128) -> Self {
+        Interval { lo: v, hi: v }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lo > self.hi
+    }
+
+    pub fn meet(&self, other: &Interval) -> Interval {
+        Interval::new(self.lo.max(other.lo), self.hi.min(other.hi))
+    }
+
+    pub fn contains(&self, other: &Interval) -> bool {
+        self.lo <= other.lo && other.hi <= self.hi
+    }
+
+    pub fn join(&self, other: &Interval) -> Interval {
+        Interval::new(self.lo.min(other.lo), self.hi.max(other.hi))
+    }
+
+    pub fn widen(&self, new: &Interval) -> Interval {
+        Interval::new(
+            if new.lo < self.lo { NEG_INF } else { self.lo },
+            if new.hi > self.hi { POS_INF } else { self.hi },
+        )
+    }
+
+    pub fn narrow(&self, new: &Interval) -> Interval {
+        Interval::new(
+            if self.lo == NEG_INF { new.lo } else { self.lo },
+            if self.hi == POS_INF { new.hi } else { self.hi },
+        )
+    }
+
+    pub fn add(&self, other: &Interval) -> Interval {
+        Interval::new(
+            bound(self.lo.saturating_add(other.lo)),
+            bound(self.hi.saturating_add(other.hi)),
+        )
+    }
+
+    pub fn sub(&self, other: &Interval) -> Interval {
+        Interval::new(
+            bound(self.lo.saturating_sub(other.hi)),
+            bound(self.hi.saturating_sub(other.lo)),
+        )
+    }
+
+    pub fn mul(&self, other: &Interval) -> Interval {
+        let products = [
+            self.lo.saturating_mul(other.lo),
+            self.lo.saturating_mul(other.hi),
+            self.hi.saturating_mul(other.lo),
+            self.hi.saturating_mul(other.hi),
+        ];
+        Interval::new(
+            bound(*products.iter().min().unwrap()),
+            bound(*products.iter().max().unwrap()),
+        )
+    }
+
+    // a truncating division, TOP if the divisor may be zero
+    pub fn div(&self, other: &Interval) -> Interval {
+        if other.lo <= 0 && 0 <= other.hi {
+            return Interval::TOP;
+        }
+        let quotient = |a: i128, b: i128| a.checked_div(b).unwrap_or(POS_INF);
+        let quotients = [
+            quotient(self.lo, other.lo),
+            quotient(self.lo, other.hi),
+            quotient(self.hi, other.lo),
+            quotient(self.hi, other.hi),
+        ];
+        Interval::new(
+            bound(*quotients.iter().min().unwrap()),
+            bound(*quotients.iter().max().unwrap()),
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Intervals(pub Option<HashMap<String, Interval>>);
+
+impl Intervals {
+    fn pointwise(x: &Self, y: &Self, f: impl Fn(&Interval, &Interval) -> Interval) -> Self {
+        match (&x.0, &y.0) {
+            (None, _) => y.clone(),
+            (_, None) => x.clone(),
+            // a variable missing from an environment may have any value
+            (Some(x), Some(y)) => {
+                let mut env = HashMap::new();
+                for var in x.keys().chain(y.keys()) {
+                    let a = x.get(var).unwrap_or(&Interval::TOP);
+                    let b = y.get(var).unwrap_or(&Interval::TOP);
+                    let interval = f(a, b);
+                    if interval != Interval::TOP {
+                        env.insert(var.clone(), interval);
+                    }
+                }
+                Intervals(Some(env))
+            }
+        }
+    }
+}
+
+impl cfg::Lattice for Intervals {
+    fn join(x: &Self, y: &Self) -> Self {
+        Intervals::pointwise(x, y, Interval::join)
+    }
+    fn bottom() -> Self {
+        Intervals(None)
+    }
+    fn widen(old: &Self, new: &Self) -> Self {
+        Intervals::pointwise(old, new, Interval::widen)
+    }
+    fn narrow(old: &Self, new: &Self) -> Self {
+        match (&old.0, &new.0) {
+            (Some(_), Some(_)) => Intervals::pointwise(old, new, Interval::narrow),
+            _ => old.clone(),
+        }
+    }
+}
+
+fn type_range(min: i128, max: i128) -> Option<Interval> {
+    Some(Interval::new(min, max))
+}
+
+// the values of the integer type computed by an arithmetic operation
+fn range(op: &BuiltinOp) -> Option<Interval> {
+    use BuiltinOp::*;
+    match op {
+        I32Add | I32Sub | I32Mul | I32Div => type_range(i32::MIN as i128, i32::MAX as i128),
+        I64Add | I64Sub | I64Mul | I64Div => type_range(i64::MIN as i128, i64::MAX as i128),
+        U32Add | U32Sub | U32Mul | U32Div => type_range(0, u32::MAX as i128),
+        U64Add | U64Sub | U64Mul | U64Div => type_range(0, u64::MAX as i128),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Relation {
+    Lt,
+    Leq,
+    Eq,
+    Neq,
+}
+
+// a comparison of a and b as a relation, with a and b swapped if the flag is set
+fn comparison(op: &BuiltinOp) -> Option<(Relation, bool)> {
+    use BuiltinOp::*;
+    match op {
+        I32Lt | I64Lt | U32Lt | U64Lt => Some((Relation::Lt, false)),
+        I32Leq | I64Leq | U32Leq | U64Leq => Some((Relation::Leq, false)),
+        I32Gt | I64Gt | U32Gt | U64Gt => Some((Relation::Lt, true)),
+        I32Geq | I64Geq | U32Geq | U64Geq => Some((Relation::Leq, true)),
+        I32Eq | I64Eq | U32Eq | U64Eq => Some((Relation::Eq, false)),
+        _ => None,
+    }
+}
+
+fn eval(env: &HashMap<String, Interval>, atom: &Atom) -> Interval {
+    match atom {
+        Atom::Var(var) => env.get(var).copied().unwrap_or(Interval::TOP),
+        Atom::I32(v) => Interval::constant(*v as i128),
+        Atom::I64(v) => Interval::constant(*v as i128),
+        Atom::U32(v) => Interval::constant(*v as i128),
+        Atom::U64(v) => Interval::constant(*v as i128),
+        _ => Interval::TOP,
+    }
+}
+
+// the exact result of an arithmetic operation and the range of its type
+fn arith(
+    env: &HashMap<String, Interval>,
+    op: &BuiltinOp,
+    args: &[Atom],
+) -> Option<(Interval, Interval)> {
+    let (Some(range), [a, b]) = (range(op), args) else {
+        return None;
+    };
+    let (a, b) = (eval(env, a), eval(env, b));
+    let result = match op {
+        BuiltinOp::I32Add | BuiltinOp::I64Add | BuiltinOp::U32Add | BuiltinOp::U64Add => a.add(&b),
+        BuiltinOp::I32Sub | BuiltinOp::I64Sub | BuiltinOp::U32Sub | BuiltinOp::U64Sub => a.sub(&b),
+        BuiltinOp::I32Mul | BuiltinOp::I64Mul | BuiltinOp::U32Mul | BuiltinOp::U64Mul => a.mul(&b),
+        _ => a.div(&b),
+    };
+    Some((result, range))
+}
+
+fn transfer_let(env: &HashMap<String, Interval>, op: &BuiltinOp, args: &[Atom]) -> Interval {
+    let length = Interval::new(0, i32::MAX as i128);
+    match (op, args) {
+        (BuiltinOp::ArrayAlloc, [len, _]) => eval(env, len).meet(&length),
+        (BuiltinOp::ArrayLength, [arr]) => eval(env, arr).meet(&length),
+        _ => match arith(env, op, args) {
+            // an overflow wraps around
+            Some((result, range)) if range.contains(&result) => result,
+            Some((_, range)) => range,
+            None => Interval::TOP,
+        },
+    }
+}
+
+// the intervals of a and b if the relation holds, None if it cannot
+fn refine(relation: Relation, a: Interval, b: Interval) -> Option<(Interval, Interval)> {
+    let (a, b) = match relation {
+        Relation::Lt => (
+            a.meet(&Interval::new(NEG_INF, bound(b.hi.saturating_sub(1)))),
+            b.meet(&Interval::new(bound(a.lo.saturating_add(1)), POS_INF)),
+        ),
+        Relation::Leq => (
+            a.meet(&Interval::new(NEG_INF, b.hi)),
+            b.meet(&Interval::new(a.lo, POS_INF)),
+        ),
+        Relation::Eq => (a.meet(&b), b.meet(&a)),
+        Relation::Neq => (a, b),
+    };
+    if a.is_empty() || b.is_empty() {
+        None
+    } else {
+        Some((a, b))
+    }
+}
+
+// the environment of a branch where the comparison `op` of a and b is `holds`
+fn assume(
+    mut env: HashMap<String, Interval>,
+    (op, a, b): &(BuiltinOp, Atom, Atom),
+    holds: bool,
+) -> Option<HashMap<String, Interval>> {
+    let (relation, swap) = comparison(op)?;
+    let (mut a, mut b) = if swap { (b, a) } else { (a, b) };
+    // not a < b is b <= a, not a <= b is b < a
+    let relation = match (relation, holds) {
+        (relation, true) => relation,
+        (Relation::Lt, false) => {
+            std::mem::swap(&mut a, &mut b);
+            Relation::Leq
+        }
+        (Relation::Leq, false) => {
+            std::mem::swap(&mut a, &mut b);
+            Relation::Lt
+        }
+        (_, false) => Relation::Neq,
+    };
+    let (refined_a, refined_b) = refine(relation, eval(&env, a), eval(&env, b))?;
+    for (atom, refined) in [(a, refined_a), (b, refined_b)] {
+        if let Atom::Var(var) = atom {
+            env.insert(var.clone(), refined);
+        }
+    }
+    Some(env)
+}
+
+// parameters of continuations and of the lambdas bound to variables
+#[derive(Default)]
+struct Params {
+    conts: HashMap<String, Vec<String>>,
+    // the label of the body of each continuation => its parameters
+    entries: HashMap<usize, Vec<String>>,
+    // the continuations used as values
+    escaping: Vec<String>,
+    // the comparisons bound to variables
+    conditions: HashMap<String, (BuiltinOp, Atom, Atom)>,
+    // the number of binders of each variable
+    binders: HashMap<String, usize>,
+    lams: HashMap<String, (usize, Vec<String>)>,
+    all_lams: Vec<(usize, Vec<String>)>,
+}
+
+impl Params {
+    fn collect(&mut self, ir: &IR) {
+        match ir {
+            IR::LetCont(_, name, args, cont_body, body) => {
+                self.bind(args);
+                self.conts.insert(name.clone(), args.clone());
+                self.entries.insert(cont_body.get_label(), args.clone());
+                self.collect(cont_body);
+                self.collect(body);
+            }
+            IR::Let(_, var, op, args, body) => {
+                self.bind(std::slice::from_ref(var));
+                if let (Some(_), [a, b]) = (comparison(op), args.as_slice()) {
+                    let condition = (op.clone(), a.clone(), b.clone());
+                    self.conditions.insert(var.clone(), condition);
+                }
+                args.iter().for_each(|arg| self.collect_atom(None, arg));
+                self.collect(body);
+            }
+            IR::LetVal(_, var, val, body) => {
+                self.bind(std::slice::from_ref(var));
+                self.collect_atom(Some(var), val);
+                self.collect(body);
+            }
+            IR::Fix(_, vars, vals, body) => {
+                self.bind(vars);
+                for (var, val) in vars.iter().zip(vals) {
+                    self.collect_atom(Some(var), val);
+                }
+                self.collect(body);
+            }
+            IR::If(_, _, then_, else_) => {
+                self.collect(then_);
+                self.collect(else_);
+            }
+            IR::App(_, f, args, _, _) => {
+                self.collect_atom(None, f);
+                args.iter().for_each(|arg| self.collect_atom(None, arg));
+            }
+            IR::AppCont(_, _, args) => args.iter().for_each(|arg| self.collect_atom(None, arg)),
+            IR::Case(_, scrutinee, _, _) => self.collect_atom(None, scrutinee),
+        }
+    }
+
+    fn collect_atom(&mut self, var: Option<&String>, atom: &Atom) {
+        match atom {
+            Atom::Lam(label, args, body) => {
+                self.bind(args);
+                if let Some(var) = var {
+                    self.lams.insert(var.clone(), (*label, args.clone()));
+                }
+                self.all_lams.push((*label, args.clone()));
+                self.collect(body);
+            }
+            Atom::Cont(name) => self.escaping.push(name.clone()),
+            _ => (),
+        }
+    }
+
+    fn bind(&mut self, vars: &[String]) {
+        for var in vars {
+            *self.binders.entry(var.clone()).or_default() += 1;
+        }
+    }
+
+    fn bound_once(&self, var: &str) -> bool {
+        self.binders.get(var).is_none_or(|count| *count <= 1)
+    }
+
+    // the comparison bound to `test`, unless it or one of the compared variables is
+    // bound more than once, as a variable may then no longer name the compared value
+    fn condition(&self, test: &str) -> Option<&(BuiltinOp, Atom, Atom)> {
+        let condition @ (_, a, b) = self.conditions.get(test)?;
+        let operands_once = [a, b].into_iter().all(|atom| match atom {
+            Atom::Var(var) => self.bound_once(var),
+            _ => true,
+        });
+        (self.bound_once(test) && operands_once).then_some(condition)
+    }
+
+    fn cont(&self, cont: &Cont) -> &[String] {
+        match cont {
+            Cont::Named(name) => self.conts.get(name).map_or(&[], |args| args.as_slice()),
+            _ => &[],
+        }
+    }
+
+    // the label and parameters of the callee if it is known
+    fn callee(&self, f: &Atom) -> Option<(usize, Vec<String>)> {
+        match f {
+            Atom::Lam(label, args, _) => Some((*label, args.clone())),
+            Atom::Var(var) => self.lams.get(var).cloned(),
+            _ => None,
+        }
+    }
+}
+
+fn collect_params(ir: &IR) -> Params {
+    let mut params = Params::default();
+    params.collect(ir);
+    params
+}
+
+pub fn make_analysis<'a>(ir: &IR) -> NodePool<'a, Intervals> {
+    make_pool(Rc::new(collect_params(ir)))
+}
+
+fn make_pool<'a>(params: Rc<Params>) -> NodePool<'a, Intervals> {
+    let transfer_params = params.clone();
+    let mut pool = NodePool::new(
+        true,
+        Box::new(move |_, ir, Intervals(env)| {
+            let params = &transfer_params;
+            let Some(mut env) = env else {
+                return Intervals(None);
+            };
+            match ir {
+                IR::Let(_, var, op, args, _) => {
+                    let value = transfer_let(&env, op, args);
+                    env.insert(var.clone(), value);
+                }
+                IR::LetVal(_, var, val, _) => {
+                    let value = eval(&env, val);
+                    env.insert(var.clone(), value);
+                }
+                IR::Fix(_, vars, _, _) => {
+                    for var in vars {
+                        env.insert(var.clone(), Interval::TOP);
+                    }
+                }
+                IR::AppCont(_, cont, args) => {
+                    let values: Vec<Interval> = args.iter().map(|arg| eval(&env, arg)).collect();
+                    for (param, value) in params.cont(cont).iter().zip(values) {
+                        env.insert(param.clone(), value);
+                    }
+                }
+                IR::Case(_, _, branches, default) => {
+                    for cont in branches.iter().map(|(_, cont)| cont).chain(default) {
+                        for param in params.cont(cont) {
+                            env.insert(param.clone(), Interval::TOP);
+                        }
+                    }
+                }
+                // a call binds parameters along its edges
+                IR::LetCont(..) | IR::If(..) | IR::App(..) => (),
+            }
+            Intervals(Some(env))
+        }),
+    );
+    pool.set_edge_fun(Box::new(move |_, ir, target, out| match (ir, &out.0) {
+        (IR::If(_, Atom::Var(test), then_, _), Some(env)) => match params.condition(test) {
+            Some(condition) => {
+                let holds = target == Some(then_.get_label());
+                Intervals(assume(env.clone(), condition, holds))
+            }
+            None => out.clone(),
+        },
+        (IR::App(_, f, args, _, _), Some(env)) => {
+            let mut env = env.clone();
+            match target.and_then(|target| params.entries.get(&target)) {
+                // the continuation the callee returns or raises to, or which it invokes
+                Some(cont_params) => {
+                    for param in cont_params {
+                        env.insert(param.clone(), Interval::TOP);
+                    }
+                }
+                // the entry of a callee, which only sees the variables of the caller bound
+                // once, the others may name other bindings in its scope
+                None => {
+                    let values: Vec<Interval> = args.iter().map(|arg| eval(&env, arg)).collect();
+                    env.retain(|var, _| params.bound_once(var));
+                    match params.callee(f) {
+                        Some((_, callee)) => {
+                            for (param, value) in callee.iter().zip(values) {
+                                env.insert(param.clone(), value);
+                            }
+                        }
+                        // every lambda may be called
+                        None => {
+                            for param in params.all_lams.iter().flat_map(|(_, params)| params) {
+                                env.insert(param.clone(), Interval::TOP);
+                            }
+                        }
+                    }
+                }
+            }
+            Intervals(Some(env))
+        }
+        _ => out.clone(),
+    }));
+    pool
+}
+
+// the intraprocedural graph, with edges from the calls to the entries of their callees,
+// an unknown callee is any lambda or a continuation used as a value
+fn construct<'a>(pool: &mut NodePool<'a, Intervals>, ir: &'a IR, params: &Params) {
+    pool.construct_intra(ir);
+    for node in 0..pool.node_count() {
+        let NodeInfo::Common(_, IR::App(_, f, ..)) = pool.get_info(node) else {
+            continue;
+        };
+        let targets: Vec<usize> = match params.callee(f) {
+            Some((label, _)) => vec![pool.get_fun_entry(label)],
+            None => params
+                .all_lams
+                .iter()
+                .map(|(label, _)| pool.get_fun_entry(*label))
+                .chain(params.escaping.iter().map(|name| pool.get_cont(name)))
+                .collect(),
+        };
+        for target in targets {
+            pool.add_edge(node, target);
+        }
+    }
+}
+
+pub struct IntervalAnalysis<'a> {
+    pool: NodePool<'a, Intervals>,
+}
+
+impl<'a> IntervalAnalysis<'a> {
+    // the interval of `var` before the IR labeled `label`, None if it is not reachable
+    pub fn interval_at(&self, label: usize, var: &str) -> Option<Interval> {
+        let env = self
+            .pool
+            .get_result_in(self.pool.get_node(label))
+            .0
+            .as_ref()?;
+        Some(env.get(var).copied().unwrap_or(Interval::TOP))
+    }
+
+    // the environment before the Let labeled `label`, None if it is not reachable
+    fn let_at(&self, label: usize) -> (Option<&HashMap<String, Interval>>, &BuiltinOp, &[Atom]) {
+        let node = self.pool.get_node(label);
+        let NodeInfo::Common(_, IR::Let(_, _, op, args, _)) = self.pool.get_info(node) else {
+            panic!("expect a Let labeled {label}")
+        };
+        (self.pool.get_result_in(node).0.as_ref(), op, args)
+    }
+
+    // whether the arithmetic operation labeled `label` may overflow or divide by zero,
+    // false if it is not reachable
+    pub fn may_overflow(&self, label: usize) -> bool {
+        match self.let_at(label) {
+            (Some(env), op, args) => match arith(env, op, args) {
+                Some((result, range)) => !range.contains(&result),
+                None => false,
+            },
+            (None, _, _) => false,
+        }
+    }
+
+    // whether the index of the array access labeled `label` is within the bounds
+    // of the array, true if it is not reachable
+    pub fn index_in_bounds(&self, label: usize) -> bool {
+        match self.let_at(label) {
+            (Some(env), BuiltinOp::ArrayGet | BuiltinOp::ArraySet, [arr, index, ..]) => {
+                let (len, index) = (eval(env, arr), eval(env, index));
+                0 <= index.lo && index.hi < len.lo
+            }
+            (Some(_), op, _) => panic!("expect an array access, found {op:?}"),
+            (None, _, _) => true,
+        }
+    }
+}
+
+pub fn analyze(ir: &IR) -> IntervalAnalysis<'_> {
+    let params = Rc::new(collect_params(ir));
+    let mut pool = make_pool(params.clone());
+    construct(&mut pool, ir, &params);
+    pool.seed(pool.get_prog_entry(), Intervals(Some(HashMap::new())));
+    pool.run_worklist();
+    IntervalAnalysis { pool }
+}
@@ -0,0 +1,128 @@
+// Live Variables Analysis, a may, backward analysis
+// Set(Variable) , \/
+// A jump to a continuation defines its parameters, so App, AppCont and Case
+// kill the parameters of the continuations they pass control to.
+
+use crate::cps_ir::{
+    Atom, Cont, IR,
+    cfg::{self, NodePool},
+};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiveVars(pub HashSet<String>);
+
+impl cfg::Lattice for LiveVars {
+    fn join(x: &Self, y: &Self) -> Self {
+        LiveVars(x.0.union(&y.0).cloned().collect())
+    }
+    fn bottom() -> Self {
+        LiveVars(HashSet::new())
+    }
+}
+
+fn collect_cont_params(ir: &IR, table: &mut HashMap<String, Vec<String>>) {
+    let in_atom = |atom: &Atom, table: &mut HashMap<String, Vec<String>>| {
+        if let Atom::Lam(_, _, body) = atom {
+            collect_cont_params(body, table);
+        }
+    };
+    match ir {
+        IR::LetCont(_, name, args, cont_body, body) => {
+            table.insert(name.clone(), args.clone());
+            collect_cont_params(cont_body, table);
+            collect_cont_params(body, table);
+        }
+        IR::Let(_, _, _, args, body) => {
+            args.iter().for_each(|arg| in_atom(arg, table));
+            collect_cont_params(body, table);
+        }
+        IR::LetVal(_, _, val, body) => {
+            in_atom(val, table);
+            collect_cont_params(body, table);
+        }
+        IR::Fix(_, _, vals, body) => {
+            vals.iter().for_each(|val| in_atom(val, table));
+            collect_cont_params(body, table);
+        }
+        IR::If(_, _, then_, else_) => {
+            collect_cont_params(then_, table);
+            collect_cont_params(else_, table);
+        }
+        IR::App(_, f, args, _, _) => {
+            in_atom(f, table);
+            args.iter().for_each(|arg| in_atom(arg, table));
+        }
+        IR::AppCont(_, _, args) => args.iter().for_each(|arg| in_atom(arg, table)),
+        IR::Case(..) => (),
+    }
+}
+
+pub fn make_analysis<'a>(ir: &'a IR) -> NodePool<'a, LiveVars> {
+    let mut cont_params = HashMap::new();
+    collect_cont_params(ir, &mut cont_params);
+    let params = move |cont: &Cont| -> Vec<String> {
+        match cont {
+            Cont::Named(name) => cont_params.get(name).cloned().unwrap_or_default(),
+            Cont::Return | Cont::Handler => vec![],
+        }
+    };
+    NodePool::new(
+        false,
+        Box::new(move |_label, ir, LiveVars(mut live)| {
+            let (defs, uses): (Vec<String>, Vec<&Atom>) = match ir {
+                IR::LetCont(..) => (vec![], vec![]),
+                IR::Let(_, var, _, args, _) => (vec![var.clone()], args.iter().collect()),
+                IR::LetVal(_, var, val, _) => (vec![var.clone()], vec![val]),
+                IR::If(_, test, _, _) => (vec![], vec![test]),
+                IR::App(_, f, args, cont, handler) => (
+                    params(cont).into_iter().chain(params(handler)).collect(),
+                    std::iter::once(f).chain(args).collect(),
+                ),
+                IR::Fix(_, vars, vals, _) => {
+                    // the values of a Fix may refer to its own bindings
+                    live.extend(vals.iter().flat_map(|val| val.free_vars()));
+                    (vars.clone(), vec![])
+                }
+                IR::AppCont(_, cont, args) => (params(cont), args.iter().collect()),
+                IR::Case(_, scrutinee, branches, default) => (
+                    branches
+                        .iter()
+                        .map(|(_tag, cont)| cont)
+                        .chain(default)
+                        .flat_map(&params)
+                        .collect(),
+                    vec![scrutinee],
+                ),
+            };
+            for def in &defs {
+                live.remove(def);
+            }
+            live.extend(uses.into_iter().flat_map(|atom| atom.free_vars()));
+            LiveVars(live)
+        }),
+    )
+}
+
+pub struct Liveness<'a> {
+    pool: NodePool<'a, LiveVars>,
+}
+
+impl<'a> Liveness<'a> {
+    // the variables live before the IR labeled `label`
+    pub fn live_at(&self, label: usize) -> &HashSet<String> {
+        &self.pool.get_result_out(self.pool.get_node(label)).0
+    }
+
+    // the variables live after the IR labeled `label`
+    pub fn live_after(&self, label: usize) -> &HashSet<String> {
+        &self.pool.get_result_in(self.pool.get_node(label)).0
+    }
+}
+
+pub fn analyze(ir: &IR) -> Liveness<'_> {
+    let mut pool = make_analysis(ir);
+    pool.construct_intra(ir);
+    pool.run_worklist();
+    Liveness { pool }
+}
@@ -1 +1,7 @@
-pub mod available_expression;
\ No newline at end of file
+pub mod available_expression;
+pub mod control_flow;
+pub mod escape;
+pub mod interval;
+pub mod liveness;
+pub mod reaching_definitions;
+pub mod sccp;
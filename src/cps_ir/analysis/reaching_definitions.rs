@@ -0,0 +1,260 @@
+// Reaching Definitions Analysis, a may, forward analysis
+// Set(Definition) , \/
+// A definition is a binder: Let, LetVal and Fix bindings are labeled by their IR,
+// lambda parameters by the lambda and continuation parameters by the LetCont.
+// Parameters are defined on entry of the lambda or continuation body.
+// Definitions flow into callees, while a call flows into its continuations with the
+// definitions of the caller: those of the callee are out of the scope of the caller.
+
+use crate::cps_ir::{
+    Atom, IR,
+    cfg::{self, NodePool},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Definition {
+    pub var: String,
+    pub label: usize,
+}
+
+impl Definition {
+    pub fn new(var: &str, label: usize) -> Self {
+        Definition {
+            var: var.to_string(),
+            label,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Definitions(pub HashSet<Definition>);
+
+impl cfg::Lattice for Definitions {
+    fn join(x: &Self, y: &Self) -> Self {
+        Definitions(x.0.union(&y.0).cloned().collect())
+    }
+    fn bottom() -> Self {
+        Definitions(HashSet::new())
+    }
+}
+
+// label of a lambda or continuation body => label of the binder, parameters
+type EntryParams = HashMap<usize, (usize, Vec<String>)>;
+
+fn collect_entry_params(ir: &IR, table: &mut EntryParams) {
+    let in_atom = |atom: &Atom, table: &mut EntryParams| {
+        if let Atom::Lam(label, args, body) = atom {
+            table.insert(body.get_label(), (*label, args.clone()));
+            collect_entry_params(body, table);
+        }
+    };
+    match ir {
+        IR::LetCont(label, _, args, cont_body, body) => {
+            table.insert(cont_body.get_label(), (*label, args.clone()));
+            collect_entry_params(cont_body, table);
+            collect_entry_params(body, table);
+        }
+        IR::Let(_, _, _, args, body) => {
+            args.iter().for_each(|arg| in_atom(arg, table));
+            collect_entry_params(body, table);
+        }
+        IR::LetVal(_, _, val, body) => {
+            in_atom(val, table);
+            collect_entry_params(body, table);
+        }
+        IR::Fix(_, _, vals, body) => {
+            vals.iter().for_each(|val| in_atom(val, table));
+            collect_entry_params(body, table);
+        }
+        IR::If(_, test, then_, else_) => {
+            in_atom(test, table);
+            collect_entry_params(then_, table);
+            collect_entry_params(else_, table);
+        }
+        IR::App(_, f, args, _, _) => {
+            in_atom(f, table);
+            args.iter().for_each(|arg| in_atom(arg, table));
+        }
+        IR::AppCont(_, _, args) => args.iter().for_each(|arg| in_atom(arg, table)),
+        IR::Case(_, scrutinee, _, _) => in_atom(scrutinee, table),
+    }
+}
+
+fn define(defs: &mut HashSet<Definition>, vars: &[String], label: usize) {
+    defs.retain(|def| !vars.contains(&def.var));
+    defs.extend(vars.iter().map(|var| Definition::new(var, label)));
+}
+
+// the definitions of the parameters when entering a lambda or continuation body
+fn enter(entry_params: &EntryParams, label: usize, defs: &mut HashSet<Definition>) {
+    if let Some((binder, params)) = entry_params.get(&label) {
+        define(defs, params, *binder);
+    }
+}
+
+pub fn make_analysis<'a>(ir: &'a IR) -> NodePool<'a, Definitions> {
+    let mut entry_params = HashMap::new();
+    collect_entry_params(ir, &mut entry_params);
+    make_pool(Rc::new(entry_params))
+}
+
+fn make_pool<'a>(entry_params: Rc<EntryParams>) -> NodePool<'a, Definitions> {
+    NodePool::new(
+        true,
+        Box::new(move |label, ir, Definitions(mut defs)| {
+            enter(&entry_params, label, &mut defs);
+            match ir {
+                IR::Let(_, var, _, _, _) | IR::LetVal(_, var, _, _) => {
+                    define(&mut defs, std::slice::from_ref(var), label)
+                }
+                IR::Fix(_, vars, _, _) => define(&mut defs, vars, label),
+                _ => (),
+            }
+            Definitions(defs)
+        }),
+    )
+}
+
+pub struct ReachingDefinitions<'a> {
+    pool: NodePool<'a, Definitions>,
+    entry_params: Rc<EntryParams>,
+}
+
+impl<'a> ReachingDefinitions<'a> {
+    // the definitions reaching the IR labeled `label`, before its own binding
+    pub fn reaching_at(&self, label: usize) -> HashSet<Definition> {
+        let mut defs = self.pool.get_result_in(self.pool.get_node(label)).0.clone();
+        enter(&self.entry_params, label, &mut defs);
+        defs
+    }
+
+    // the definitions of `var` reaching the IR labeled `label`
+    pub fn reaching_defs_of(&self, label: usize, var: &str) -> HashSet<Definition> {
+        self.reaching_at(label)
+            .into_iter()
+            .filter(|def| def.var == var)
+            .collect()
+    }
+}
+
+pub fn analyze(ir: &IR) -> ReachingDefinitions<'_> {
+    let mut entry_params = HashMap::new();
+    collect_entry_params(ir, &mut entry_params);
+    let entry_params = Rc::new(entry_params);
+    let mut pool = make_pool(entry_params.clone());
+    pool.construct_local(ir);
+    pool.run_worklist();
+    ReachingDefinitions { pool, entry_params }
+}
+
+// Def-use and use-def chains. In CPS every occurrence of a variable is bound by
+// the innermost enclosing binder, so the chains are resolved by scoping.
+// Occurrences are identified by the label of the IR they appear in,
+// variables without a binder are free in the program and have no definition.
+pub struct DefUse {
+    uses: HashMap<Definition, Vec<usize>>,
+    defs: HashMap<(usize, String), Definition>,
+}
+
+impl DefUse {
+    // labels of the IR using the definition, a label occurs once per occurrence
+    pub fn uses_of(&self, def: &Definition) -> &[usize] {
+        self.uses
+            .get(def)
+            .map(|uses| uses.as_slice())
+            .unwrap_or(&[])
+    }
+
+    // the definition of `var` used by the IR labeled `label`
+    pub fn def_of(&self, label: usize, var: &str) -> Option<&Definition> {
+        self.defs.get(&(label, var.to_string()))
+    }
+
+    pub fn definitions(&self) -> impl Iterator<Item = &Definition> {
+        self.uses.keys()
+    }
+
+    fn bind(&mut self, scope: &mut Vec<Definition>, vars: &[String], label: usize) {
+        for var in vars {
+            let def = Definition::new(var, label);
+            self.uses.entry(def.clone()).or_default();
+            scope.push(def);
+        }
+    }
+
+    fn use_atom(&mut self, atom: &Atom, label: usize, scope: &mut Vec<Definition>) {
+        match atom {
+            Atom::Var(var) => {
+                if let Some(def) = scope.iter().rev().find(|def| &def.var == var) {
+                    self.uses.entry(def.clone()).or_default().push(label);
+                    self.defs.insert((label, var.clone()), def.clone());
+                }
+            }
+            Atom::Lam(lam_label, args, body) => {
+                let depth = scope.len();
+                self.bind(scope, args, *lam_label);
+                self.build(body, scope);
+                scope.truncate(depth);
+            }
+            _ => (),
+        }
+    }
+
+    fn build(&mut self, ir: &IR, scope: &mut Vec<Definition>) {
+        let depth = scope.len();
+        match ir {
+            IR::LetCont(label, _, args, cont_body, body) => {
+                self.bind(scope, args, *label);
+                self.build(cont_body, scope);
+                scope.truncate(depth);
+                self.build(body, scope);
+            }
+            IR::Let(label, var, _, args, body) => {
+                args.iter()
+                    .for_each(|arg| self.use_atom(arg, *label, scope));
+                self.bind(scope, std::slice::from_ref(var), *label);
+                self.build(body, scope);
+            }
+            IR::LetVal(label, var, val, body) => {
+                self.use_atom(val, *label, scope);
+                self.bind(scope, std::slice::from_ref(var), *label);
+                self.build(body, scope);
+            }
+            IR::If(label, test, then_, else_) => {
+                self.use_atom(test, *label, scope);
+                self.build(then_, scope);
+                self.build(else_, scope);
+            }
+            IR::App(label, f, args, _, _) => {
+                self.use_atom(f, *label, scope);
+                args.iter()
+                    .for_each(|arg| self.use_atom(arg, *label, scope));
+            }
+            IR::Fix(label, vars, vals, body) => {
+                self.bind(scope, vars, *label);
+                vals.iter()
+                    .for_each(|val| self.use_atom(val, *label, scope));
+                self.build(body, scope);
+            }
+            IR::AppCont(label, _, args) => {
+                args.iter()
+                    .for_each(|arg| self.use_atom(arg, *label, scope));
+            }
+            IR::Case(label, scrutinee, _, _) => self.use_atom(scrutinee, *label, scope),
+        }
+        scope.truncate(depth);
+    }
+}
+
+pub fn def_use(ir: &IR) -> DefUse {
+    let mut chains = DefUse {
+        uses: HashMap::new(),
+        defs: HashMap::new(),
+    };
+    chains.build(ir, &mut vec![]);
+    chains
+}
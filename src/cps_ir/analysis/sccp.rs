@@ -0,0 +1,308 @@
+// Sparse Conditional Constant Propagation, a forward analysis
+// Option(Variable -> Const) , \/
+// None marks code that is not executable yet. A lambda body starts with its parameters
+// overdefined, a variable missing from one of the joined environments is overdefined,
+// so a binding of an outer scope never stands for a parameter of the same name.
+// A jump to a continuation binds its parameters, so the join at the continuation body
+// merges the arguments of all executable jumps, the CPS analogue of a phi node.
+// Branches of If and Case are only executable when the scrutinee may select them.
+
+use crate::cps_ir::builtin_call::BuiltinOp;
+use crate::cps_ir::{
+    Atom, CaseTag, Cont, IR, Value, builtin_call, can_fold,
+    cfg::{self, NodePool},
+};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Const {
+    Value(Atom),
+    Overdefined,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConstEnv(pub Option<HashMap<String, Const>>);
+
+impl cfg::Lattice for ConstEnv {
+    fn join(x: &Self, y: &Self) -> Self {
+        match (&x.0, &y.0) {
+            (None, _) => y.clone(),
+            (_, None) => x.clone(),
+            (Some(x), Some(y)) => {
+                let mut env = x.clone();
+                for (var, c) in y {
+                    if env.get(var) != Some(c) {
+                        env.insert(var.clone(), Const::Overdefined);
+                    }
+                }
+                for (var, c) in env.iter_mut() {
+                    if !y.contains_key(var) {
+                        *c = Const::Overdefined;
+                    }
+                }
+                ConstEnv(Some(env))
+            }
+        }
+    }
+    fn bottom() -> Self {
+        ConstEnv(None)
+    }
+}
+
+fn to_value(atom: &Atom) -> Option<Value<'_>> {
+    match atom {
+        Atom::I32(v) => Some(Value::I32(*v)),
+        Atom::I64(v) => Some(Value::I64(*v)),
+        Atom::U32(v) => Some(Value::U32(*v)),
+        Atom::U64(v) => Some(Value::U64(*v)),
+        Atom::Bool(v) => Some(Value::Bool(*v)),
+        Atom::Char(v) => Some(Value::Char(*v)),
+        Atom::StringLiteral(v) => Some(Value::StringLiteral(v.clone())),
+        _ => None,
+    }
+}
+
+fn to_atom(value: Value) -> Option<Atom> {
+    match value {
+        Value::I32(v) => Some(Atom::I32(v)),
+        Value::I64(v) => Some(Atom::I64(v)),
+        Value::U32(v) => Some(Atom::U32(v)),
+        Value::U64(v) => Some(Atom::U64(v)),
+        Value::Bool(v) => Some(Atom::Bool(v)),
+        Value::Char(v) => Some(Atom::Char(v)),
+        Value::StringLiteral(v) => Some(Atom::StringLiteral(v)),
+        _ => None,
+    }
+}
+
+fn eval(env: &HashMap<String, Const>, atom: &Atom) -> Const {
+    match atom {
+        Atom::Var(var) => env.get(var).cloned().unwrap_or(Const::Overdefined),
+        Atom::Lam(..) | Atom::Cont(_) => Const::Overdefined,
+        literal => Const::Value(literal.clone()),
+    }
+}
+
+fn fold(env: &HashMap<String, Const>, op: &BuiltinOp, args: &[Atom]) -> Const {
+    let mut values = vec![];
+    for arg in args {
+        match eval(env, arg) {
+            Const::Value(atom) => values.push(atom),
+            Const::Overdefined => return Const::Overdefined,
+        }
+    }
+    let values: Option<Vec<Value>> = values.iter().map(to_value).collect();
+    match values {
+        Some(values) if can_fold(op, &values) => {
+            to_atom(builtin_call(op, values)).map_or(Const::Overdefined, Const::Value)
+        }
+        _ => Const::Overdefined,
+    }
+}
+
+// continuation name => parameters, label of the body
+type ContTable = HashMap<String, (Vec<String>, usize)>;
+
+// label of a lambda body => parameters
+type Entries = HashMap<usize, Vec<String>>;
+
+fn collect_conts(ir: &IR, table: &mut ContTable, entries: &mut Entries) {
+    let mut in_atom = |atom: &Atom, table: &mut ContTable| {
+        if let Atom::Lam(_, params, body) = atom {
+            entries.insert(body.get_label(), params.clone());
+            collect_conts(body, table, entries);
+        }
+    };
+    match ir {
+        IR::LetCont(_, name, args, cont_body, body) => {
+            table.insert(name.clone(), (args.clone(), cont_body.get_label()));
+            collect_conts(cont_body, table, entries);
+            collect_conts(body, table, entries);
+        }
+        IR::Let(_, _, _, args, body) => {
+            args.iter().for_each(|arg| in_atom(arg, table));
+            collect_conts(body, table, entries);
+        }
+        IR::LetVal(_, _, val, body) => {
+            in_atom(val, table);
+            collect_conts(body, table, entries);
+        }
+        IR::Fix(_, _, vals, body) => {
+            vals.iter().for_each(|val| in_atom(val, table));
+            collect_conts(body, table, entries);
+        }
+        IR::If(_, _, then_, else_) => {
+            collect_conts(then_, table, entries);
+            collect_conts(else_, table, entries);
+        }
+        IR::App(_, f, args, _, _) => {
+            in_atom(f, table);
+            args.iter().for_each(|arg| in_atom(arg, table));
+        }
+        IR::AppCont(_, _, args) => args.iter().for_each(|arg| in_atom(arg, table)),
+        IR::Case(..) => (),
+    }
+}
+
+fn params<'c>(conts: &'c ContTable, cont: &Cont) -> &'c [String] {
+    match cont {
+        Cont::Named(name) => conts.get(name).map_or(&[], |(params, _)| params.as_slice()),
+        _ => &[],
+    }
+}
+
+fn bind(env: &mut HashMap<String, Const>, conts: &ContTable, cont: &Cont, values: Vec<Const>) {
+    for (param, value) in params(conts, cont).iter().zip(values) {
+        env.insert(param.clone(), value);
+    }
+}
+
+fn overdefine(env: &mut HashMap<String, Const>, conts: &ContTable, cont: &Cont) {
+    for param in params(conts, cont) {
+        env.insert(param.clone(), Const::Overdefined);
+    }
+}
+
+// the branch of a Case selected by a constant scrutinee
+fn select<'a>(
+    value: &Atom,
+    branches: &'a [(CaseTag, Cont)],
+    default: &'a Option<Cont>,
+) -> Option<&'a Cont> {
+    let selected = branches.iter().find(|(tag, _)| match (tag, value) {
+        (CaseTag::I32(a), Atom::I32(b)) => a == b,
+        (CaseTag::I64(a), Atom::I64(b)) => a == b,
+        (CaseTag::U32(a), Atom::U32(b)) => a == b,
+        (CaseTag::U64(a), Atom::U64(b)) => a == b,
+        _ => false,
+    });
+    selected.map(|(_, cont)| cont).or(default.as_ref())
+}
+
+fn cont_label(conts: &ContTable, cont: &Cont) -> Option<usize> {
+    match cont {
+        Cont::Named(name) => conts.get(name).map(|(_, label)| *label),
+        _ => None,
+    }
+}
+
+pub fn make_analysis<'a>(ir: &'a IR) -> NodePool<'a, ConstEnv> {
+    let mut conts = HashMap::new();
+    // the program and lambda bodies are executable, their inputs are overdefined
+    let mut entries = HashMap::from([(ir.get_label(), vec![])]);
+    collect_conts(ir, &mut conts, &mut entries);
+    let edge_conts = conts.clone();
+    let mut pool = NodePool::new(
+        true,
+        Box::new(move |label, ir, ConstEnv(env)| {
+            let mut env = match (env, entries.get(&label)) {
+                (Some(env), None) => env,
+                (env, Some(params)) => {
+                    let mut env = env.unwrap_or_default();
+                    for param in params {
+                        env.insert(param.clone(), Const::Overdefined);
+                    }
+                    env
+                }
+                (None, None) => return ConstEnv(None),
+            };
+            match ir {
+                IR::Let(_, var, op, args, _) => {
+                    let value = if op.is_pure() {
+                        fold(&env, op, args)
+                    } else {
+                        Const::Overdefined
+                    };
+                    env.insert(var.clone(), value);
+                }
+                IR::LetVal(_, var, val, _) => {
+                    let value = eval(&env, val);
+                    env.insert(var.clone(), value);
+                }
+                IR::Fix(_, vars, _, _) => {
+                    for var in vars {
+                        env.insert(var.clone(), Const::Overdefined);
+                    }
+                }
+                IR::AppCont(_, cont, args) => {
+                    let values = args.iter().map(|arg| eval(&env, arg)).collect();
+                    bind(&mut env, &conts, cont, values);
+                }
+                IR::App(_, _, _, cont, handler) => {
+                    overdefine(&mut env, &conts, cont);
+                    overdefine(&mut env, &conts, handler);
+                }
+                IR::Case(_, _, branches, default) => {
+                    for (_, cont) in branches {
+                        overdefine(&mut env, &conts, cont);
+                    }
+                    if let Some(cont) = default {
+                        overdefine(&mut env, &conts, cont);
+                    }
+                }
+                IR::LetCont(..) | IR::If(..) => (),
+            }
+            ConstEnv(Some(env))
+        }),
+    );
+    pool.set_edge_fun(Box::new(move |_, ir, target, out| {
+        let Some(env) = &out.0 else {
+            return ConstEnv(None);
+        };
+        let executable = match ir {
+            IR::If(_, test, then_, else_) => match eval(env, test) {
+                Const::Value(Atom::Bool(b)) => {
+                    let taken = if b { then_ } else { else_ };
+                    target == Some(taken.get_label())
+                }
+                _ => true,
+            },
+            IR::Case(_, scrutinee, branches, default) => match eval(env, scrutinee) {
+                Const::Value(value) => match select(&value, branches, default) {
+                    Some(cont) => target == cont_label(&edge_conts, cont),
+                    None => false,
+                },
+                Const::Overdefined => true,
+            },
+            _ => true,
+        };
+        if executable {
+            out.clone()
+        } else {
+            ConstEnv(None)
+        }
+    }));
+    pool
+}
+
+pub struct Sccp<'a> {
+    pool: NodePool<'a, ConstEnv>,
+}
+
+impl<'a> Sccp<'a> {
+    // whether the IR labeled `label` may be executed
+    pub fn executable(&self, label: usize) -> bool {
+        self.pool
+            .get_result_out(self.pool.get_node(label))
+            .0
+            .is_some()
+    }
+
+    // the constant value of `var` before the IR labeled `label`, if any
+    pub fn constant_at(&self, label: usize, var: &str) -> Option<&Atom> {
+        match &self.pool.get_result_in(self.pool.get_node(label)).0 {
+            Some(env) => match env.get(var) {
+                Some(Const::Value(atom)) => Some(atom),
+                _ => None,
+            },
+            None => None,
+        }
+    }
+}
+
+pub fn analyze(ir: &IR) -> Sccp<'_> {
+    let mut pool = make_analysis(ir);
+    pool.construct_intra(ir);
+    pool.run_worklist();
+    Sccp { pool }
+}
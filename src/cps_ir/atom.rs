@@ -1,5 +1,5 @@
 use super::IR;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Atom {
     Var(String),
@@ -11,6 +11,7 @@ pub enum Atom {
     Char(char),
     StringLiteral(String),
     Lam(usize, Vec<String>, Box<IR>),
+    Cont(String),
 }
 
 impl Atom {
@@ -24,6 +25,19 @@ impl Atom {
     pub fn v(s: &str) -> Self {
         Atom::Var(s.to_string())
     }
+    pub fn free_vars(&self) -> HashSet<String> {
+        match self {
+            Atom::Var(v) => HashSet::from([v.clone()]),
+            Atom::Lam(_label, args, body) => {
+                let mut vars = body.free_vars();
+                for arg in args {
+                    vars.remove(arg);
+                }
+                vars
+            }
+            _ => HashSet::new(),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -35,6 +49,10 @@ pub enum Value<'a> {
     Bool(bool),
     Char(char),
     StringLiteral(String),
+    Tuple(Vec<Value<'a>>),
+    Data(usize, Vec<Value<'a>>),
+    Ref(usize),
+    Array(usize, usize),
     Clo(&'a Vec<String>, &'a IR, HashMap<&'a str, usize>),
     Cont(&'a Vec<String>, &'a IR, HashMap<&'a str, usize>),
 }
@@ -49,6 +67,10 @@ impl<'a> PartialEq for Value<'a> {
             (Value::Bool(a), Value::Bool(b)) => a == b,
             (Value::Char(a), Value::Char(b)) => a == b,
             (Value::StringLiteral(a), Value::StringLiteral(b)) => a == b,
+            (Value::Tuple(a), Value::Tuple(b)) => a == b,
+            (Value::Data(tag_a, a), Value::Data(tag_b, b)) => tag_a == tag_b && a == b,
+            (Value::Ref(a), Value::Ref(b)) => a == b,
+            (Value::Array(a, len_a), Value::Array(b, len_b)) => a == b && len_a == len_b,
             _ => false,
         }
     }
@@ -57,6 +57,62 @@ pub enum BuiltinOp {
     U64Or,
     U64Xor,
     U64Not,
+
+    Tuple,
+    Proj(usize),
+    Construct(usize),
+
+    Ref,
+    Deref,
+    Assign,
+    ArrayAlloc,
+    ArrayGet,
+    ArraySet,
+    ArrayLength,
+}
+
+impl BuiltinOp {
+    // memory operations read or write the store, they must be neither
+    // reordered, shared nor removed by optimizations
+    pub fn is_pure(&self) -> bool {
+        !matches!(
+            self,
+            BuiltinOp::Ref
+                | BuiltinOp::Deref
+                | BuiltinOp::Assign
+                | BuiltinOp::ArrayAlloc
+                | BuiltinOp::ArrayGet
+                | BuiltinOp::ArraySet
+                | BuiltinOp::ArrayLength
+        )
+    }
+
+    // arithmetic panics on overflow or division by zero and a projection out of bounds,
+    // the other pure operations cannot fail on arguments of the right type,
+    // so they can be evaluated where the program would not have evaluated them
+    pub fn may_fail(&self) -> bool {
+        use BuiltinOp::*;
+        matches!(
+            self,
+            I32Add
+                | I32Sub
+                | I32Mul
+                | I32Div
+                | I64Add
+                | I64Sub
+                | I64Mul
+                | I64Div
+                | U32Add
+                | U32Sub
+                | U32Mul
+                | U32Div
+                | U64Add
+                | U64Sub
+                | U64Mul
+                | U64Div
+                | Proj(_)
+        ) || !self.is_pure()
+    }
 }
 
 pub fn builtin_call<'a>(op: &'a BuiltinOp, args: Vec<Value<'a>>) -> Value<'a> {
@@ -529,5 +585,80 @@ pub fn builtin_call<'a>(op: &'a BuiltinOp, args: Vec<Value<'a>>) -> Value<'a> {
                 _ => panic!("U64Not: wrong type of arguments"),
             }
         }
+        BuiltinOp::Tuple => Value::Tuple(args),
+        BuiltinOp::Proj(idx) => {
+            if args.len() != 1 {
+                panic!("Proj: requires 1 argument but receives {}", args.len())
+            }
+            match &args[0] {
+                Value::Tuple(fields) => match fields.get(*idx) {
+                    Some(field) => field.clone(),
+                    None => panic!("Proj: index {idx} out of bound"),
+                },
+                _ => panic!("Proj: wrong type of arguments"),
+            }
+        }
+        BuiltinOp::Construct(tag) => Value::Data(*tag, args),
+        BuiltinOp::Ref
+        | BuiltinOp::Deref
+        | BuiltinOp::Assign
+        | BuiltinOp::ArrayAlloc
+        | BuiltinOp::ArrayGet
+        | BuiltinOp::ArraySet
+        | BuiltinOp::ArrayLength => panic!("{op:?}: requires a store"),
+    }
+}
+
+// the range of an integer value, and the value itself
+fn integer(value: &Value) -> Option<((i128, i128), i128)> {
+    match value {
+        Value::I32(v) => Some(((i32::MIN as i128, i32::MAX as i128), *v as i128)),
+        Value::I64(v) => Some(((i64::MIN as i128, i64::MAX as i128), *v as i128)),
+        Value::U32(v) => Some(((0, u32::MAX as i128), *v as i128)),
+        Value::U64(v) => Some(((0, u64::MAX as i128), *v as i128)),
+        _ => None,
+    }
+}
+
+// whether builtin_call evaluates `op` on `args` without panicking or overflowing,
+// so that the call can be folded at compile time
+pub fn can_fold(op: &BuiltinOp, args: &[Value]) -> bool {
+    use BuiltinOp::*;
+    let range = match op {
+        I32Add | I32Sub | I32Mul | I32Div | I32Eq | I32Gt | I32Geq | I32Lt | I32Leq | I32And
+        | I32Or | I32Xor | I32Not => (i32::MIN as i128, i32::MAX as i128),
+        I64Add | I64Sub | I64Mul | I64Div | I64Eq | I64Gt | I64Geq | I64Lt | I64Leq | I64And
+        | I64Or | I64Xor | I64Not => (i64::MIN as i128, i64::MAX as i128),
+        U32Add | U32Sub | U32Mul | U32Div | U32Eq | U32Gt | U32Geq | U32Lt | U32Leq | U32And
+        | U32Or | U32Xor | U32Not => (0, u32::MAX as i128),
+        U64Add | U64Sub | U64Mul | U64Div | U64Eq | U64Gt | U64Geq | U64Lt | U64Leq | U64And
+        | U64Or | U64Xor | U64Not => (0, u64::MAX as i128),
+        _ => return false,
+    };
+    let arity = match op {
+        I32Not | I64Not | U32Not | U64Not => 1,
+        _ => 2,
+    };
+    let mut operands = vec![];
+    for arg in args {
+        match integer(arg) {
+            Some((arg_range, v)) if arg_range == range => operands.push(v),
+            _ => return false,
+        }
+    }
+    if operands.len() != arity {
+        return false;
     }
+    let result = match op {
+        I32Add | I64Add | U32Add | U64Add => operands[0] + operands[1],
+        I32Sub | I64Sub | U32Sub | U64Sub => operands[0] - operands[1],
+        I32Mul | I64Mul | U32Mul | U64Mul => match operands[0].checked_mul(operands[1]) {
+            Some(result) => result,
+            None => return false,
+        },
+        I32Div | I64Div | U32Div | U64Div if operands[1] == 0 => return false,
+        I32Div | I64Div | U32Div | U64Div => operands[0] / operands[1],
+        _ => return true,
+    };
+    range.0 <= result && result <= range.1
 }
@@ -1,5 +1,6 @@
 use super::{Atom, Cont, IR};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
 
 #[derive(Clone)]
 pub enum NodeInfo<'a> {
@@ -33,47 +34,120 @@ impl<'a, L: Lattice> Node<'a, L> {
     }
 }
 
+// the variables in scope during construction, with the label of the lambda bound to them if known
+type Scope<'a> = Vec<(&'a str, Option<usize>)>;
+
 pub trait Lattice: Eq + Clone {
     fn join(a: &Self, b: &Self) -> Self;
     fn bottom() -> Self;
+    // Lattices of infinite height override widen and narrow. run_worklist widens
+    // at the targets of back edges, then narrows there once a fixpoint is reached.
+    fn widen(old: &Self, new: &Self) -> Self {
+        Self::join(old, new)
+    }
+    fn narrow(old: &Self, _new: &Self) -> Self {
+        old.clone()
+    }
+}
+
+
+type EdgeFun<'a, L> = Box<dyn FnMut(usize, &'a IR, Option<usize>, &L) -> L>;
+
+// the number of visits of nodes and of changes of their results during a run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WorklistStats {
+    pub visits: usize,
+    pub updates: usize,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IterationLimitExceeded {
+    pub limit: usize,
+    pub stats: WorklistStats,
+}
+
+impl fmt::Display for IterationLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "worklist did not converge within {} visits", self.limit)
+    }
+}
+
+impl std::error::Error for IterationLimitExceeded {}
+
+// a call site collected during an interprocedural construction
+struct CallSite {
+    node: usize,
+    label: usize,
+    callee: Option<usize>,
+    // nodes of the return and handler continuations
+    return_targets: Vec<usize>,
+}
 
 pub struct NodePool<'a, L: Lattice> {
     nodes: Vec<Node<'a, L>>,
-    worklist: Vec<usize>,
+    // the queued nodes, ordered by their priority
+    worklist: BTreeSet<(usize, usize)>,
+    priority: Vec<usize>,
     direction: bool, // true = foward analysis
     // false = backward analysis
     constraint_fun: Box<dyn FnMut(usize,&'a IR, L) -> L>,
+    // the result of a node along an edge, given the label of the node and of the target if any
+    edge_fun: Option<EdgeFun<'a, L>>,
     fun_entry_table: HashMap<usize, usize>,
     fun_exit_table: HashMap<usize, usize>,
     cont_table: HashMap<String, usize>,
+    label_table: HashMap<usize, usize>,
     prog_entry: usize,
     prog_exit: usize,
+    interprocedural: bool,
+    call_sites: Vec<CallSite>,
+    escaping_conts: Vec<usize>,
+    call_graph: HashMap<usize, Vec<usize>>,
 }
 
 impl<'a, L: Lattice> NodePool<'a, L> {
     pub fn new(direction: bool, constraint_fun: Box<dyn FnMut(usize,&'a IR, L) -> L>) -> Self {
         NodePool {
             nodes: vec![],
-            worklist: vec![],
+            worklist: BTreeSet::new(),
+            priority: vec![],
             direction,
             constraint_fun,
+            edge_fun: None,
             fun_entry_table: HashMap::new(),
             fun_exit_table: HashMap::new(),
             cont_table: HashMap::new(),
+            label_table: HashMap::new(),
             prog_entry: 0,
             prog_exit: 0,
+            interprocedural: false,
+            call_sites: vec![],
+            escaping_conts: vec![],
+            call_graph: HashMap::new(),
         }
     }
     pub fn new_node(&mut self, node: Node<'a, L>) -> usize {
         let count = self.nodes.len();
+        if let NodeInfo::Common(label, _) = node.info {
+            self.label_table.insert(label, count);
+        }
         self.nodes.push(node);
         count
     }
 
+    // restricts what flows along each edge leaving a node, e.g. to mark branches not executable
+    pub fn set_edge_fun(&mut self, edge_fun: EdgeFun<'a, L>) {
+        self.edge_fun = Some(edge_fun);
+    }
+
+    // the input of a node without predecessors, e.g. the environment at the program entry
+    pub fn seed(&mut self, n: usize, input: L) {
+        self.nodes[n].result_in = input;
+    }
+
     pub fn push_worklist(&mut self, n: usize) {
-        self.worklist.push(n);
+        let priority = self.priority.get(n).copied().unwrap_or(n);
+        self.worklist.insert((priority, n));
     }
 
     pub fn add_edge(&mut self, from: usize, to: usize) {
@@ -81,6 +155,27 @@ impl<'a, L: Lattice> NodePool<'a, L> {
         self.nodes[to].predecessors.push(from);
     }
 
+    fn add_cont_edge(&mut self, from: usize, cont: &Cont, exit: usize) {
+        let target = self.cont_target(cont, exit);
+        self.add_edge(from, target);
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn get_info(&self, n: usize) -> &NodeInfo<'a> {
+        &self.nodes[n].info
+    }
+
+    pub fn get_successors(&self, n: usize) -> &[usize] {
+        &self.nodes[n].successors
+    }
+
+    pub fn get_predecessors(&self, n: usize) -> &[usize] {
+        &self.nodes[n].predecessors
+    }
+
     pub fn get_result_in(&self,n: usize) -> &L {
         &self.nodes[n].result_in
     }
@@ -98,28 +193,40 @@ impl<'a, L: Lattice> NodePool<'a, L> {
     }
 
     pub fn get_fun_entry(&self,label:usize) -> usize {
-        self.fun_entry_table.get(&label).expect("unrecognized fun entry").clone()
+        *self.fun_entry_table.get(&label).expect("unrecognized fun entry")
     }
 
     pub fn get_fun_exit(&self,label:usize) -> usize {
-        self.fun_exit_table.get(&label).expect("unrecognized fun exit").clone()
+        *self.fun_exit_table.get(&label).expect("unrecognized fun exit")
+    }
+
+    pub fn get_node(&self, label: usize) -> usize {
+        *self.label_table.get(&label).expect("unrecognized label")
     }
 
     pub fn get_cont(&self, name:&str) -> usize {
-        self.cont_table.get(name).expect("unrecognized cont name").clone()
+        *self.cont_table.get(name).expect("unrecognized cont name")
     }
 
     
-    fn construct_atom(&mut self, a: &'a Atom) {
+    fn construct_atom(&mut self, a: &'a Atom, scope: &mut Scope<'a>) {
         match a {
-            Atom::Lam(label, _args, body) => {
+            Atom::Lam(label, args, body) => {
                 let fun_entry_node = self.new_node(Node::new(NodeInfo::FunEntry(*label)));
                 let fun_exit_node = self.new_node(Node::new(NodeInfo::FunExit(*label)));
                 self.fun_entry_table.insert(*label, fun_entry_node);
                 self.fun_exit_table.insert(*label, fun_exit_node);
                 let body_node = self.new_node(Node::from(body));
                 self.add_edge(fun_entry_node, body_node);
-                self.construct_intra_inner(body, body_node, fun_exit_node);
+                let depth = scope.len();
+                scope.extend(args.iter().map(|arg| (arg.as_str(), None)));
+                self.construct_inner(body, body_node, fun_exit_node, scope);
+                scope.truncate(depth);
+            }
+            Atom::Cont(name) => {
+                if let Some(cont_node) = self.cont_table.get(name) {
+                    self.escaping_conts.push(*cont_node);
+                }
             }
             _ => (),
         }
@@ -133,116 +240,361 @@ impl<'a, L: Lattice> NodePool<'a, L> {
 
         let start_node = self.new_node(Node::from(ir));
         self.add_edge(prog_entry, start_node);
-        self.construct_intra_inner(ir, start_node, prog_exit);
+        self.construct_inner(ir, start_node, prog_exit, &mut vec![]);
+    }
+
+    // Like construct_intra, but call sites are connected to the entries of their callees
+    // and the exits of the callees to the continuations of the call sites.
+    // A callee is known when the operator is a lambda or a variable bound to one by Fix or LetVal,
+    // otherwise every function and every continuation captured as a value is a possible callee.
+    pub fn construct_inter(&mut self, ir: &'a IR) {
+        self.construct_inter_with(ir, &HashMap::new());
+    }
+
+    // Interprocedural construction where the callees of the call sites in `call_graph`
+    // are given, e.g. by a control-flow analysis, the other call sites are resolved as above.
+    pub fn construct_inter_with(
+        &mut self,
+        ir: &'a IR,
+        call_graph: &HashMap<usize, HashSet<usize>>,
+    ) {
+        self.construct_calls_with(ir, call_graph, true);
+    }
+
+    // Like construct_inter, but the exits of the callees are not connected to the call sites,
+    // which keep their edges to their continuations: the state of the caller flows past the
+    // call, the one of the callee does not flow back into the scope of the caller.
+    pub fn construct_local(&mut self, ir: &'a IR) {
+        self.construct_calls_with(ir, &HashMap::new(), false);
+    }
+
+    fn construct_calls_with(
+        &mut self,
+        ir: &'a IR,
+        call_graph: &HashMap<usize, HashSet<usize>>,
+        returns: bool,
+    ) {
+        self.interprocedural = true;
+        self.construct_intra(ir);
+        let mut all_funs: Vec<usize> = self.fun_entry_table.keys().copied().collect();
+        all_funs.sort();
+        for call_site in std::mem::take(&mut self.call_sites) {
+            let CallSite {
+                node,
+                label,
+                callee,
+                return_targets,
+            } = call_site;
+            let (callees, conservative) = match (call_graph.get(&label), callee) {
+                (Some(known), _) => {
+                    let mut callees: Vec<usize> = known.iter().copied().collect();
+                    callees.sort();
+                    (callees, false)
+                }
+                (None, Some(label)) => (vec![label], false),
+                (None, None) => (all_funs.clone(), true),
+            };
+            for label in &callees {
+                self.add_edge(node, self.get_fun_entry(*label));
+                if returns {
+                    let fun_exit_node = self.get_fun_exit(*label);
+                    for target in &return_targets {
+                        self.add_edge(fun_exit_node, *target);
+                    }
+                }
+            }
+            if !returns {
+                for target in return_targets {
+                    self.add_edge(node, target);
+                }
+            }
+            if conservative {
+                for cont_node in self.escaping_conts.clone() {
+                    self.add_edge(node, cont_node);
+                }
+            }
+            self.call_graph.insert(label, callees);
+        }
+    }
+
+    // the labels of the functions possibly called at an App, available after construct_inter
+    pub fn get_callees(&self, label: usize) -> &[usize] {
+        self.call_graph.get(&label).expect("unrecognized call site")
     }
 
-    fn construct_intra_inner(&mut self, ir: &'a IR, node: usize, exit: usize) {
+    fn cont_target(&self, cont: &Cont, exit: usize) -> usize {
+        match cont {
+            Cont::Named(s) => {
+                let Some(cont_node) = self.cont_table.get(s) else {
+                    panic!("continuation not found,named : {}", s);
+                };
+                *cont_node
+            }
+            Cont::Return | Cont::Handler => exit,
+        }
+    }
+
+    fn resolve_callee(f: &Atom, scope: &Scope<'a>) -> Option<usize> {
+        match f {
+            Atom::Lam(label, _, _) => Some(*label),
+            Atom::Var(v) => scope
+                .iter()
+                .rev()
+                .find(|(name, _)| name == v)
+                .and_then(|(_, label)| *label),
+            _ => None,
+        }
+    }
+
+    fn construct_inner(&mut self, ir: &'a IR, node: usize, exit: usize, scope: &mut Scope<'a>) {
+        let depth = scope.len();
         match ir {
-            IR::App(_label, f, args, cont) => {
-                self.construct_atom(f);
+            IR::App(label, f, args, cont, handler) => {
+                self.construct_atom(f, scope);
                 for arg in args {
-                    self.construct_atom(arg);
-                }
-                match cont {
-                    Cont::Named(s) => {
-                        let Some(cont_node) = self.cont_table.get(s) else {
-                            panic!("continuation not found,named : {}", s);
-                        };
-                        self.add_edge(node, *cont_node);
-                    }
-                    Cont::Return => {
-                        self.add_edge(node, exit);
-                    }
+                    self.construct_atom(arg, scope);
+                }
+                if self.interprocedural {
+                    self.call_sites.push(CallSite {
+                        node,
+                        label: *label,
+                        callee: Self::resolve_callee(f, scope),
+                        return_targets: vec![
+                            self.cont_target(cont, exit),
+                            self.cont_target(handler, exit),
+                        ],
+                    });
+                } else {
+                    self.add_cont_edge(node, cont, exit);
+                    self.add_cont_edge(node, handler, exit);
                 }
             }
             IR::AppCont(_label, cont, args) => {
                 for arg in args {
-                    self.construct_atom(arg);
-                }
-                match cont {
-                    Cont::Named(s) => {
-                        let Some(cont_node) = self.cont_table.get(s) else {
-                            panic!("continuation not found,named : {}", s);
-                        };
-                        self.add_edge(node, *cont_node);
-                    }
-                    Cont::Return => {
-                        self.add_edge(node, exit);
-                    }
+                    self.construct_atom(arg, scope);
                 }
+                self.add_cont_edge(node, cont, exit);
             }
-            IR::Fix(_label, _vars, vals, body) => {
+            IR::Case(_label, scrutinee, branches, default) => {
+                self.construct_atom(scrutinee, scope);
+                for cont in branches.iter().map(|(_tag, cont)| cont).chain(default) {
+                    self.add_cont_edge(node, cont, exit);
+                }
+            }
+            IR::Fix(_label, vars, vals, body) => {
+                // the bindings of a Fix are visible in its own values
+                for (var, val) in vars.iter().zip(vals) {
+                    scope.push((var.as_str(), Self::resolve_callee(val, scope)));
+                }
                 for val in vals {
-                    self.construct_atom(val);
+                    self.construct_atom(val, scope);
                 }
                 let body_node = self.new_node(Node::from(body));
                 self.add_edge(node, body_node);
-                self.construct_intra_inner(body, body_node, exit);
+                self.construct_inner(body, body_node, exit, scope);
             }
 
             IR::If(_label, test, then_, else_) => {
-                self.construct_atom(test);
+                self.construct_atom(test, scope);
                 let then_node = self.new_node(Node::from(then_));
                 let else_node = self.new_node(Node::from(else_));
                 self.add_edge(node, then_node);
                 self.add_edge(node, else_node);
-                self.construct_intra_inner(then_, then_node, exit);
-                self.construct_intra_inner(else_, else_node, exit);
+                self.construct_inner(then_, then_node, exit, scope);
+                self.construct_inner(else_, else_node, exit, scope);
             }
-            IR::Let(_label, _var, _op, args, body) => {
+            IR::Let(_label, var, _op, args, body) => {
                 for arg in args {
-                    self.construct_atom(arg);
+                    self.construct_atom(arg, scope);
                 }
                 let body_node = self.new_node(Node::from(body));
                 self.add_edge(node, body_node);
-                self.construct_intra_inner(body, body_node, exit);
+                scope.push((var.as_str(), None));
+                self.construct_inner(body, body_node, exit, scope);
             }
-            IR::LetVal(_label, _var, atm, body) => {
-                self.construct_atom(atm);
+            IR::LetVal(_label, var, atm, body) => {
+                self.construct_atom(atm, scope);
                 let body_node = self.new_node(Node::from(body));
                 self.add_edge(node, body_node);
-                self.construct_intra_inner(body, body_node, exit);
+                scope.push((var.as_str(), Self::resolve_callee(atm, scope)));
+                self.construct_inner(body, body_node, exit, scope);
             }
-            IR::LetCont(_label, cont_name, _args, cont_body, body) => {
+            IR::LetCont(_label, cont_name, args, cont_body, body) => {
                 let body_node = self.new_node(Node::from(body));
                 self.add_edge(node, body_node);
                 let cont_body_node = self.new_node(Node::from(cont_body));
                 self.cont_table.insert(cont_name.clone(), cont_body_node);
-                self.construct_intra_inner(body, body_node, exit);
-                self.construct_intra_inner(cont_body, cont_body_node, exit);
+                self.construct_inner(body, body_node, exit, scope);
+                scope.extend(args.iter().map(|arg| (arg.as_str(), None)));
+                self.construct_inner(cont_body, cont_body_node, exit, scope);
             }
         }
+        scope.truncate(depth);
     }
 
-    pub fn run_worklist(&mut self) {
-        self.worklist = (0..self.nodes.len()).collect();
-        while !self.worklist.is_empty() {
-            let Some(node) = self.worklist.pop() else {
-                unreachable!()
-            };
-            let input = self.nodes[node].result_in.clone();
-            let result = match self.nodes[node].info {
-                NodeInfo::ProgramEntry => input,
-                NodeInfo::ProgramExit => input,
-                NodeInfo::FunEntry(_) =>input,
-                NodeInfo::FunExit(_)=>input,
-                NodeInfo::Common(label,ir) => (self.constraint_fun)(label,ir, input)
-            };
-            if &result != &self.nodes[node].result_out {
-                self.nodes[node].result_out = result;
-                let successors = if self.direction {
-                    self.nodes[node].successors.clone()
-                } else {
-                    self.nodes[node].predecessors.clone()
+    // the nodes in reverse postorder of a depth first search,
+    // started from the nodes without predecessors, then from any node not yet visited
+    fn reverse_postorder(&self) -> Vec<usize> {
+        let mut visited = vec![false; self.nodes.len()];
+        let mut order = vec![];
+        let roots = (0..self.nodes.len()).filter(|n| self.nodes[*n].predecessors.is_empty());
+        for root in roots.chain(0..self.nodes.len()).collect::<Vec<_>>() {
+            if visited[root] {
+                continue;
+            }
+            visited[root] = true;
+            let mut stack = vec![(root, 0)];
+            while let Some((n, i)) = stack.pop() {
+                match self.nodes[n].successors.get(i) {
+                    Some(succ) => {
+                        stack.push((n, i + 1));
+                        if !visited[*succ] {
+                            visited[*succ] = true;
+                            stack.push((*succ, 0));
+                        }
+                    }
+                    None => order.push(n),
+                }
+            }
+        }
+        order.reverse();
+        order
+    }
+
+    // Nodes are visited in reverse postorder for a forward analysis, in postorder for
+    // a backward one, and a node is queued at most once.
+    pub fn run_worklist(&mut self) -> WorklistStats {
+        match self.run(None) {
+            Ok(stats) => stats,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    // Like run_worklist, but gives up after `max_visits` visits of nodes,
+    // e.g. when the constraint function is not monotone.
+    pub fn run_worklist_bounded(
+        &mut self,
+        max_visits: usize,
+    ) -> Result<WorklistStats, IterationLimitExceeded> {
+        self.run(Some(max_visits))
+    }
+
+    // the result of a node for the input of `target`
+    fn edge_out(&mut self, node: usize, target: usize) -> L {
+        match (&mut self.edge_fun, &self.nodes[node].info) {
+            (Some(edge_fun), NodeInfo::Common(label, ir)) => {
+                let target = match self.nodes[target].info {
+                    NodeInfo::Common(target, _) => Some(target),
+                    _ => None,
                 };
-                for succ in successors {
-                    let new = L::join(&self.nodes[succ].result_in, &self.nodes[node].result_out);
-                    if &new != &self.nodes[succ].result_in {
+                edge_fun(*label, ir, target, &self.nodes[node].result_out)
+            }
+            _ => self.nodes[node].result_out.clone(),
+        }
+    }
+
+    fn transfer(&mut self, node: usize) -> L {
+        let input = self.nodes[node].result_in.clone();
+        match self.nodes[node].info {
+            NodeInfo::ProgramEntry => input,
+            NodeInfo::ProgramExit => input,
+            NodeInfo::FunEntry(_) =>input,
+            NodeInfo::FunExit(_)=>input,
+            NodeInfo::Common(label,ir) => (self.constraint_fun)(label,ir, input)
+        }
+    }
+
+    // the nodes whose results flow into `node`, and the nodes `node` flows into
+    fn flow(&self, node: usize) -> (Vec<usize>, Vec<usize>) {
+        let (preds, succs) = (&self.nodes[node].predecessors, &self.nodes[node].successors);
+        if self.direction {
+            (preds.clone(), succs.clone())
+        } else {
+            (succs.clone(), preds.clone())
+        }
+    }
+
+    fn run(&mut self, max_visits: Option<usize>) -> Result<WorklistStats, IterationLimitExceeded> {
+        let mut order = self.reverse_postorder();
+        if !self.direction {
+            order.reverse();
+        }
+        self.priority = vec![0; self.nodes.len()];
+        for (i, n) in order.iter().enumerate() {
+            self.priority[*n] = i;
+        }
+        // the targets of back edges, the nodes following their sources in the order
+        let loop_heads: HashSet<usize> = order
+            .iter()
+            .flat_map(|n| self.flow(*n).1.into_iter().map(move |succ| (*n, succ)))
+            .filter(|(n, succ)| self.priority[*succ] <= self.priority[*n])
+            .map(|(_, succ)| succ)
+            .collect();
+        let mut stats = WorklistStats::default();
+        let visit = |stats: &mut WorklistStats| match max_visits {
+            Some(limit) if stats.visits >= limit => Err(IterationLimitExceeded {
+                limit,
+                stats: *stats,
+            }),
+            _ => {
+                stats.visits += 1;
+                Ok(())
+            }
+        };
+
+        self.worklist = order.iter().map(|n| (self.priority[*n], *n)).collect();
+        while let Some((_, node)) = self.worklist.pop_first() {
+            if let Err(error) = visit(&mut stats) {
+                self.worklist.clear();
+                return Err(error);
+            }
+            let result = self.transfer(node);
+            if result != self.nodes[node].result_out {
+                stats.updates += 1;
+                self.nodes[node].result_out = result;
+                for succ in self.flow(node).1 {
+                    let out = self.edge_out(node, succ);
+                    let old = &self.nodes[succ].result_in;
+                    let mut new = L::join(old, &out);
+                    if loop_heads.contains(&succ) {
+                        new = L::widen(old, &new);
+                    }
+                    if new != self.nodes[succ].result_in {
                         self.nodes[succ].result_in = new;
                         self.push_worklist(succ);
                     }
                 }
             }
         }
+
+        // descending iterations, recomputing the inputs and narrowing at the loop heads
+        let mut changed = !loop_heads.is_empty();
+        while changed {
+            changed = false;
+            for node in order.iter().copied() {
+                visit(&mut stats)?;
+                let sources = self.flow(node).0;
+                if !sources.is_empty() {
+                    let mut input = L::bottom();
+                    for source in sources {
+                        input = L::join(&input, &self.edge_out(source, node));
+                    }
+                    if loop_heads.contains(&node) {
+                        input = L::narrow(&self.nodes[node].result_in, &input);
+                    }
+                    if input != self.nodes[node].result_in {
+                        self.nodes[node].result_in = input;
+                        changed = true;
+                    }
+                }
+                let result = self.transfer(node);
+                if result != self.nodes[node].result_out {
+                    stats.updates += 1;
+                    self.nodes[node].result_out = result;
+                    changed = true;
+                }
+            }
+        }
+        Ok(stats)
     }
 }
@@ -0,0 +1,209 @@
+// Dominator trees, dominance frontiers and natural loops over the graph of a NodePool,
+// computed with the iterative algorithm of Cooper, Harvey and Kennedy.
+// Post-dominators are the dominators of the reversed graph, rooted at an exit.
+// An intraprocedural graph has no call edges, so a function bound by Fix only forms
+// a loop, headed by its FunEntry, in an interprocedural graph.
+
+use super::cfg::{Lattice, NodePool};
+use std::collections::HashSet;
+
+pub struct DomTree {
+    root: usize,
+    // the immediate dominator of each node reachable from the root, the root is its own
+    idom: Vec<Option<usize>>,
+    children: Vec<Vec<usize>>,
+    frontiers: Vec<HashSet<usize>>,
+}
+
+impl DomTree {
+    fn new(root: usize, node_count: usize, succs: &dyn Fn(usize) -> Vec<usize>) -> Self {
+        let mut preds = vec![vec![]; node_count];
+        for n in 0..node_count {
+            for succ in succs(n) {
+                preds[succ].push(n);
+            }
+        }
+
+        // reverse postorder from the root
+        let mut order = vec![];
+        let mut visited = vec![false; node_count];
+        let mut stack = vec![(root, 0)];
+        visited[root] = true;
+        while let Some((n, i)) = stack.pop() {
+            let next = succs(n).get(i).copied();
+            match next {
+                Some(succ) => {
+                    stack.push((n, i + 1));
+                    if !visited[succ] {
+                        visited[succ] = true;
+                        stack.push((succ, 0));
+                    }
+                }
+                None => order.push(n),
+            }
+        }
+        order.reverse();
+        let mut rpo_index = vec![usize::MAX; node_count];
+        for (i, n) in order.iter().enumerate() {
+            rpo_index[*n] = i;
+        }
+
+        let mut idom = vec![None; node_count];
+        idom[root] = Some(root);
+        let intersect = |idom: &[Option<usize>], mut a: usize, mut b: usize| {
+            while a != b {
+                while rpo_index[a] > rpo_index[b] {
+                    a = idom[a].unwrap();
+                }
+                while rpo_index[b] > rpo_index[a] {
+                    b = idom[b].unwrap();
+                }
+            }
+            a
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for n in order.iter().skip(1) {
+                let mut new_idom = None;
+                for pred in preds[*n].iter().filter(|pred| idom[**pred].is_some()) {
+                    new_idom = match new_idom {
+                        None => Some(*pred),
+                        Some(other) => Some(intersect(&idom, *pred, other)),
+                    };
+                }
+                if new_idom != idom[*n] {
+                    idom[*n] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut children = vec![vec![]; node_count];
+        for n in order.iter().skip(1) {
+            children[idom[*n].unwrap()].push(*n);
+        }
+        let mut frontiers = vec![HashSet::new(); node_count];
+        for n in &order {
+            let reachable: Vec<usize> = preds[*n]
+                .iter()
+                .copied()
+                .filter(|pred| idom[*pred].is_some())
+                .collect();
+            if reachable.len() < 2 {
+                continue;
+            }
+            for pred in reachable {
+                let mut runner = pred;
+                while Some(runner) != idom[*n] {
+                    frontiers[runner].insert(*n);
+                    if runner == root {
+                        break;
+                    }
+                    runner = idom[runner].unwrap();
+                }
+            }
+        }
+        DomTree {
+            root,
+            idom,
+            children,
+            frontiers,
+        }
+    }
+
+    pub fn root(&self) -> usize {
+        self.root
+    }
+
+    pub fn is_reachable(&self, n: usize) -> bool {
+        self.idom[n].is_some()
+    }
+
+    // None for the root and for nodes not reachable from it
+    pub fn idom(&self, n: usize) -> Option<usize> {
+        self.idom[n].filter(|_| n != self.root)
+    }
+
+    pub fn children(&self, n: usize) -> &[usize] {
+        &self.children[n]
+    }
+
+    // whether every path from the root to `b` goes through `a`, a node dominates itself
+    pub fn dominates(&self, a: usize, b: usize) -> bool {
+        if !self.is_reachable(b) {
+            return false;
+        }
+        let mut n = b;
+        loop {
+            if n == a {
+                return true;
+            }
+            match self.idom(n) {
+                Some(parent) => n = parent,
+                None => return false,
+            }
+        }
+    }
+
+    pub fn frontier(&self, n: usize) -> &HashSet<usize> {
+        &self.frontiers[n]
+    }
+}
+
+// a loop with a single entry, the header, which dominates the sources of its back edges
+#[derive(Debug)]
+pub struct Loop {
+    pub header: usize,
+    pub latches: Vec<usize>,
+    pub body: HashSet<usize>,
+}
+
+impl<'a, L: Lattice> NodePool<'a, L> {
+    pub fn dominators(&self, entry: usize) -> DomTree {
+        DomTree::new(entry, self.node_count(), &|n| {
+            self.get_successors(n).to_vec()
+        })
+    }
+
+    pub fn post_dominators(&self, exit: usize) -> DomTree {
+        DomTree::new(exit, self.node_count(), &|n| {
+            self.get_predecessors(n).to_vec()
+        })
+    }
+
+    // the natural loops reachable from `entry`, one per header, ordered by header
+    pub fn natural_loops(&self, entry: usize) -> Vec<Loop> {
+        let tree = self.dominators(entry);
+        let mut loops: Vec<Loop> = vec![];
+        for n in (0..self.node_count()).filter(|n| tree.is_reachable(*n)) {
+            for header in self.get_successors(n) {
+                if !tree.dominates(*header, n) {
+                    continue;
+                }
+                let index = match loops.iter().position(|l| l.header == *header) {
+                    Some(index) => index,
+                    None => {
+                        loops.push(Loop {
+                            header: *header,
+                            latches: vec![],
+                            body: HashSet::from([*header]),
+                        });
+                        loops.len() - 1
+                    }
+                };
+                let l = &mut loops[index];
+                l.latches.push(n);
+                let mut worklist = vec![n];
+                while let Some(m) = worklist.pop() {
+                    if l.body.insert(m) {
+                        let preds = self.get_predecessors(m).iter();
+                        worklist.extend(preds.filter(|pred| tree.is_reachable(**pred)));
+                    }
+                }
+            }
+        }
+        loops.sort_by_key(|l| l.header);
+        loops
+    }
+}
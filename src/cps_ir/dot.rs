@@ -0,0 +1,88 @@
+// Graphviz export of the graph of a NodePool, for debugging the construction
+// and the results of analyses.
+
+use super::cfg::{Lattice, NodeInfo, NodePool};
+use super::{Atom, Cont, IR};
+
+fn atom(a: &Atom) -> String {
+    match a {
+        Atom::Var(v) => v.clone(),
+        Atom::I32(v) => v.to_string(),
+        Atom::I64(v) => format!("{v}i64"),
+        Atom::U32(v) => format!("{v}u32"),
+        Atom::U64(v) => format!("{v}u64"),
+        Atom::Bool(v) => v.to_string(),
+        Atom::Char(v) => format!("{v:?}"),
+        Atom::StringLiteral(v) => format!("{v:?}"),
+        Atom::Lam(label, args, _) => format!("lam{label}({})", args.join(", ")),
+        Atom::Cont(name) => name.clone(),
+    }
+}
+
+fn atoms(args: &[Atom]) -> String {
+    args.iter().map(atom).collect::<Vec<_>>().join(", ")
+}
+
+fn cont(k: &Cont) -> &str {
+    match k {
+        Cont::Named(name) => name,
+        Cont::Return => "return",
+        Cont::Handler => "handler",
+    }
+}
+
+// the head of an IR, without its sub terms
+fn head(ir: &IR) -> String {
+    match ir {
+        IR::LetCont(_, name, args, _, _) => format!("letcont {name}({})", args.join(", ")),
+        IR::Let(_, var, op, args, _) => format!("let {var} = {op:?}({})", atoms(args)),
+        IR::LetVal(_, var, val, _) => format!("letval {var} = {}", atom(val)),
+        IR::If(_, test, _, _) => format!("if {}", atom(test)),
+        IR::App(_, f, args, k, h) => {
+            format!("{}({}) -> {}, {}", atom(f), atoms(args), cont(k), cont(h))
+        }
+        IR::Fix(_, vars, _, _) => format!("fix {}", vars.join(", ")),
+        IR::AppCont(_, k, args) => format!("{}({})", cont(k), atoms(args)),
+        IR::Case(_, scrutinee, branches, default) => {
+            let mut conts: Vec<&str> = branches.iter().map(|(_, k)| cont(k)).collect();
+            conts.extend(default.iter().map(cont));
+            format!("case {} -> {}", atom(scrutinee), conts.join(", "))
+        }
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl<'a, L: Lattice> NodePool<'a, L> {
+    // a DOT digraph with a node per graph node, labeled by its kind or its IR,
+    // `formatter` annotates the nodes with their results
+    pub fn to_dot(&self, formatter: Option<&dyn Fn(&L) -> String>) -> String {
+        let mut dot = String::from("digraph cfg {\n    node [shape=box];\n");
+        for n in 0..self.node_count() {
+            let mut label = match self.get_info(n) {
+                NodeInfo::ProgramEntry => "program entry".to_string(),
+                NodeInfo::ProgramExit => "program exit".to_string(),
+                NodeInfo::FunEntry(label) => format!("entry lam{label}"),
+                NodeInfo::FunExit(label) => format!("exit lam{label}"),
+                NodeInfo::Common(label, ir) => format!("{label}: {}", head(ir)),
+            };
+            if let Some(formatter) = formatter {
+                label.push_str(&format!("\nin: {}", formatter(self.get_result_in(n))));
+                label.push_str(&format!("\nout: {}", formatter(self.get_result_out(n))));
+            }
+            dot.push_str(&format!(
+                "    n{n} [label=\"{}\"];\n",
+                escape(&label).replace('\n', "\\n")
+            ));
+        }
+        for n in 0..self.node_count() {
+            for succ in self.get_successors(n) {
+                dot.push_str(&format!("    n{n} -> n{succ};\n"));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
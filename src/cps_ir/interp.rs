@@ -1,11 +1,23 @@
-use super::{Atom, Cont, IR, Value, builtin_call};
+use super::{Atom, CaseTag, Cont, IR, Value, builtin_call, builtin_call::BuiltinOp};
 use std::collections::HashMap;
 
+// a function body refers to the continuation it returns to through this binding,
+// a missing binding means returning from the whole program
+pub(super) const RETURN_CONT: &str = "%ret";
+// likewise for the exception handler, a missing binding means the exception is uncaught
+pub(super) const HANDLER_CONT: &str = "%handler";
+
 pub struct Store<'a> {
     pub mem: Vec<Value<'a>>,
     pub count: usize,
 }
 
+impl<'a> Default for Store<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<'a> Store<'a> {
     pub fn new() -> Store<'a> {
         Store {
@@ -28,9 +40,72 @@ impl<'a> Store<'a> {
     pub fn get_count(&self) -> usize {
         self.count
     }
-    pub fn set_mem(&mut self, idx: usize, v: Value<'a>) -> () {
+    pub fn set_mem(&mut self, idx: usize, v: Value<'a>) {
         self.mem[idx] = v;
     }
+
+    // references occupy one cell of the store, arrays a contiguous block of cells
+    pub fn memory_call(&mut self, op: &BuiltinOp, args: Vec<Value<'a>>) -> Value<'a> {
+        let arity = match op {
+            BuiltinOp::Ref | BuiltinOp::Deref | BuiltinOp::ArrayLength => 1,
+            BuiltinOp::Assign | BuiltinOp::ArrayAlloc | BuiltinOp::ArrayGet => 2,
+            BuiltinOp::ArraySet => 3,
+            _ => panic!("{op:?}: not a memory operation"),
+        };
+        if args.len() != arity {
+            panic!(
+                "{op:?}: requires {arity} arguments but receives {}",
+                args.len()
+            )
+        }
+        match (op, &args[..]) {
+            (BuiltinOp::Ref, [init]) => Value::Ref(self.alloc(init.clone())),
+            (BuiltinOp::Deref, [Value::Ref(addr)]) => self.get(*addr),
+            (BuiltinOp::Assign, [Value::Ref(addr), val]) => {
+                self.set_mem(*addr, val.clone());
+                Value::Tuple(vec![])
+            }
+            (BuiltinOp::ArrayAlloc, [len, init]) => {
+                let Some(len) = as_index(len) else {
+                    panic!("ArrayAlloc: length should be a non-negative integer")
+                };
+                let base = self.get_count();
+                for _ in 0..len {
+                    self.alloc(init.clone());
+                }
+                Value::Array(base, len)
+            }
+            (BuiltinOp::ArrayGet, [Value::Array(base, len), idx]) => {
+                self.get(base + checked_index("ArrayGet", idx, *len))
+            }
+            (BuiltinOp::ArraySet, [Value::Array(base, len), idx, val]) => {
+                self.set_mem(base + checked_index("ArraySet", idx, *len), val.clone());
+                Value::Tuple(vec![])
+            }
+            (BuiltinOp::ArrayLength, [Value::Array(_base, len)]) => match i32::try_from(*len) {
+                Ok(len) => Value::I32(len),
+                Err(_) => panic!("ArrayLength: length {len} does not fit in i32"),
+            },
+            _ => panic!("{op:?}: wrong type of arguments"),
+        }
+    }
+}
+
+fn as_index(v: &Value) -> Option<usize> {
+    match v {
+        Value::I32(i) => usize::try_from(*i).ok(),
+        Value::I64(i) => usize::try_from(*i).ok(),
+        Value::U32(i) => usize::try_from(*i).ok(),
+        Value::U64(i) => usize::try_from(*i).ok(),
+        _ => None,
+    }
+}
+
+fn checked_index(op: &str, idx: &Value, len: usize) -> usize {
+    match as_index(idx) {
+        Some(i) if i < len => i,
+        _ => panic!("{op}: index {idx:?} out of bound {len}"),
+    }
 }
 
 pub fn interp_atom<'a>(
@@ -43,34 +118,42 @@ pub fn interp_atom<'a>(
             Some(addr) => store.get(*addr),
             None => panic!("unbound variable: {name}"),
         },
-        Atom::I32(v) => Value::I32(v.clone()),
-        Atom::I64(v) => Value::I64(v.clone()),
-        Atom::U32(v) => Value::U32(v.clone()),
-        Atom::U64(v) => Value::U64(v.clone()),
-        Atom::Bool(v) => Value::Bool(v.clone()),
-        Atom::Char(v) => Value::Char(v.clone()),
+        Atom::I32(v) => Value::I32(*v),
+        Atom::I64(v) => Value::I64(*v),
+        Atom::U32(v) => Value::U32(*v),
+        Atom::U64(v) => Value::U64(*v),
+        Atom::Bool(v) => Value::Bool(*v),
+        Atom::Char(v) => Value::Char(*v),
         Atom::StringLiteral(v) => Value::StringLiteral(v.clone()),
         Atom::Lam(_label, args, ir) => Value::Clo(args, ir, env.clone()),
+        Atom::Cont(name) => match env.get(name.as_str()) {
+            Some(addr) => store.get(*addr),
+            None => panic!("unbound continuation: {name}"),
+        },
     }
 }
 
-pub fn apply_cont_by_name<'a>(
-    cont_name: &'a str,
-    values: Vec<Value<'a>>,
-    env: HashMap<&'a str, usize>,
-    store: &mut Store<'a>,
-) -> Value<'a> {
-    let Some(cont_addr) = env.get(cont_name) else {
-        panic!("unbound continuation: {cont_name}");
-    };
-    match store.get(*cont_addr) {
+// where control goes after passing values to a continuation
+pub enum Jump<'a> {
+    Enter(&'a IR, HashMap<&'a str, usize>),
+    Halt(Value<'a>),
+}
+
+pub fn enter_cont<'a>(cont: Value<'a>, values: Vec<Value<'a>>, store: &mut Store<'a>) -> Jump<'a> {
+    match cont {
         Value::Cont(args, body, env) => {
+            if args.len() != values.len() {
+                panic!(
+                    "apply_cont: requires {} arguments but receives {}",
+                    args.len(),
+                    values.len()
+                );
+            }
             let mut new_env = env;
-            let mut values_ = values;
-            for (i, val) in values_.drain(..).enumerate() {
-                new_env.insert(&args[i], store.alloc(val));
+            for (arg, val) in args.iter().zip(values) {
+                new_env.insert(arg, store.alloc(val));
             }
-            interp(body, new_env, store)
+            Jump::Enter(body, new_env)
         }
         _ => {
             panic!("apply_cont: not a continuation");
@@ -78,87 +161,151 @@ pub fn apply_cont_by_name<'a>(
     }
 }
 
+// the address of the continuation value denoted by `cont`,
+// None stands for leaving the whole program
+pub fn resolve_cont(cont: &Cont, env: &HashMap<&str, usize>) -> Option<usize> {
+    match cont {
+        Cont::Named(cont_name) => match env.get(cont_name.as_str()) {
+            Some(addr) => Some(*addr),
+            None => panic!("unbound continuation: {cont_name}"),
+        },
+        Cont::Return => env.get(RETURN_CONT).copied(),
+        Cont::Handler => env.get(HANDLER_CONT).copied(),
+    }
+}
+
 pub fn apply_cont<'a>(
-    cont: &'a Cont,
+    cont: &Cont,
     values: Vec<Value<'a>>,
-    env: HashMap<&'a str, usize>,
+    env: &HashMap<&'a str, usize>,
     store: &mut Store<'a>,
-) -> Value<'a> {
-    match cont {
-        Cont::Named(cont_name) => apply_cont_by_name(&cont_name, values, env, store),
-        Cont::Return => values[0].clone(),
+) -> Jump<'a> {
+    match (resolve_cont(cont, env), cont) {
+        (Some(addr), _) => enter_cont(store.get(addr), values, store),
+        (None, Cont::Handler) => panic!("uncaught exception: {:?}", values[0]),
+        (None, _) => Jump::Halt(values[0].clone()),
     }
 }
 
+// the interpreter runs as a loop: every transfer of control is a tail call,
+// so escaping through a continuation value simply replaces the current state
 pub fn interp<'a>(ir: &'a IR, env: HashMap<&'a str, usize>, store: &mut Store<'a>) -> Value<'a> {
-    match ir {
-        IR::LetCont(_label, cont_name, args, cont_body, body) => {
-            let mut new_env = env.clone();
-            new_env.insert(cont_name, store.alloc(Value::Cont(args, cont_body, env)));
-            interp(body, new_env, store)
-        }
-        IR::Let(_label, bind, prim, args, body) => {
-            let args_value = args
-                .iter()
-                .map(|a| interp_atom(a, &env, store))
-                .collect::<Vec<Value<'a>>>();
-            let result = builtin_call(prim, args_value);
-            let mut new_env = env;
-            new_env.insert(&bind, store.alloc(result));
-            interp(body, new_env, store)
-        }
-        IR::LetVal(_label, var, val, body) => {
-            let value = interp_atom(val, &env, store);
-            let mut new_env = env;
-            new_env.insert(var, store.alloc(value));
-            interp(body, new_env, store)
-        }
-        IR::If(_label, test, then_, else_) => match interp_atom(test, &env, store) {
-            Value::Bool(b) => {
-                if b {
-                    interp(then_, env, store)
+    let mut ir = ir;
+    let mut env = env;
+    loop {
+        let jump = match ir {
+            // the continuation is in scope in its own body, so it can jump to itself
+            IR::LetCont(_label, cont_name, args, cont_body, body) => {
+                let mut new_env = env;
+                let addr = store.alloc(Value::Bool(false));
+                new_env.insert(cont_name, addr);
+                store.set_mem(addr, Value::Cont(args, cont_body, new_env.clone()));
+                Jump::Enter(body, new_env)
+            }
+            IR::Let(_label, bind, prim, args, body) => {
+                let args_value = args
+                    .iter()
+                    .map(|a| interp_atom(a, &env, store))
+                    .collect::<Vec<Value<'a>>>();
+                let result = if prim.is_pure() {
+                    builtin_call(prim, args_value)
                 } else {
-                    interp(else_, env, store)
-                }
+                    store.memory_call(prim, args_value)
+                };
+                let mut new_env = env;
+                new_env.insert(bind, store.alloc(result));
+                Jump::Enter(body, new_env)
             }
-            _ => {
-                panic!("if: test should be a boolean")
+            IR::LetVal(_label, var, val, body) => {
+                let value = interp_atom(val, &env, store);
+                let mut new_env = env;
+                new_env.insert(var, store.alloc(value));
+                Jump::Enter(body, new_env)
             }
-        },
-        IR::App(_label, f, args, cont) => {
-            let f_value = interp_atom(f, &env, store);
-            let Value::Clo(vars, body, clo_env) = f_value else {
-                panic!("application: not a function");
-            };
-            let mut new_env = clo_env;
-            for (arg, var) in args.iter().zip(vars.iter()) {
-                let val = interp_atom(arg, &env, store);
-                new_env.insert(var, store.alloc(val));
+            IR::If(_label, test, then_, else_) => match interp_atom(test, &env, store) {
+                Value::Bool(b) => {
+                    if b {
+                        Jump::Enter(then_, env)
+                    } else {
+                        Jump::Enter(else_, env)
+                    }
+                }
+                _ => {
+                    panic!("if: test should be a boolean")
+                }
+            },
+            IR::App(_label, f, args, cont, handler) => {
+                let f_value = interp_atom(f, &env, store);
+                let args_value = args
+                    .iter()
+                    .map(|arg| interp_atom(arg, &env, store))
+                    .collect::<Vec<Value<'a>>>();
+                match f_value {
+                    Value::Clo(vars, body, clo_env) => {
+                        let mut new_env = clo_env;
+                        for (var, val) in vars.iter().zip(args_value) {
+                            new_env.insert(var, store.alloc(val));
+                        }
+                        for (name, cont) in [(RETURN_CONT, cont), (HANDLER_CONT, handler)] {
+                            match resolve_cont(cont, &env) {
+                                Some(addr) => new_env.insert(name, addr),
+                                None => new_env.remove(name),
+                            };
+                        }
+                        Jump::Enter(body, new_env)
+                    }
+                    // invoking a captured continuation abandons the current one
+                    Value::Cont(..) => enter_cont(f_value, args_value, store),
+                    _ => panic!("application: not a function"),
+                }
             }
-            let result = interp(body, new_env, store);
-            apply_cont(cont, vec![result], env, store)
-        }
-        IR::Fix(_label, vars, vals, body) => {
-            let mut new_env = env;
-            let mut offset = store.get_count();
-            for var in vars {
-                new_env.insert(&var, store.alloc(Value::Bool(false)));
+            IR::Fix(_label, vars, vals, body) => {
+                let mut new_env = env;
+                let mut offset = store.get_count();
+                for var in vars {
+                    new_env.insert(var, store.alloc(Value::Bool(false)));
+                }
+                for val in vals {
+                    store.set_mem(offset, interp_atom(val, &new_env, store));
+                    offset += 1;
+                }
+                Jump::Enter(body, new_env)
             }
-            for val in vals {
-                store.set_mem(offset, interp_atom(val, &new_env, store));
-                offset += 1;
+            IR::Case(_label, scrutinee, branches, default) => {
+                let scrutinee_value = interp_atom(scrutinee, &env, store);
+                let selected =
+                    branches
+                        .iter()
+                        .find_map(|(tag, cont)| match (tag, &scrutinee_value) {
+                            (CaseTag::Tag(t), Value::Data(tag_value, fields)) if t == tag_value => {
+                                Some((cont, fields.clone()))
+                            }
+                            (CaseTag::I32(a), Value::I32(b)) if a == b => Some((cont, vec![])),
+                            (CaseTag::I64(a), Value::I64(b)) if a == b => Some((cont, vec![])),
+                            (CaseTag::U32(a), Value::U32(b)) if a == b => Some((cont, vec![])),
+                            (CaseTag::U64(a), Value::U64(b)) if a == b => Some((cont, vec![])),
+                            _ => None,
+                        });
+                match (selected, default) {
+                    (Some((cont, values)), _) => apply_cont(cont, values, &env, store),
+                    (None, Some(cont)) => apply_cont(cont, vec![], &env, store),
+                    (None, None) => panic!("case: no branch matches"),
+                }
             }
-            interp(body, new_env, store)
-        }
-        IR::AppCont(_label, cont, args) => match cont {
-            Cont::Return => interp_atom(&args[0], &env, store),
-            Cont::Named(name) => {
+            IR::AppCont(_label, cont, args) => {
                 let values = args
                     .iter()
                     .map(|arg| interp_atom(arg, &env, store))
                     .collect();
-                apply_cont_by_name(&name, values, env, store)
+                apply_cont(cont, values, &env, store)
             }
-        },
+        };
+        match jump {
+            Jump::Enter(next, next_env) => {
+                ir = next;
+                env = next_env;
+            }
+            Jump::Halt(value) => break value,
+        }
     }
 }
@@ -1,14 +1,16 @@
 use super::{Atom, builtin_call::BuiltinOp};
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, collections::HashSet, rc::Rc};
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum IR {
+    // the continuation is recursive, its body may jump to itself
     LetCont(usize, String, Vec<String>, Box<IR>, Box<IR>),
     Let(usize, String, BuiltinOp, Vec<Atom>, Box<IR>),
     LetVal(usize, String, Atom, Box<IR>),
     If(usize, Atom, Box<IR>, Box<IR>),
-    App(usize, Atom, Vec<Atom>, Cont),
+    App(usize, Atom, Vec<Atom>, Cont, Cont),
     Fix(usize, Vec<String>, Vec<Atom>, Box<IR>),
     AppCont(usize, Cont, Vec<Atom>),
+    Case(usize, Atom, Vec<(CaseTag, Cont)>, Option<Cont>),
 }
 
 impl IR {
@@ -18,9 +20,83 @@ impl IR {
             IR::Let(label, _, _, _, _) => *label,
             IR::LetVal(label, _, _, _) => *label,
             IR::If(label, _, _, _) => *label,
-            IR::App(label, _, _, _) => *label,
+            IR::App(label, _, _, _, _) => *label,
             IR::Fix(label, _, _, _) => *label,
             IR::AppCont(label, _, _) => *label,
+            IR::Case(label, _, _, _) => *label,
+        }
+    }
+
+    // the variables used but not bound in the IR, continuation names are not variables
+    pub fn free_vars(&self) -> HashSet<String> {
+        fn without(mut vars: HashSet<String>, bound: &[String]) -> HashSet<String> {
+            for var in bound {
+                vars.remove(var);
+            }
+            vars
+        }
+        fn atoms_free_vars(atoms: &[Atom]) -> HashSet<String> {
+            atoms.iter().flat_map(|atom| atom.free_vars()).collect()
+        }
+        match self {
+            IR::LetCont(_, _, args, cont_body, body) => {
+                let mut vars = without(cont_body.free_vars(), args);
+                vars.extend(body.free_vars());
+                vars
+            }
+            IR::Let(_, var, _, args, body) => {
+                let mut vars = without(body.free_vars(), std::slice::from_ref(var));
+                vars.extend(atoms_free_vars(args));
+                vars
+            }
+            IR::LetVal(_, var, val, body) => {
+                let mut vars = without(body.free_vars(), std::slice::from_ref(var));
+                vars.extend(val.free_vars());
+                vars
+            }
+            IR::If(_, test, then_, else_) => {
+                let mut vars = test.free_vars();
+                vars.extend(then_.free_vars());
+                vars.extend(else_.free_vars());
+                vars
+            }
+            IR::App(_, f, args, _, _) => {
+                let mut vars = f.free_vars();
+                vars.extend(atoms_free_vars(args));
+                vars
+            }
+            IR::Fix(_, vars, vals, body) => {
+                let mut free = atoms_free_vars(vals);
+                free.extend(body.free_vars());
+                without(free, vars)
+            }
+            IR::AppCont(_, _, args) => atoms_free_vars(args),
+            IR::Case(_, scrutinee, _, _) => scrutinee.free_vars(),
+        }
+    }
+
+    // the number of IR nodes, lambda bodies included
+    pub fn size(&self) -> usize {
+        fn atoms_size(atoms: &[Atom]) -> usize {
+            atoms
+                .iter()
+                .map(|atom| match atom {
+                    Atom::Lam(_, _, body) => body.size(),
+                    _ => 0,
+                })
+                .sum()
+        }
+        1 + match self {
+            IR::LetCont(_, _, _, cont_body, body) => cont_body.size() + body.size(),
+            IR::Let(_, _, _, args, body) => atoms_size(args) + body.size(),
+            IR::LetVal(_, _, val, body) => atoms_size(std::slice::from_ref(val)) + body.size(),
+            IR::If(_, test, then_, else_) => {
+                atoms_size(std::slice::from_ref(test)) + then_.size() + else_.size()
+            }
+            IR::App(_, f, args, _, _) => atoms_size(std::slice::from_ref(f)) + atoms_size(args),
+            IR::Fix(_, _, vals, body) => atoms_size(vals) + body.size(),
+            IR::AppCont(_, _, args) => atoms_size(args),
+            IR::Case(..) => 0,
         }
     }
 
@@ -63,17 +139,17 @@ impl IR {
             }
         }
         fn normalize_atoms(mut a: Vec<Atom>) -> Vec<Atom> {
-            a.drain(..).map(|x| normalize_atom(x)).collect()
+            a.drain(..).map(normalize_atom).collect()
         }
 
         let mut cursor = self;
         loop {
             match cursor {
-                IR::App(label, f, args, k) => {
+                IR::App(label, f, args, k, h) => {
                     break unroll(
                         lifted_defs,
                         let_cont_defs,
-                        IR::App(label, normalize_atom(f), normalize_atoms(args), k),
+                        IR::App(label, normalize_atom(f), normalize_atoms(args), k, h),
                     );
                 }
                 IR::AppCont(label, k, args) => {
@@ -95,6 +171,13 @@ impl IR {
                         ),
                     );
                 }
+                IR::Case(label, scrutinee, branches, default) => {
+                    break unroll(
+                        lifted_defs,
+                        let_cont_defs,
+                        IR::Case(label, normalize_atom(scrutinee), branches, default),
+                    );
+                }
                 IR::Fix(label, vars, vals, body) => {
                     let placeholder = Box::new(IR::AppCont(0, Cont::Return, vec![]));
                     lifted_defs.push(IR::Fix(label, vars, normalize_atoms(vals), placeholder));
@@ -124,8 +207,23 @@ impl IR {
 pub enum Cont {
     Named(String),
     Return,
+    // the exception handler the current function was called with
+    Handler,
+}
+
+// a Case branch is selected either by the tag of a constructed value,
+// whose payload fields are passed to the branch continuation,
+// or by an integer literal, in which case the continuation takes no argument
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CaseTag {
+    Tag(usize),
+    I32(i32),
+    I64(i64),
+    U32(u32),
+    U64(u64),
 }
 
+#[derive(Clone)]
 pub enum BuilderExpr {
     Var(String),
     I32(i32),
@@ -141,6 +239,23 @@ pub enum BuilderExpr {
     If(Box<BuilderExpr>, Box<BuilderExpr>, Box<BuilderExpr>),
     Fix(Vec<String>, Vec<BuilderExpr>, Box<BuilderExpr>),
     Let(String, Box<BuilderExpr>, Box<BuilderExpr>),
+    CallCc(Box<BuilderExpr>),
+    Raise(Box<BuilderExpr>),
+    Try(Box<BuilderExpr>, String, Box<BuilderExpr>),
+    Reset(Box<BuilderExpr>),
+    Shift(String, Box<BuilderExpr>),
+    Perform(usize, Vec<BuilderExpr>),
+    // body, return clause and operation clauses (operation, parameters, resumption, body)
+    Handle(
+        Box<BuilderExpr>,
+        (String, Box<BuilderExpr>),
+        Vec<(usize, Vec<String>, String, BuilderExpr)>,
+    ),
+    Case(
+        Box<BuilderExpr>,
+        Vec<(CaseTag, Vec<String>, BuilderExpr)>,
+        Option<Box<BuilderExpr>>,
+    ),
 }
 
 impl BuilderExpr {
@@ -177,6 +292,36 @@ impl BuilderExpr {
     pub fn papp(op: BuiltinOp, args: Vec<BuilderExpr>) -> Self {
         BuilderExpr::PrimApp(op, args)
     }
+    pub fn tuple(fields: Vec<BuilderExpr>) -> Self {
+        BuilderExpr::PrimApp(BuiltinOp::Tuple, fields)
+    }
+    pub fn proj(tuple: BuilderExpr, idx: usize) -> Self {
+        BuilderExpr::PrimApp(BuiltinOp::Proj(idx), vec![tuple])
+    }
+    pub fn construct(tag: usize, fields: Vec<BuilderExpr>) -> Self {
+        BuilderExpr::PrimApp(BuiltinOp::Construct(tag), fields)
+    }
+    pub fn ref_(init: BuilderExpr) -> Self {
+        BuilderExpr::PrimApp(BuiltinOp::Ref, vec![init])
+    }
+    pub fn deref(r: BuilderExpr) -> Self {
+        BuilderExpr::PrimApp(BuiltinOp::Deref, vec![r])
+    }
+    pub fn assign(r: BuilderExpr, val: BuilderExpr) -> Self {
+        BuilderExpr::PrimApp(BuiltinOp::Assign, vec![r, val])
+    }
+    pub fn array(len: BuilderExpr, init: BuilderExpr) -> Self {
+        BuilderExpr::PrimApp(BuiltinOp::ArrayAlloc, vec![len, init])
+    }
+    pub fn array_get(arr: BuilderExpr, idx: BuilderExpr) -> Self {
+        BuilderExpr::PrimApp(BuiltinOp::ArrayGet, vec![arr, idx])
+    }
+    pub fn array_set(arr: BuilderExpr, idx: BuilderExpr, val: BuilderExpr) -> Self {
+        BuilderExpr::PrimApp(BuiltinOp::ArraySet, vec![arr, idx, val])
+    }
+    pub fn array_length(arr: BuilderExpr) -> Self {
+        BuilderExpr::PrimApp(BuiltinOp::ArrayLength, vec![arr])
+    }
     pub fn if_(test: BuilderExpr, then_: BuilderExpr, else_: BuilderExpr) -> Self {
         BuilderExpr::If(Box::new(test), Box::new(then_), Box::new(else_))
     }
@@ -189,12 +334,238 @@ impl BuilderExpr {
     pub fn let_(var: &str, val: BuilderExpr, body: BuilderExpr) -> Self {
         BuilderExpr::Let(var.to_string(), Box::new(val), Box::new(body))
     }
+
+    pub fn callcc(f: BuilderExpr) -> Self {
+        BuilderExpr::CallCc(Box::new(f))
+    }
+
+    pub fn raise(exn: BuilderExpr) -> Self {
+        BuilderExpr::Raise(Box::new(exn))
+    }
+
+    pub fn try_(body: BuilderExpr, var: &str, handler: BuilderExpr) -> Self {
+        BuilderExpr::Try(Box::new(body), var.to_string(), Box::new(handler))
+    }
+
+    pub fn reset(body: BuilderExpr) -> Self {
+        BuilderExpr::Reset(Box::new(body))
+    }
+
+    pub fn shift(k: &str, body: BuilderExpr) -> Self {
+        BuilderExpr::Shift(k.to_string(), Box::new(body))
+    }
+
+    pub fn perform(op: usize, args: Vec<BuilderExpr>) -> Self {
+        BuilderExpr::Perform(op, args)
+    }
+
+    pub fn handle(
+        body: BuilderExpr,
+        ret: (&str, BuilderExpr),
+        ops: Vec<(usize, &[&str], &str, BuilderExpr)>,
+    ) -> Self {
+        BuilderExpr::Handle(
+            Box::new(body),
+            (ret.0.to_string(), Box::new(ret.1)),
+            ops.into_iter()
+                .map(|(op, params, resume, body)| {
+                    let params = params.iter().map(|s| s.to_string()).collect();
+                    (op, params, resume.to_string(), body)
+                })
+                .collect(),
+        )
+    }
+
+    pub fn case(
+        scrutinee: BuilderExpr,
+        branches: Vec<(CaseTag, &[&str], BuilderExpr)>,
+        default: Option<BuilderExpr>,
+    ) -> Self {
+        BuilderExpr::Case(
+            Box::new(scrutinee),
+            branches
+                .into_iter()
+                .map(|(tag, vars, body)| (tag, vars.iter().map(|s| s.to_string()).collect(), body))
+                .collect(),
+            default.map(Box::new),
+        )
+    }
+
+    // whether the term needs the meta continuation cell bound by `cps`
+    fn uses_control(&self) -> bool {
+        match self {
+            BuilderExpr::Var(_)
+            | BuilderExpr::I32(_)
+            | BuilderExpr::I64(_)
+            | BuilderExpr::U32(_)
+            | BuilderExpr::U64(_)
+            | BuilderExpr::Bool(_)
+            | BuilderExpr::Char(_)
+            | BuilderExpr::StringLiteral(_) => false,
+            BuilderExpr::Reset(_)
+            | BuilderExpr::Shift(..)
+            | BuilderExpr::Perform(..)
+            | BuilderExpr::Handle(..) => true,
+            BuilderExpr::Lam(_, body) | BuilderExpr::CallCc(body) | BuilderExpr::Raise(body) => {
+                body.uses_control()
+            }
+            BuilderExpr::App(f, args) => f.uses_control() || args.iter().any(Self::uses_control),
+            BuilderExpr::PrimApp(_, args) => args.iter().any(Self::uses_control),
+            BuilderExpr::If(test, then_, else_) => {
+                test.uses_control() || then_.uses_control() || else_.uses_control()
+            }
+            BuilderExpr::Fix(_, vals, body) => {
+                vals.iter().any(Self::uses_control) || body.uses_control()
+            }
+            BuilderExpr::Let(_, val, body) | BuilderExpr::Try(val, _, body) => {
+                val.uses_control() || body.uses_control()
+            }
+            BuilderExpr::Case(scrutinee, branches, default) => {
+                scrutinee.uses_control()
+                    || branches.iter().any(|(_, _, body)| body.uses_control())
+                    || default.as_ref().is_some_and(|body| body.uses_control())
+            }
+        }
+    }
+}
+
+// Delimited control is encoded with call/cc and a mutable cell holding the meta continuation,
+// i.e. the continuation of the innermost delimiter (Filinski, "Representing Monads").
+// the delimited computation of an effect handler delivers either its result or a request
+const DONE_TAG: usize = 0;
+const REQUEST_TAG: usize = 1;
+
+fn abort(ctx: &Rc<RefCell<GenTable>>, result: BuilderExpr) -> BuilderExpr {
+    let meta_cont = ctx.borrow().meta_cont();
+    BuilderExpr::app(BuilderExpr::deref(BuilderExpr::v(&meta_cont)), vec![result])
+}
+
+// reset: run `body` with the current continuation as the innermost delimiter
+fn delimit(ctx: &Rc<RefCell<GenTable>>, body: BuilderExpr) -> BuilderExpr {
+    use BuilderExpr as E;
+    let meta_cont = ctx.borrow().meta_cont();
+    let k = ctx.borrow_mut().alloc_var();
+    let saved = ctx.borrow_mut().alloc_var();
+    let r = ctx.borrow_mut().alloc_var();
+    let x = ctx.borrow_mut().alloc_var();
+    let delivered = abort(ctx, E::v(&x));
+    let restore = E::lam(
+        &[&r],
+        E::let_(
+            &ctx.borrow_mut().alloc_var(),
+            E::assign(E::v(&meta_cont), E::v(&saved)),
+            E::app(E::v(&k), vec![E::v(&r)]),
+        ),
+    );
+    E::callcc(E::lam(
+        &[&k],
+        E::let_(
+            &saved,
+            E::deref(E::v(&meta_cont)),
+            E::let_(
+                &ctx.borrow_mut().alloc_var(),
+                E::assign(E::v(&meta_cont), restore),
+                E::let_(&x, body, delivered),
+            ),
+        ),
+    ))
+}
+
+// shift: pass the continuation up to the innermost delimiter to `f` as a function,
+// the result of `f` is delivered to the delimiter
+fn capture(ctx: &Rc<RefCell<GenTable>>, f: BuilderExpr) -> BuilderExpr {
+    use BuilderExpr as E;
+    let k = ctx.borrow_mut().alloc_var();
+    let v = ctx.borrow_mut().alloc_var();
+    let x = ctx.borrow_mut().alloc_var();
+    let resumption = E::lam(&[&v], delimit(ctx, E::app(E::v(&k), vec![E::v(&v)])));
+    E::callcc(E::lam(
+        &[&k],
+        E::let_(&x, E::app(f, vec![resumption]), abort(ctx, E::v(&x))),
+    ))
+}
+
+fn perform(ctx: &Rc<RefCell<GenTable>>, op: BuilderExpr, args: BuilderExpr) -> BuilderExpr {
+    use BuilderExpr as E;
+    let k = ctx.borrow_mut().alloc_var();
+    let request = E::construct(REQUEST_TAG, vec![op, args, E::v(&k)]);
+    capture(ctx, E::lam(&[&k], request))
+}
+
+// a deep handler: the body runs under a delimiter and every request it delivers
+// is dispatched by a loop which also handles the requests of resumed computations
+fn handle(
+    ctx: &Rc<RefCell<GenTable>>,
+    body: BuilderExpr,
+    ret: (String, Box<BuilderExpr>),
+    ops: Vec<(usize, Vec<String>, String, BuilderExpr)>,
+) -> BuilderExpr {
+    use BuilderExpr as E;
+    let handle_loop = ctx.borrow_mut().alloc_var();
+    let res = ctx.borrow_mut().alloc_var();
+    let op = ctx.borrow_mut().alloc_var();
+    let args = ctx.borrow_mut().alloc_var();
+    let k = ctx.borrow_mut().alloc_var();
+    let resume_with = |v: BuilderExpr| E::app(E::v(&handle_loop), vec![E::app(E::v(&k), vec![v])]);
+
+    let clauses = ops
+        .into_iter()
+        .map(|(op, params, resume, body)| {
+            let v = ctx.borrow_mut().alloc_var();
+            let body = params
+                .iter()
+                .enumerate()
+                .rev()
+                .fold(body, |body, (i, param)| {
+                    E::let_(param, E::proj(E::v(&args), i), body)
+                });
+            let resume_fn = E::lam(&[&v], resume_with(E::v(&v)));
+            (CaseTag::Tag(op), vec![], E::let_(&resume, resume_fn, body))
+        })
+        .collect();
+    // requests for other operations are forwarded to the enclosing handler
+    let v = ctx.borrow_mut().alloc_var();
+    let forward = E::let_(
+        &v,
+        perform(ctx, E::v(&op), E::v(&args)),
+        resume_with(E::v(&v)),
+    );
+    let dispatch = BuilderExpr::Case(Box::new(E::v(&op)), clauses, Some(Box::new(forward)));
+
+    let (ret_var, ret_body) = ret;
+    let handle_fn = E::lam(
+        &[&res],
+        BuilderExpr::Case(
+            Box::new(E::v(&res)),
+            vec![
+                (CaseTag::Tag(DONE_TAG), vec![ret_var], *ret_body),
+                (
+                    CaseTag::Tag(REQUEST_TAG),
+                    vec![op.clone(), args.clone(), k.clone()],
+                    dispatch,
+                ),
+            ],
+            None,
+        ),
+    );
+    E::fix(
+        &[&handle_loop],
+        vec![handle_fn],
+        E::app(
+            E::v(&handle_loop),
+            vec![delimit(ctx, E::construct(DONE_TAG, vec![body]))],
+        ),
+    )
 }
 
 pub struct GenTable {
     label_count: usize,
     cont_count: usize,
     var_count: usize,
+    // the handler continuation raised exceptions are passed to at the current translation point
+    handler: Cont,
+    // the variable bound to the meta continuation cell, in a program using delimited control
+    meta_cont: Option<String>,
 }
 
 impl GenTable {
@@ -203,23 +574,177 @@ impl GenTable {
             label_count: 0,
             cont_count: 0,
             var_count: 0,
+            handler: Cont::Handler,
+            meta_cont: None,
         }))
     }
-    fn alloc_label(&mut self) -> usize {
+    // a table allocating past the labels and generated names used in `ir`,
+    // for transformations introducing new code
+    pub fn after(ir: &IR) -> Rc<RefCell<Self>> {
+        fn index(name: &str, prefix: &str) -> usize {
+            name.strip_prefix(prefix)
+                .and_then(|n| n.parse::<usize>().ok())
+                .map_or(0, |n| n + 1)
+        }
+        fn var(table: &mut GenTable, name: &str) {
+            table.var_count = table.var_count.max(index(name, "g_var_"));
+        }
+        fn cont(table: &mut GenTable, cont: &Cont) {
+            if let Cont::Named(name) = cont {
+                table.cont_count = table.cont_count.max(index(name, "g_cont_"));
+            }
+        }
+        fn atom(table: &mut GenTable, a: &Atom) {
+            match a {
+                Atom::Var(name) => var(table, name),
+                Atom::Cont(name) => cont(table, &Cont::Named(name.clone())),
+                Atom::Lam(label, args, body) => {
+                    table.label_count = table.label_count.max(label + 1);
+                    args.iter().for_each(|arg| var(table, arg));
+                    scan(table, body);
+                }
+                _ => (),
+            }
+        }
+        fn scan(table: &mut GenTable, ir: &IR) {
+            table.label_count = table.label_count.max(ir.get_label() + 1);
+            match ir {
+                IR::LetCont(_, name, args, cont_body, body) => {
+                    cont(table, &Cont::Named(name.clone()));
+                    args.iter().for_each(|arg| var(table, arg));
+                    scan(table, cont_body);
+                    scan(table, body);
+                }
+                IR::Let(_, v, _, args, body) => {
+                    var(table, v);
+                    args.iter().for_each(|arg| atom(table, arg));
+                    scan(table, body);
+                }
+                IR::LetVal(_, v, val, body) => {
+                    var(table, v);
+                    atom(table, val);
+                    scan(table, body);
+                }
+                IR::If(_, test, then_, else_) => {
+                    atom(table, test);
+                    scan(table, then_);
+                    scan(table, else_);
+                }
+                IR::App(_, f, args, k, h) => {
+                    atom(table, f);
+                    args.iter().for_each(|arg| atom(table, arg));
+                    cont(table, k);
+                    cont(table, h);
+                }
+                IR::Fix(_, vars, vals, body) => {
+                    vars.iter().for_each(|v| var(table, v));
+                    vals.iter().for_each(|val| atom(table, val));
+                    scan(table, body);
+                }
+                IR::AppCont(_, k, args) => {
+                    cont(table, k);
+                    args.iter().for_each(|arg| atom(table, arg));
+                }
+                IR::Case(_, scrutinee, branches, default) => {
+                    atom(table, scrutinee);
+                    branches.iter().for_each(|(_, k)| cont(table, k));
+                    default.iter().for_each(|k| cont(table, k));
+                }
+            }
+        }
+        let table = GenTable::new();
+        scan(&mut table.borrow_mut(), ir);
+        table
+    }
+    pub(super) fn alloc_label(&mut self) -> usize {
         let label = self.label_count;
         self.label_count += 1;
         label
     }
-    fn alloc_cont(&mut self) -> String {
+    pub(super) fn alloc_cont(&mut self) -> String {
         let cont = format!("g_cont_{}", self.cont_count);
         self.cont_count += 1;
         cont
     }
-    fn alloc_var(&mut self) -> String {
+    pub(super) fn alloc_var(&mut self) -> String {
         let var = format!("g_var_{}", self.var_count);
         self.var_count += 1;
         var
     }
+    fn current_handler(&self) -> Cont {
+        self.handler.clone()
+    }
+    fn set_handler(&mut self, handler: Cont) -> Cont {
+        std::mem::replace(&mut self.handler, handler)
+    }
+    fn meta_cont(&self) -> String {
+        self.meta_cont
+            .clone()
+            .expect("delimited control outside of a program translated by cps")
+    }
+}
+
+// the continuation a term is translated with, after Danvy and Filinski, "Representing
+// Control": a static continuation is applied to the atom of the result at translation
+// time, a dynamic one is a continuation of the IR the result is passed to.
+// A term in tail position is translated with the dynamic continuation of its context, so
+// a tail call passes it on and a branch jumps to it, a static continuation is only
+// reified into a LetCont when IR needs a continuation.
+enum MetaCont {
+    Static(Box<dyn FnOnce(Atom) -> IR>),
+    // the static continuation of the bound expression of a let,
+    // the IR computing the result binds it to the variable
+    Bind(String, Box<dyn FnOnce(Atom) -> IR>),
+    Dynamic(Cont),
+}
+
+impl MetaCont {
+    fn apply(self, ctx: &Rc<RefCell<GenTable>>, result: Atom) -> IR {
+        match self {
+            MetaCont::Static(k) | MetaCont::Bind(_, k) => k(result),
+            MetaCont::Dynamic(cont) => {
+                let label = ctx.borrow_mut().alloc_label();
+                IR::AppCont(label, cont, vec![result])
+            }
+        }
+    }
+
+    // the variable binding the result passed to the continuation
+    fn result_var(&self, ctx: &Rc<RefCell<GenTable>>) -> String {
+        match self {
+            MetaCont::Bind(var, _) => var.clone(),
+            _ => ctx.borrow_mut().alloc_var(),
+        }
+    }
+
+    // the IR built by `body` for the dynamic continuation,
+    // a static one is reified by a LetCont binding the result
+    fn reify(self, ctx: &Rc<RefCell<GenTable>>, body: impl FnOnce(Cont) -> IR) -> IR {
+        match self {
+            MetaCont::Dynamic(cont) => body(cont),
+            k => k.reify_named(ctx, |cont_name| body(Cont::Named(cont_name))),
+        }
+    }
+
+    // like `reify`, for a continuation used as a value, which has to be named
+    fn reify_named(self, ctx: &Rc<RefCell<GenTable>>, body: impl FnOnce(String) -> IR) -> IR {
+        match self {
+            MetaCont::Dynamic(Cont::Named(cont_name)) => body(cont_name),
+            k => {
+                let label = ctx.borrow_mut().alloc_label();
+                let cont_name = ctx.borrow_mut().alloc_cont();
+                let var_name = k.result_var(ctx);
+                let cont_body = k.apply(ctx, Atom::Var(var_name.clone()));
+                IR::LetCont(
+                    label,
+                    cont_name.clone(),
+                    vec![var_name],
+                    Box::new(cont_body),
+                    Box::new(body(cont_name)),
+                )
+            }
+        }
+    }
 }
 
 pub fn cps_vec(
@@ -232,10 +757,10 @@ pub fn cps_vec(
     } else {
         let ctx1 = ctx.clone();
         let arg: BuilderExpr = v.remove(0);
-        cps(
-            ctx,
+        cps_term(
+            &ctx,
             arg,
-            Box::new(|r| {
+            MetaCont::Static(Box::new(move |r| {
                 cps_vec(
                     ctx1,
                     v,
@@ -244,7 +769,7 @@ pub fn cps_vec(
                         k(rv)
                     }),
                 )
-            }),
+            })),
         )
     }
 }
@@ -253,18 +778,11 @@ pub fn cps_lam(ctx: Rc<RefCell<GenTable>>, term: BuilderExpr) -> Atom {
     match term {
         BuilderExpr::Lam(args, body) => {
             let label = ctx.borrow_mut().alloc_label();
-            Atom::Lam(
-                label,
-                args,
-                Box::new(cps(
-                    ctx.clone(),
-                    *body,
-                    Box::new(move |r| {
-                        let label = ctx.borrow_mut().alloc_label();
-                        IR::AppCont(label, Cont::Return, vec![r])
-                    }),
-                )),
-            )
+            // exceptions raised in the body go to the handler the function is called with
+            let outer_handler = ctx.borrow_mut().set_handler(Cont::Handler);
+            let body_ir = cps_term(&ctx, *body, MetaCont::Dynamic(Cont::Return));
+            ctx.borrow_mut().set_handler(outer_handler);
+            Atom::Lam(label, args, Box::new(body_ir))
         }
         _ => panic!("not a lambda"),
     }
@@ -272,111 +790,238 @@ pub fn cps_lam(ctx: Rc<RefCell<GenTable>>, term: BuilderExpr) -> Atom {
 
 pub fn quick_cps(t: BuilderExpr) -> IR {
     let ctx = GenTable::new();
-    let label = ctx.borrow_mut().alloc_label();
-    cps(
-        ctx,
-        t,
-        Box::new(move |r| IR::AppCont(label, Cont::Return, vec![r])),
-    )
+    let t = bind_meta_cont(&ctx, t);
+    cps_term(&ctx, t, MetaCont::Dynamic(Cont::Return))
 }
+
+// the IR of `term`, passing its result to `k`
 pub fn cps(ctx: Rc<RefCell<GenTable>>, term: BuilderExpr, k: Box<dyn FnOnce(Atom) -> IR>) -> IR {
+    let term = bind_meta_cont(&ctx, term);
+    cps_term(&ctx, term, MetaCont::Static(k))
+}
+
+// without an enclosing delimiter, aborting raises an exception
+fn bind_meta_cont(ctx: &Rc<RefCell<GenTable>>, term: BuilderExpr) -> BuilderExpr {
+    if !term.uses_control() || ctx.borrow().meta_cont.is_some() {
+        return term;
+    }
+    let meta_cont = ctx.borrow_mut().alloc_var();
+    ctx.borrow_mut().meta_cont = Some(meta_cont.clone());
+    let r = ctx.borrow_mut().alloc_var();
+    let unhandled = BuilderExpr::lam(
+        &[&r],
+        BuilderExpr::raise(BuilderExpr::str("unhandled effect or shift without reset")),
+    );
+    BuilderExpr::let_(&meta_cont, BuilderExpr::ref_(unhandled), term)
+}
+
+fn cps_term(ctx: &Rc<RefCell<GenTable>>, term: BuilderExpr, k: MetaCont) -> IR {
     match term {
-        BuilderExpr::Var(v) => k(Atom::Var(v)),
-        BuilderExpr::I32(v) => k(Atom::I32(v)),
-        BuilderExpr::I64(v) => k(Atom::I64(v)),
-        BuilderExpr::U32(v) => k(Atom::U32(v)),
-        BuilderExpr::U64(v) => k(Atom::U64(v)),
-        BuilderExpr::Bool(v) => k(Atom::Bool(v)),
-        BuilderExpr::Char(c) => k(Atom::Char(c)),
-        BuilderExpr::StringLiteral(s) => k(Atom::StringLiteral(s)),
-        BuilderExpr::Lam(args, body) => k(cps_lam(ctx, BuilderExpr::Lam(args, body))),
-        BuilderExpr::App(f, args) => cps(
-            ctx.clone(),
-            *f,
-            Box::new(|f_| {
-                cps_vec(
-                    ctx.clone(),
-                    args,
-                    Box::new(move |mut args_atom| {
-                        let label1 = ctx.borrow_mut().alloc_label();
-                        let label2 = ctx.borrow_mut().alloc_label();
-                        let cont_name = ctx.borrow_mut().alloc_cont();
-                        let var_name = ctx.borrow_mut().alloc_var();
-                        args_atom.reverse();
-                        IR::LetCont(
-                            label1,
-                            cont_name.clone(),
-                            vec![var_name.clone()],
-                            Box::new(k(Atom::Var(var_name))),
-                            Box::new(IR::App(label2, f_, args_atom, Cont::Named(cont_name))),
+        BuilderExpr::Var(v) => k.apply(ctx, Atom::Var(v)),
+        BuilderExpr::I32(v) => k.apply(ctx, Atom::I32(v)),
+        BuilderExpr::I64(v) => k.apply(ctx, Atom::I64(v)),
+        BuilderExpr::U32(v) => k.apply(ctx, Atom::U32(v)),
+        BuilderExpr::U64(v) => k.apply(ctx, Atom::U64(v)),
+        BuilderExpr::Bool(v) => k.apply(ctx, Atom::Bool(v)),
+        BuilderExpr::Char(c) => k.apply(ctx, Atom::Char(c)),
+        BuilderExpr::StringLiteral(s) => k.apply(ctx, Atom::StringLiteral(s)),
+        BuilderExpr::Lam(args, body) => {
+            let lam = cps_lam(ctx.clone(), BuilderExpr::Lam(args, body));
+            k.apply(ctx, lam)
+        }
+        BuilderExpr::App(f, args) => {
+            let ctx1 = ctx.clone();
+            cps_term(
+                ctx,
+                *f,
+                MetaCont::Static(Box::new(move |f_| {
+                    let ctx2 = ctx1.clone();
+                    cps_vec(
+                        ctx1,
+                        args,
+                        Box::new(move |mut args_atom| {
+                            let handler = ctx2.borrow().current_handler();
+                            args_atom.reverse();
+                            k.reify(&ctx2, |cont| {
+                                let label = ctx2.borrow_mut().alloc_label();
+                                IR::App(label, f_, args_atom, cont, handler)
+                            })
+                        }),
+                    )
+                })),
+            )
+        }
+        // the current continuation is passed as an ordinary argument
+        BuilderExpr::CallCc(f) => {
+            let ctx1 = ctx.clone();
+            cps_term(
+                ctx,
+                *f,
+                MetaCont::Static(Box::new(move |f_| {
+                    let handler = ctx1.borrow().current_handler();
+                    k.reify_named(&ctx1, |cont_name| {
+                        let label = ctx1.borrow_mut().alloc_label();
+                        IR::App(
+                            label,
+                            f_,
+                            vec![Atom::Cont(cont_name.clone())],
+                            Cont::Named(cont_name),
+                            handler,
                         )
-                    }),
-                )
-            }),
-        ),
-        BuilderExpr::PrimApp(op, args) => cps_vec(
-            ctx.clone(),
-            args,
-            Box::new(move |mut args_atom| {
-                let label = ctx.borrow_mut().alloc_label();
-                let var_name = ctx.borrow_mut().alloc_var();
-                args_atom.reverse();
-                IR::Let(
-                    label,
-                    var_name.clone(),
-                    op,
-                    args_atom,
-                    Box::new(k(Atom::Var(var_name))),
-                )
-            }),
-        ),
-        BuilderExpr::If(test, then_, else_) => cps(
-            ctx.clone(),
-            *test,
-            Box::new(move |test_r| {
-                let label = ctx.borrow_mut().alloc_label();
-                let label_cont = ctx.borrow_mut().alloc_label();
-                let label_test = ctx.borrow_mut().alloc_label();
-                let label_else = ctx.borrow_mut().alloc_label();
-                let cont = ctx.borrow_mut().alloc_cont();
-                let var = ctx.borrow_mut().alloc_var();
-                let cont1 = cont.clone();
-                let cont2 = cont.clone();
-                IR::LetCont(
-                    label_cont,
-                    cont.clone(),
-                    vec![var.clone()],
-                    Box::new(k(Atom::Var(var))),
-                    Box::new(IR::If(
+                    })
+                })),
+            )
+        }
+        BuilderExpr::Reset(body) => {
+            let reset = delimit(ctx, *body);
+            cps_term(ctx, reset, k)
+        }
+        BuilderExpr::Shift(cont, body) => {
+            let shift = capture(ctx, BuilderExpr::Lam(vec![cont], body));
+            cps_term(ctx, shift, k)
+        }
+        BuilderExpr::Perform(op, args) => {
+            let request = perform(
+                ctx,
+                BuilderExpr::construct(op, vec![]),
+                BuilderExpr::tuple(args),
+            );
+            cps_term(ctx, request, k)
+        }
+        BuilderExpr::Handle(body, ret, ops) => {
+            let handler = handle(ctx, *body, ret, ops);
+            cps_term(ctx, handler, k)
+        }
+        // the continuation is dropped: control never comes back from a raise
+        BuilderExpr::Raise(exn) => {
+            let ctx1 = ctx.clone();
+            cps_term(
+                ctx,
+                *exn,
+                MetaCont::Static(Box::new(move |exn_r| {
+                    let label = ctx1.borrow_mut().alloc_label();
+                    let handler = ctx1.borrow().current_handler();
+                    IR::AppCont(label, handler, vec![exn_r])
+                })),
+            )
+        }
+        // the body and the handler both deliver their result to the continuation
+        BuilderExpr::Try(body, var, handler_body) => k.reify(ctx, |join| {
+            let label_handler = ctx.borrow_mut().alloc_label();
+            let handler_cont = ctx.borrow_mut().alloc_cont();
+            let handler_ir = cps_term(ctx, *handler_body, MetaCont::Dynamic(join.clone()));
+
+            // the body is translated with the new handler installed
+            let outer_handler = ctx
+                .borrow_mut()
+                .set_handler(Cont::Named(handler_cont.clone()));
+            let body_ir = cps_term(ctx, *body, MetaCont::Dynamic(join));
+            ctx.borrow_mut().set_handler(outer_handler);
+
+            IR::LetCont(
+                label_handler,
+                handler_cont,
+                vec![var],
+                Box::new(handler_ir),
+                Box::new(body_ir),
+            )
+        }),
+        BuilderExpr::PrimApp(op, args) => {
+            let ctx1 = ctx.clone();
+            cps_vec(
+                ctx.clone(),
+                args,
+                Box::new(move |mut args_atom| {
+                    let label = ctx1.borrow_mut().alloc_label();
+                    let var_name = k.result_var(&ctx1);
+                    args_atom.reverse();
+                    IR::Let(
                         label,
-                        test_r,
-                        Box::new(cps(
-                            ctx.clone(),
-                            *then_,
-                            Box::new(move |x| IR::AppCont(label_test, Cont::Named(cont1), vec![x])),
-                        )),
-                        Box::new(cps(
-                            ctx.clone(),
-                            *else_,
-                            Box::new(move |x| IR::AppCont(label_else, Cont::Named(cont2), vec![x])),
-                        )),
-                    )),
-                )
-            }),
-        ),
-        BuilderExpr::Let(var, expr, body) => cps(
-            ctx.clone(),
-            *expr,
-            Box::new(move |r| {
-                let label = ctx.borrow_mut().alloc_label();
-                IR::LetVal(
-                    label,
+                        var_name.clone(),
+                        op,
+                        args_atom,
+                        Box::new(k.apply(&ctx1, Atom::Var(var_name))),
+                    )
+                }),
+            )
+        }
+        // both branches jump to the continuation, which is reified once
+        BuilderExpr::If(test, then_, else_) => {
+            let ctx1 = ctx.clone();
+            cps_term(
+                ctx,
+                *test,
+                MetaCont::Static(Box::new(move |test_r| {
+                    k.reify(&ctx1, |join| {
+                        let label = ctx1.borrow_mut().alloc_label();
+                        let then_ir = cps_term(&ctx1, *then_, MetaCont::Dynamic(join.clone()));
+                        let else_ir = cps_term(&ctx1, *else_, MetaCont::Dynamic(join));
+                        IR::If(label, test_r, Box::new(then_ir), Box::new(else_ir))
+                    })
+                })),
+            )
+        }
+        // the IR computing the bound expression binds the variable itself,
+        // a LetVal is only left for an atom
+        BuilderExpr::Let(var, expr, body) => {
+            let ctx1 = ctx.clone();
+            let bound = var.clone();
+            cps_term(
+                ctx,
+                *expr,
+                MetaCont::Bind(
                     var,
-                    r,
-                    Box::new(cps(ctx.clone(), *body, Box::new(|bodyr| k(bodyr)))),
-                )
-            }),
-        ),
+                    Box::new(move |r| {
+                        if r == Atom::Var(bound.clone()) {
+                            return cps_term(&ctx1, *body, k);
+                        }
+                        let label = ctx1.borrow_mut().alloc_label();
+                        IR::LetVal(label, bound, r, Box::new(cps_term(&ctx1, *body, k)))
+                    }),
+                ),
+            )
+        }
+        BuilderExpr::Case(scrutinee, branches, default) => {
+            let ctx1 = ctx.clone();
+            cps_term(
+                ctx,
+                *scrutinee,
+                MetaCont::Static(Box::new(move |scrutinee_r| {
+                    k.reify(&ctx1, |join| {
+                        let label = ctx1.borrow_mut().alloc_label();
+
+                        // every branch (and the default) becomes a continuation jumping to the join point
+                        let mut branch_defs = Vec::new();
+                        let mut targets = Vec::new();
+                        for (tag, vars, body) in branches {
+                            let branch_cont = ctx1.borrow_mut().alloc_cont();
+                            branch_defs.push((branch_cont.clone(), vars, body));
+                            targets.push((tag, Cont::Named(branch_cont)));
+                        }
+                        let default_cont = default.map(|body| {
+                            let branch_cont = ctx1.borrow_mut().alloc_cont();
+                            branch_defs.push((branch_cont.clone(), vec![], *body));
+                            Cont::Named(branch_cont)
+                        });
+
+                        let mut base = IR::Case(label, scrutinee_r, targets, default_cont);
+                        for (branch_cont, vars, body) in branch_defs.into_iter().rev() {
+                            let label_branch = ctx1.borrow_mut().alloc_label();
+                            let branch_body =
+                                cps_term(&ctx1, body, MetaCont::Dynamic(join.clone()));
+                            base = IR::LetCont(
+                                label_branch,
+                                branch_cont,
+                                vars,
+                                Box::new(branch_body),
+                                Box::new(base),
+                            );
+                        }
+                        base
+                    })
+                })),
+            )
+        }
         BuilderExpr::Fix(vars, mut vals, body) => {
             let label = ctx.borrow_mut().alloc_label();
             IR::Fix(
@@ -385,7 +1030,7 @@ pub fn cps(ctx: Rc<RefCell<GenTable>>, term: BuilderExpr, k: Box<dyn FnOnce(Atom
                 vals.drain(..)
                     .map(|val| cps_lam(ctx.clone(), val))
                     .collect(),
-                Box::new(cps(ctx.clone(), *body, Box::new(|body_r| k(body_r)))),
+                Box::new(cps_term(ctx, *body, k)),
             )
         }
     }
@@ -2,12 +2,15 @@ pub mod analysis;
 mod atom;
 mod builtin_call;
 pub mod cfg;
+pub mod dominance;
+pub mod dot;
 mod interp;
 mod ir;
+pub mod opt;
 #[cfg(test)]
 mod test;
 
 pub use atom::{Atom, Value};
-pub use builtin_call::builtin_call;
+pub use builtin_call::{builtin_call, can_fold};
 pub use interp::{Store, interp};
-pub use ir::{BuilderExpr, Cont, GenTable, IR, cps, quick_cps};
+pub use ir::{BuilderExpr, CaseTag, Cont, GenTable, IR, cps, quick_cps};
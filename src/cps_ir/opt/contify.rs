@@ -0,0 +1,380 @@
+// Contification, after Fluet and Weeks, "Contification Using Dominators", and Kennedy,
+// "Compiling with Continuations, Continued".
+// A function bound by Fix is contifiable when it never escapes and all its calls, apart
+// from tail calls in its own body, come from the function of the Fix and return to the
+// same continuation K with the same handler H. It then becomes a LetCont: its calls turn
+// into jumps and its body returns to K and raises to H, so a recursive function becomes
+// a loop of jumps.
+// A continuation only passing its parameters on to another is identified with it, so a
+// call returning through the join of an If is still a tail call.
+// Contifying a function moves its body into the function of the Fix, which can make the
+// functions it calls contifiable, so the pass repeats until nothing changes.
+
+use super::reroute;
+use crate::cps_ir::{Atom, Cont, GenTable, IR};
+use std::collections::{HashMap, HashSet};
+
+// a call by the lambda `fun`, None at the top level, with the continuations it is
+// passed and the LetConts whose body encloses it
+struct Call {
+    fun: Option<usize>,
+    cont: Cont,
+    handler: Cont,
+    arity: usize,
+    enclosing: Vec<String>,
+}
+
+enum Use {
+    Call(Call),
+    Escape,
+}
+
+struct FixSite {
+    label: usize,
+    fun: Option<usize>,
+    // the continuations in scope
+    conts: Vec<String>,
+    // the bound variable, label, arity and free variables of each lambda
+    lams: Vec<(String, usize, usize, HashSet<String>)>,
+}
+
+#[derive(Default)]
+struct Collector {
+    // continuation name => the continuation it forwards its parameters to
+    forwards: HashMap<String, Cont>,
+    // variable => number of binders
+    binders: HashMap<String, usize>,
+    uses: HashMap<String, Vec<Use>>,
+    fixes: Vec<FixSite>,
+}
+
+// where the new LetCont goes
+enum Placement {
+    Fix,
+    // at the head of the body of the LetCont defining K
+    LetCont(String),
+}
+
+struct Plan {
+    fix: usize,
+    var: String,
+    cont: Cont,
+    handler: Cont,
+    placement: Placement,
+}
+
+impl Collector {
+    fn bind(&mut self, vars: &[String]) {
+        for var in vars {
+            *self.binders.entry(var.clone()).or_default() += 1;
+        }
+    }
+
+    fn use_atom(&mut self, atom: &Atom) {
+        match atom {
+            Atom::Var(var) => self.uses.entry(var.clone()).or_default().push(Use::Escape),
+            Atom::Lam(label, params, body) => {
+                self.bind(params);
+                self.collect(body, Some(*label), &mut vec![]);
+            }
+            _ => (),
+        }
+    }
+
+    // `scope` holds the continuations in scope, and whether the IR is in their body
+    fn collect(&mut self, ir: &IR, fun: Option<usize>, scope: &mut Vec<(String, bool)>) {
+        let depth = scope.len();
+        match ir {
+            IR::LetCont(_, name, params, cont_body, body) => {
+                if let IR::AppCont(_, target, args) = &**cont_body {
+                    let forwarding = args.len() == params.len()
+                        && args
+                            .iter()
+                            .zip(params)
+                            .all(|(arg, param)| matches!(arg, Atom::Var(v) if v == param));
+                    if forwarding && *target != Cont::Named(name.clone()) {
+                        self.forwards.insert(name.clone(), target.clone());
+                    }
+                }
+                self.bind(params);
+                scope.push((name.clone(), false));
+                self.collect(cont_body, fun, scope);
+                scope.pop();
+                scope.push((name.clone(), true));
+                self.collect(body, fun, scope);
+            }
+            IR::Let(_, var, _, args, body) => {
+                self.bind(std::slice::from_ref(var));
+                args.iter().for_each(|arg| self.use_atom(arg));
+                self.collect(body, fun, scope);
+            }
+            IR::LetVal(_, var, val, body) => {
+                self.bind(std::slice::from_ref(var));
+                self.use_atom(val);
+                self.collect(body, fun, scope);
+            }
+            IR::Fix(label, vars, vals, body) => {
+                self.bind(vars);
+                let lams = vars
+                    .iter()
+                    .zip(vals)
+                    .filter_map(|(var, val)| match val {
+                        Atom::Lam(lam, params, _) => {
+                            Some((var.clone(), *lam, params.len(), val.free_vars()))
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                self.fixes.push(FixSite {
+                    label: *label,
+                    fun,
+                    conts: scope.iter().map(|(name, _)| name.clone()).collect(),
+                    lams,
+                });
+                vals.iter().for_each(|val| self.use_atom(val));
+                self.collect(body, fun, scope);
+            }
+            IR::If(_, test, then_, else_) => {
+                self.use_atom(test);
+                self.collect(then_, fun, scope);
+                self.collect(else_, fun, scope);
+            }
+            IR::App(_, f, args, cont, handler) => {
+                match f {
+                    Atom::Var(var) => {
+                        let call = Call {
+                            fun,
+                            cont: cont.clone(),
+                            handler: handler.clone(),
+                            arity: args.len(),
+                            enclosing: scope
+                                .iter()
+                                .filter(|(_, in_body)| *in_body)
+                                .map(|(name, _)| name.clone())
+                                .collect(),
+                        };
+                        self.uses
+                            .entry(var.clone())
+                            .or_default()
+                            .push(Use::Call(call));
+                    }
+                    _ => self.use_atom(f),
+                }
+                args.iter().for_each(|arg| self.use_atom(arg));
+            }
+            IR::AppCont(_, _, args) => args.iter().for_each(|arg| self.use_atom(arg)),
+            IR::Case(_, scrutinee, _, _) => self.use_atom(scrutinee),
+        }
+        scope.truncate(depth);
+    }
+
+    // the continuation reached by following forwarding continuations
+    fn resolve(&self, cont: &Cont) -> Cont {
+        let mut cont = cont.clone();
+        let mut seen = HashSet::new();
+        loop {
+            let next = match &cont {
+                Cont::Named(name) if seen.insert(name.clone()) => self.forwards.get(name),
+                _ => None,
+            };
+            match next {
+                Some(next) => cont = next.clone(),
+                None => break cont,
+            }
+        }
+    }
+
+    fn plan(&self) -> Option<Plan> {
+        self.fixes.iter().find_map(|site| {
+            site.lams.iter().find_map(|(var, lam, arity, free_vars)| {
+                self.plan_for(site, var, *lam, *arity, free_vars)
+            })
+        })
+    }
+
+    fn plan_for(
+        &self,
+        site: &FixSite,
+        var: &str,
+        lam: usize,
+        arity: usize,
+        free_vars: &HashSet<String>,
+    ) -> Option<Plan> {
+        if self.binders[var] != 1 {
+            return None;
+        }
+        let mut target = None;
+        let mut calls = vec![];
+        for use_ in self.uses.get(var)? {
+            let Use::Call(call) = use_ else {
+                return None;
+            };
+            if call.arity != arity {
+                return None;
+            }
+            let conts = (self.resolve(&call.cont), self.resolve(&call.handler));
+            if call.fun == Some(lam) {
+                if conts != (Cont::Return, Cont::Handler) {
+                    return None;
+                }
+            } else if call.fun == site.fun {
+                match &target {
+                    None => target = Some(conts),
+                    Some(target) if *target == conts => (),
+                    Some(_) => return None,
+                }
+                calls.push(call);
+            } else {
+                return None;
+            }
+        }
+        let (cont, handler) = target?;
+        if let Cont::Named(h) = &handler
+            && !site.conts.contains(h)
+        {
+            return None;
+        }
+        let placement = match &cont {
+            // K is defined inside the Fix, its body has to enclose the calls and the free
+            // variables of the function must not be rebound in between
+            Cont::Named(k) if !site.conts.contains(k) => {
+                let rebound = free_vars
+                    .iter()
+                    .any(|v| self.binders.get(v).is_some_and(|n| *n > 1));
+                if rebound || !calls.iter().all(|call| call.enclosing.contains(k)) {
+                    return None;
+                }
+                Placement::LetCont(k.clone())
+            }
+            _ => Placement::Fix,
+        };
+        Some(Plan {
+            fix: site.label,
+            var: var.to_string(),
+            cont,
+            handler,
+            placement,
+        })
+    }
+}
+
+struct Rewriter<'p> {
+    plan: &'p Plan,
+    label: usize,
+    name: String,
+    // the parameters and body of the new LetCont, until it is placed
+    pending: Option<(Vec<String>, IR)>,
+}
+
+impl<'p> Rewriter<'p> {
+    fn define(&mut self, body: IR) -> IR {
+        let (params, cont_body) = self.pending.take().expect("contify: body not found");
+        IR::LetCont(
+            self.label,
+            self.name.clone(),
+            params,
+            Box::new(cont_body),
+            Box::new(body),
+        )
+    }
+
+    fn rewrite_atom(&mut self, atom: Atom) -> Atom {
+        match atom {
+            Atom::Lam(label, params, body) => {
+                Atom::Lam(label, params, Box::new(self.rewrite(*body)))
+            }
+            atom => atom,
+        }
+    }
+
+    fn rewrite_atoms(&mut self, atoms: Vec<Atom>) -> Vec<Atom> {
+        atoms
+            .into_iter()
+            .map(|atom| self.rewrite_atom(atom))
+            .collect()
+    }
+
+    fn rewrite(&mut self, ir: IR) -> IR {
+        match ir {
+            IR::Fix(label, vars, vals, body) if label == self.plan.fix => {
+                let (mut kept_vars, mut kept_vals) = (vec![], vec![]);
+                for (var, val) in vars.into_iter().zip(vals) {
+                    match val {
+                        Atom::Lam(_, params, fun_body) if var == self.plan.var => {
+                            let fun_body = reroute(*fun_body, &self.plan.cont, &self.plan.handler);
+                            let fun_body = self.rewrite(fun_body);
+                            self.pending = Some((params, fun_body));
+                        }
+                        val => {
+                            kept_vars.push(var);
+                            kept_vals.push(self.rewrite_atom(val));
+                        }
+                    }
+                }
+                let mut body = self.rewrite(*body);
+                if let Placement::Fix = self.plan.placement {
+                    body = self.define(body);
+                }
+                if kept_vars.is_empty() {
+                    body
+                } else {
+                    IR::Fix(label, kept_vars, kept_vals, Box::new(body))
+                }
+            }
+            IR::LetCont(label, name, params, cont_body, body) => {
+                let cont_body = self.rewrite(*cont_body);
+                let mut body = self.rewrite(*body);
+                if matches!(&self.plan.placement, Placement::LetCont(k) if *k == name) {
+                    body = self.define(body);
+                }
+                IR::LetCont(label, name, params, Box::new(cont_body), Box::new(body))
+            }
+            IR::Let(label, var, op, args, body) => {
+                let args = self.rewrite_atoms(args);
+                IR::Let(label, var, op, args, Box::new(self.rewrite(*body)))
+            }
+            IR::LetVal(label, var, val, body) => {
+                let val = self.rewrite_atom(val);
+                IR::LetVal(label, var, val, Box::new(self.rewrite(*body)))
+            }
+            IR::If(label, test, then_, else_) => {
+                let then_ = self.rewrite(*then_);
+                IR::If(label, test, Box::new(then_), Box::new(self.rewrite(*else_)))
+            }
+            IR::App(label, Atom::Var(f), args, _, _) if f == self.plan.var => {
+                IR::AppCont(label, Cont::Named(self.name.clone()), args)
+            }
+            IR::App(label, f, args, cont, handler) => {
+                let f = self.rewrite_atom(f);
+                IR::App(label, f, self.rewrite_atoms(args), cont, handler)
+            }
+            IR::Fix(label, vars, vals, body) => {
+                let vals = self.rewrite_atoms(vals);
+                IR::Fix(label, vars, vals, Box::new(self.rewrite(*body)))
+            }
+            IR::AppCont(label, cont, args) => IR::AppCont(label, cont, self.rewrite_atoms(args)),
+            case @ IR::Case(..) => case,
+        }
+    }
+}
+
+pub fn contify(ir: IR) -> IR {
+    let table = GenTable::after(&ir);
+    let mut ir = ir;
+    loop {
+        let mut collector = Collector::default();
+        collector.collect(&ir, None, &mut vec![]);
+        let Some(plan) = collector.plan() else {
+            break ir;
+        };
+        let label = table.borrow_mut().alloc_label();
+        let name = table.borrow_mut().alloc_cont();
+        let mut rewriter = Rewriter {
+            plan: &plan,
+            label,
+            name,
+            pending: None,
+        };
+        ir = rewriter.rewrite(ir);
+    }
+}
@@ -0,0 +1,281 @@
+// Eta-reduction of continuations and lambdas which only pass their parameters on.
+// A continuation k(x) = k'(x) is removed and k replaced by k' where it is used. When k'
+// is the return or handler continuation, this is only possible for uses of k in its own
+// function and not as a value, which has to name a continuation.
+// A lambda λx. f(x) returning to its return continuation and raising to its handler is
+// replaced by f, also when the call returns through a continuation forwarding to the
+// return one. A function bound by Fix to such a lambda is removed from its bundle and
+// replaced by f.
+// Replacing names moves their uses under other binders, so the names involved must be
+// bound once.
+
+use crate::cps_ir::{Atom, Cont, IR};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Default)]
+struct Census {
+    // variable or continuation => number of binders
+    binders: HashMap<String, usize>,
+    // continuation => the lambda it belongs to
+    owners: HashMap<String, Option<usize>>,
+    // continuations used as values or from another function
+    pinned: HashSet<String>,
+}
+
+impl Census {
+    fn bind(&mut self, names: &[String]) {
+        for name in names {
+            *self.binders.entry(name.clone()).or_default() += 1;
+        }
+    }
+
+    fn use_cont(&mut self, cont: &Cont, fun: Option<usize>) {
+        if let Cont::Named(name) = cont
+            && self.owners.get(name).is_none_or(|owner| *owner != fun)
+        {
+            self.pinned.insert(name.clone());
+        }
+    }
+
+    fn atom(&mut self, atom: &Atom) {
+        match atom {
+            Atom::Cont(name) => {
+                self.pinned.insert(name.clone());
+            }
+            Atom::Lam(label, params, body) => {
+                self.bind(params);
+                self.collect(body, Some(*label));
+            }
+            _ => (),
+        }
+    }
+
+    fn collect(&mut self, ir: &IR, fun: Option<usize>) {
+        match ir {
+            IR::LetCont(_, name, params, cont_body, body) => {
+                self.bind(std::slice::from_ref(name));
+                self.bind(params);
+                self.owners.insert(name.clone(), fun);
+                self.collect(cont_body, fun);
+                self.collect(body, fun);
+            }
+            IR::Let(_, var, _, args, body) => {
+                self.bind(std::slice::from_ref(var));
+                args.iter().for_each(|arg| self.atom(arg));
+                self.collect(body, fun);
+            }
+            IR::LetVal(_, var, val, body) => {
+                self.bind(std::slice::from_ref(var));
+                self.atom(val);
+                self.collect(body, fun);
+            }
+            IR::If(_, _, then_, else_) => {
+                self.collect(then_, fun);
+                self.collect(else_, fun);
+            }
+            IR::App(_, f, args, k, h) => {
+                self.atom(f);
+                args.iter().for_each(|arg| self.atom(arg));
+                self.use_cont(k, fun);
+                self.use_cont(h, fun);
+            }
+            IR::Fix(_, vars, vals, body) => {
+                self.bind(vars);
+                vals.iter().for_each(|val| self.atom(val));
+                self.collect(body, fun);
+            }
+            IR::AppCont(_, k, args) => {
+                self.use_cont(k, fun);
+                args.iter().for_each(|arg| self.atom(arg));
+            }
+            IR::Case(_, _, branches, default) => {
+                for k in branches.iter().map(|(_, k)| k).chain(default) {
+                    self.use_cont(k, fun);
+                }
+            }
+        }
+    }
+}
+
+fn passes_on(args: &[Atom], params: &[String]) -> bool {
+    args.len() == params.len()
+        && args
+            .iter()
+            .zip(params)
+            .all(|(arg, param)| matches!(arg, Atom::Var(v) if v == param))
+}
+
+// the function a lambda only calls with its parameters
+fn eta_target(lam: &Atom) -> Option<&str> {
+    let Atom::Lam(_, params, body) = lam else {
+        return None;
+    };
+    let (f, args) = match &**body {
+        IR::App(_, Atom::Var(f), args, Cont::Return, Cont::Handler) => (f, args),
+        IR::LetCont(_, k, results, cont_body, body) => match (&**cont_body, &**body) {
+            (
+                IR::AppCont(_, Cont::Return, passed),
+                IR::App(_, Atom::Var(f), args, Cont::Named(cont), Cont::Handler),
+            ) if passes_on(passed, results) && cont == k => (f, args),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    (passes_on(args, params) && !params.contains(f)).then_some(f.as_str())
+}
+
+struct Eta {
+    census: Census,
+    vars: HashMap<String, String>,
+    conts: HashMap<String, Cont>,
+}
+
+impl Eta {
+    fn unique(&self, name: &str) -> bool {
+        self.census.binders.get(name).is_none_or(|n| *n <= 1)
+    }
+
+    fn var(&self, var: &str) -> String {
+        let mut var = var;
+        while let Some(next) = self.vars.get(var) {
+            var = next;
+        }
+        var.to_string()
+    }
+
+    fn cont(&self, cont: Cont) -> Cont {
+        let mut cont = cont;
+        while let Cont::Named(name) = &cont
+            && let Some(next) = self.conts.get(name)
+        {
+            cont = next.clone();
+        }
+        cont
+    }
+
+    fn atom(&mut self, atom: Atom) -> Atom {
+        if let Some(f) = eta_target(&atom)
+            && self.unique(f)
+        {
+            return Atom::Var(self.var(f));
+        }
+        match atom {
+            Atom::Var(var) => Atom::Var(self.var(&var)),
+            Atom::Cont(name) => match self.cont(Cont::Named(name)) {
+                Cont::Named(name) => Atom::Cont(name),
+                _ => unreachable!("a pinned continuation is not reduced"),
+            },
+            Atom::Lam(label, params, body) => {
+                Atom::Lam(label, params, Box::new(self.reduce(*body)))
+            }
+            literal => literal,
+        }
+    }
+
+    fn atoms(&mut self, atoms: Vec<Atom>) -> Vec<Atom> {
+        atoms.into_iter().map(|atom| self.atom(atom)).collect()
+    }
+
+    fn reduce(&mut self, ir: IR) -> IR {
+        match ir {
+            IR::LetCont(label, name, params, cont_body, body) => {
+                if let IR::AppCont(_, target, args) = &*cont_body
+                    && passes_on(args, &params)
+                {
+                    let target = self.cont(target.clone());
+                    let reducible = match &target {
+                        Cont::Named(k) => *k != name && self.unique(k),
+                        Cont::Return | Cont::Handler => !self.census.pinned.contains(&name),
+                    };
+                    if reducible && self.unique(&name) {
+                        self.conts.insert(name, target);
+                        return self.reduce(*body);
+                    }
+                }
+                let cont_body = self.reduce(*cont_body);
+                IR::LetCont(
+                    label,
+                    name,
+                    params,
+                    Box::new(cont_body),
+                    Box::new(self.reduce(*body)),
+                )
+            }
+            IR::Let(label, var, op, args, body) => {
+                let args = self.atoms(args);
+                IR::Let(label, var, op, args, Box::new(self.reduce(*body)))
+            }
+            IR::LetVal(label, var, val, body) => {
+                let val = self.atom(val);
+                IR::LetVal(label, var, val, Box::new(self.reduce(*body)))
+            }
+            IR::If(label, test, then_, else_) => {
+                let test = self.atom(test);
+                let then_ = self.reduce(*then_);
+                IR::If(label, test, Box::new(then_), Box::new(self.reduce(*else_)))
+            }
+            IR::App(label, f, args, k, h) => {
+                let f = self.atom(f);
+                let args = self.atoms(args);
+                IR::App(label, f, args, self.cont(k), self.cont(h))
+            }
+            IR::Fix(label, vars, vals, body) => {
+                let (mut kept_vars, mut kept_vals) = (vec![], vec![]);
+                for (var, val) in vars.into_iter().zip(vals) {
+                    if let Some(f) = eta_target(&val)
+                        && self.unique(f)
+                        && self.unique(&var)
+                    {
+                        // a cycle of such functions keeps one of them
+                        let target = self.var(f);
+                        if target != var {
+                            self.vars.insert(var, target);
+                            continue;
+                        }
+                    }
+                    kept_vars.push(var);
+                    kept_vals.push(val);
+                }
+                let body = self.reduce(*body);
+                if kept_vars.is_empty() {
+                    return body;
+                }
+                // the bundle binds lambdas, they are only reduced inside
+                let vals = kept_vals
+                    .into_iter()
+                    .map(|val| match val {
+                        Atom::Lam(lam, params, lam_body) => {
+                            Atom::Lam(lam, params, Box::new(self.reduce(*lam_body)))
+                        }
+                        val => self.atom(val),
+                    })
+                    .collect();
+                IR::Fix(label, kept_vars, vals, Box::new(body))
+            }
+            IR::AppCont(label, k, args) => {
+                let args = self.atoms(args);
+                IR::AppCont(label, self.cont(k), args)
+            }
+            IR::Case(label, scrutinee, branches, default) => IR::Case(
+                label,
+                self.atom(scrutinee),
+                branches
+                    .into_iter()
+                    .map(|(tag, k)| (tag, self.cont(k)))
+                    .collect(),
+                default.map(|k| self.cont(k)),
+            ),
+        }
+    }
+}
+
+pub fn eta(ir: IR) -> IR {
+    let mut census = Census::default();
+    census.collect(&ir, None);
+    let mut eta = Eta {
+        census,
+        vars: HashMap::new(),
+        conts: HashMap::new(),
+    };
+    eta.reduce(ir)
+}
@@ -0,0 +1,356 @@
+// Inlining of calls to known functions: lambdas applied directly, and lambdas bound by
+// LetVal or Fix to a variable which is the operator of the call.
+// A directly applied lambda is used once and moved to the call, a bound one is copied
+// when its body is small enough. The copy gets fresh labels and binders from a GenTable,
+// its return continuation becomes the continuation of the call and its handler the
+// handler of the call.
+// A function is not inlined into its own copies, nor into the functions of its Fix
+// bundle, so recursive functions are unrolled at most once per call outside the bundle.
+// The definitions left unused are removed by the shrinking pass.
+
+use super::{bind, reroute};
+use crate::cps_ir::{Atom, Cont, GenTable, IR};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+pub struct InlineConfig {
+    // the largest function body copied to a call, in IR nodes
+    pub max_size: usize,
+    // the most IR nodes copies may add to the program
+    pub max_growth: usize,
+}
+
+impl Default for InlineConfig {
+    fn default() -> Self {
+        InlineConfig {
+            max_size: 30,
+            max_growth: 1000,
+        }
+    }
+}
+
+// the fresh names of the variables and continuations bound in a copy
+#[derive(Clone, Default)]
+struct Renaming {
+    vars: HashMap<String, String>,
+    conts: HashMap<String, String>,
+}
+
+impl Renaming {
+    fn var(&self, var: &str) -> String {
+        self.vars
+            .get(var)
+            .cloned()
+            .unwrap_or_else(|| var.to_string())
+    }
+
+    fn cont(&self, cont: &Cont) -> Cont {
+        match cont {
+            Cont::Named(name) => Cont::Named(
+                self.conts
+                    .get(name)
+                    .cloned()
+                    .unwrap_or_else(|| name.clone()),
+            ),
+            _ => cont.clone(),
+        }
+    }
+
+    fn bind(&mut self, table: &Rc<RefCell<GenTable>>, vars: &[String]) -> Vec<String> {
+        vars.iter()
+            .map(|var| {
+                let fresh = table.borrow_mut().alloc_var();
+                self.vars.insert(var.clone(), fresh.clone());
+                fresh
+            })
+            .collect()
+    }
+}
+
+// a copy of `ir` with fresh labels and binders, free names are kept
+fn copy(table: &Rc<RefCell<GenTable>>, ir: &IR, renaming: &Renaming) -> IR {
+    let label = table.borrow_mut().alloc_label();
+    let atoms = |atoms: &[Atom]| -> Vec<Atom> {
+        atoms
+            .iter()
+            .map(|atom| copy_atom(table, atom, renaming))
+            .collect()
+    };
+    match ir {
+        IR::LetCont(_, name, params, cont_body, body) => {
+            let mut inner = renaming.clone();
+            let fresh = table.borrow_mut().alloc_cont();
+            inner.conts.insert(name.clone(), fresh.clone());
+            let mut in_cont = inner.clone();
+            let params = in_cont.bind(table, params);
+            IR::LetCont(
+                label,
+                fresh,
+                params,
+                Box::new(copy(table, cont_body, &in_cont)),
+                Box::new(copy(table, body, &inner)),
+            )
+        }
+        IR::Let(_, var, op, args, body) => {
+            let args = atoms(args);
+            let mut inner = renaming.clone();
+            let var = inner.bind(table, std::slice::from_ref(var)).remove(0);
+            IR::Let(
+                label,
+                var,
+                op.clone(),
+                args,
+                Box::new(copy(table, body, &inner)),
+            )
+        }
+        IR::LetVal(_, var, val, body) => {
+            let val = copy_atom(table, val, renaming);
+            let mut inner = renaming.clone();
+            let var = inner.bind(table, std::slice::from_ref(var)).remove(0);
+            IR::LetVal(label, var, val, Box::new(copy(table, body, &inner)))
+        }
+        IR::If(_, test, then_, else_) => IR::If(
+            label,
+            copy_atom(table, test, renaming),
+            Box::new(copy(table, then_, renaming)),
+            Box::new(copy(table, else_, renaming)),
+        ),
+        IR::App(_, f, args, k, h) => IR::App(
+            label,
+            copy_atom(table, f, renaming),
+            atoms(args),
+            renaming.cont(k),
+            renaming.cont(h),
+        ),
+        IR::Fix(_, vars, vals, body) => {
+            let mut inner = renaming.clone();
+            let vars = inner.bind(table, vars);
+            let vals = vals
+                .iter()
+                .map(|val| copy_atom(table, val, &inner))
+                .collect();
+            IR::Fix(label, vars, vals, Box::new(copy(table, body, &inner)))
+        }
+        IR::AppCont(_, k, args) => IR::AppCont(label, renaming.cont(k), atoms(args)),
+        IR::Case(_, scrutinee, branches, default) => IR::Case(
+            label,
+            copy_atom(table, scrutinee, renaming),
+            branches
+                .iter()
+                .map(|(tag, k)| (tag.clone(), renaming.cont(k)))
+                .collect(),
+            default.as_ref().map(|k| renaming.cont(k)),
+        ),
+    }
+}
+
+fn copy_atom(table: &Rc<RefCell<GenTable>>, atom: &Atom, renaming: &Renaming) -> Atom {
+    match atom {
+        Atom::Var(var) => Atom::Var(renaming.var(var)),
+        Atom::Cont(name) => match renaming.cont(&Cont::Named(name.clone())) {
+            Cont::Named(name) => Atom::Cont(name),
+            _ => unreachable!(),
+        },
+        Atom::Lam(_, params, body) => {
+            let label = table.borrow_mut().alloc_label();
+            let mut inner = renaming.clone();
+            let params = inner.bind(table, params);
+            Atom::Lam(label, params, Box::new(copy(table, body, &inner)))
+        }
+        literal => literal.clone(),
+    }
+}
+
+// a function that can be copied to its calls
+struct Known {
+    params: Vec<String>,
+    body: IR,
+}
+
+struct Inliner<'c> {
+    config: &'c InlineConfig,
+    table: Rc<RefCell<GenTable>>,
+    // variable => number of binders
+    binders: HashMap<String, usize>,
+    known: HashMap<String, Known>,
+    // the functions not to inline, being copied or in the bundle of the current function
+    active: HashSet<String>,
+    growth: usize,
+}
+
+fn count_binders(ir: &IR, binders: &mut HashMap<String, usize>) {
+    fn bind(vars: &[String], binders: &mut HashMap<String, usize>) {
+        for var in vars {
+            *binders.entry(var.clone()).or_default() += 1;
+        }
+    }
+    fn in_atoms(atoms: &[Atom], binders: &mut HashMap<String, usize>) {
+        for atom in atoms {
+            if let Atom::Lam(_, params, body) = atom {
+                bind(params, binders);
+                count_binders(body, binders);
+            }
+        }
+    }
+    match ir {
+        IR::LetCont(_, _, params, cont_body, body) => {
+            bind(params, binders);
+            count_binders(cont_body, binders);
+            count_binders(body, binders);
+        }
+        IR::Let(_, var, _, args, body) => {
+            bind(std::slice::from_ref(var), binders);
+            in_atoms(args, binders);
+            count_binders(body, binders);
+        }
+        IR::LetVal(_, var, val, body) => {
+            bind(std::slice::from_ref(var), binders);
+            in_atoms(std::slice::from_ref(val), binders);
+            count_binders(body, binders);
+        }
+        IR::If(_, _, then_, else_) => {
+            count_binders(then_, binders);
+            count_binders(else_, binders);
+        }
+        IR::App(_, f, args, _, _) => {
+            in_atoms(std::slice::from_ref(f), binders);
+            in_atoms(args, binders);
+        }
+        IR::Fix(_, vars, vals, body) => {
+            bind(vars, binders);
+            in_atoms(vals, binders);
+            count_binders(body, binders);
+        }
+        IR::AppCont(_, _, args) => in_atoms(args, binders),
+        IR::Case(..) => (),
+    }
+}
+
+impl<'c> Inliner<'c> {
+    // remember the lambda bound to `var` if it can be copied to its calls, which requires
+    // its free variables not to be rebound in between
+    fn learn(&mut self, var: &str, val: &Atom) {
+        let unique = |v: &String| self.binders.get(v).is_none_or(|n| *n <= 1);
+        if let Atom::Lam(_, params, body) = val
+            && unique(&var.to_string())
+            && val.free_vars().iter().all(unique)
+        {
+            let known = Known {
+                params: params.clone(),
+                body: (**body).clone(),
+            };
+            self.known.insert(var.to_string(), known);
+        }
+    }
+
+    fn inline_atom(&mut self, atom: Atom) -> Atom {
+        match atom {
+            Atom::Lam(label, params, body) => {
+                Atom::Lam(label, params, Box::new(self.inline(*body)))
+            }
+            atom => atom,
+        }
+    }
+
+    fn inline_atoms(&mut self, atoms: Vec<Atom>) -> Vec<Atom> {
+        atoms
+            .into_iter()
+            .map(|atom| self.inline_atom(atom))
+            .collect()
+    }
+
+    // the copy of the function bound to `var` for a call, if it is inlined
+    fn instantiate(&mut self, var: &str, args: &[Atom]) -> Option<(Vec<String>, IR)> {
+        let known = self.known.get(var)?;
+        let size = known.body.size();
+        if self.active.contains(var)
+            || known.params.len() != args.len()
+            || size > self.config.max_size
+            || self.growth + size > self.config.max_growth
+        {
+            return None;
+        }
+        self.growth += size;
+        let mut renaming = Renaming::default();
+        let params = renaming.bind(&self.table, &known.params);
+        Some((params, copy(&self.table, &known.body, &renaming)))
+    }
+
+    fn inline(&mut self, ir: IR) -> IR {
+        match ir {
+            IR::LetCont(label, name, params, cont_body, body) => {
+                let cont_body = self.inline(*cont_body);
+                IR::LetCont(
+                    label,
+                    name,
+                    params,
+                    Box::new(cont_body),
+                    Box::new(self.inline(*body)),
+                )
+            }
+            IR::Let(label, var, op, args, body) => {
+                let args = self.inline_atoms(args);
+                IR::Let(label, var, op, args, Box::new(self.inline(*body)))
+            }
+            IR::LetVal(label, var, val, body) => {
+                let val = self.inline_atom(val);
+                self.learn(&var, &val);
+                IR::LetVal(label, var, val, Box::new(self.inline(*body)))
+            }
+            IR::If(label, test, then_, else_) => {
+                let then_ = self.inline(*then_);
+                IR::If(label, test, Box::new(then_), Box::new(self.inline(*else_)))
+            }
+            IR::App(_, Atom::Lam(_, params, body), args, k, h) => {
+                let ir = bind(&self.table, params, args, reroute(*body, &k, &h));
+                self.inline(ir)
+            }
+            IR::App(label, Atom::Var(f), args, k, h) => match self.instantiate(&f, &args) {
+                Some((params, body)) => {
+                    let ir = bind(&self.table, params, args, reroute(body, &k, &h));
+                    self.active.insert(f.clone());
+                    let ir = self.inline(ir);
+                    self.active.remove(&f);
+                    ir
+                }
+                None => IR::App(label, Atom::Var(f), self.inline_atoms(args), k, h),
+            },
+            IR::App(label, f, args, k, h) => IR::App(label, f, self.inline_atoms(args), k, h),
+            IR::Fix(label, vars, vals, body) => {
+                // a function is not inlined into its bundle
+                let added: Vec<String> = vars
+                    .iter()
+                    .filter(|var| self.active.insert((*var).clone()))
+                    .cloned()
+                    .collect();
+                let vals = self.inline_atoms(vals);
+                for var in &added {
+                    self.active.remove(var);
+                }
+                for (var, val) in vars.iter().zip(&vals) {
+                    self.learn(var, val);
+                }
+                IR::Fix(label, vars, vals, Box::new(self.inline(*body)))
+            }
+            IR::AppCont(label, k, args) => IR::AppCont(label, k, self.inline_atoms(args)),
+            case @ IR::Case(..) => case,
+        }
+    }
+}
+
+pub fn inline(ir: IR, config: &InlineConfig) -> IR {
+    let mut binders = HashMap::new();
+    count_binders(&ir, &mut binders);
+    let mut inliner = Inliner {
+        config,
+        table: GenTable::after(&ir),
+        binders,
+        known: HashMap::new(),
+        active: HashSet::new(),
+        growth: 0,
+    };
+    inliner.inline(ir)
+}
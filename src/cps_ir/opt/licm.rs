@@ -0,0 +1,341 @@
+// Loop-invariant code motion. A function bound by Fix which calls itself is a loop, a
+// pure primitive application in its body whose arguments are defined outside the Fix, or
+// by applications hoisted before it, has the same result on every iteration and is
+// hoisted before the Fix.
+// The hoisted application is evaluated even when the function is not called, so one which
+// may fail is only hoisted when it is evaluated on every call, dominating the exit of the
+// function, and a call of the function post-dominates the body of the Fix.
+// The bound variable comes into scope for the whole Fix, so it has to be bound once.
+// Hoisting out of an inner loop can make an application invariant in an outer one, so the
+// pass repeats until nothing changes.
+
+use crate::cps_ir::{
+    Atom, IR,
+    analysis::reaching_definitions::{DefUse, Definition, def_use},
+    builtin_call::BuiltinOp,
+    cfg::{Lattice, NodeInfo, NodePool},
+};
+use std::collections::{HashMap, HashSet};
+
+// the graph is only used for its dominators
+#[derive(Clone, PartialEq, Eq)]
+struct Graph;
+
+impl Lattice for Graph {
+    fn join(_: &Self, _: &Self) -> Self {
+        Graph
+    }
+    fn bottom() -> Self {
+        Graph
+    }
+}
+
+struct Hoisted {
+    label: usize,
+    var: String,
+    op: BuiltinOp,
+    args: Vec<Atom>,
+}
+
+struct FixSite<'a> {
+    label: usize,
+    // the lambda the Fix is in, None at the top level
+    fun: Option<usize>,
+    body: usize,
+    // the labels of the IR and lambdas inside the Fix, its own included
+    inside: HashSet<usize>,
+    // the bound variable, label and body of each lambda
+    lams: Vec<(&'a str, usize, &'a IR)>,
+}
+
+fn labels(ir: &IR, inside: &mut HashSet<usize>) {
+    inside.insert(ir.get_label());
+    let in_atoms = |atoms: &[Atom], inside: &mut HashSet<usize>| {
+        for atom in atoms {
+            if let Atom::Lam(label, _, body) = atom {
+                inside.insert(*label);
+                labels(body, inside);
+            }
+        }
+    };
+    match ir {
+        IR::LetCont(_, _, _, cont_body, body) => {
+            labels(cont_body, inside);
+            labels(body, inside);
+        }
+        IR::Let(_, _, _, args, body) => {
+            in_atoms(args, inside);
+            labels(body, inside);
+        }
+        IR::LetVal(_, _, val, body) => {
+            in_atoms(std::slice::from_ref(val), inside);
+            labels(body, inside);
+        }
+        IR::If(_, _, then_, else_) => {
+            labels(then_, inside);
+            labels(else_, inside);
+        }
+        IR::App(_, f, args, _, _) => {
+            in_atoms(std::slice::from_ref(f), inside);
+            in_atoms(args, inside);
+        }
+        IR::Fix(_, _, vals, body) => {
+            in_atoms(vals, inside);
+            labels(body, inside);
+        }
+        IR::AppCont(_, _, args) => in_atoms(args, inside),
+        IR::Case(..) => (),
+    }
+}
+
+// the IR of a function body in preorder, without the bodies of nested lambdas
+fn own_nodes<'a>(ir: &'a IR, nodes: &mut Vec<&'a IR>) {
+    nodes.push(ir);
+    match ir {
+        IR::LetCont(_, _, _, cont_body, body) => {
+            own_nodes(cont_body, nodes);
+            own_nodes(body, nodes);
+        }
+        IR::Let(.., body) | IR::LetVal(.., body) | IR::Fix(.., body) => own_nodes(body, nodes),
+        IR::If(_, _, then_, else_) => {
+            own_nodes(then_, nodes);
+            own_nodes(else_, nodes);
+        }
+        IR::App(..) | IR::AppCont(..) | IR::Case(..) => (),
+    }
+}
+
+fn collect<'a>(ir: &'a IR, fun: Option<usize>, sites: &mut Vec<FixSite<'a>>) {
+    let in_atoms = |atoms: &'a [Atom], sites: &mut Vec<FixSite<'a>>| {
+        for atom in atoms {
+            if let Atom::Lam(label, _, body) = atom {
+                collect(body, Some(*label), sites);
+            }
+        }
+    };
+    match ir {
+        IR::LetCont(_, _, _, cont_body, body) => {
+            collect(cont_body, fun, sites);
+            collect(body, fun, sites);
+        }
+        IR::Let(_, _, _, args, body) => {
+            in_atoms(args, sites);
+            collect(body, fun, sites);
+        }
+        IR::LetVal(_, _, val, body) => {
+            in_atoms(std::slice::from_ref(val), sites);
+            collect(body, fun, sites);
+        }
+        IR::If(_, _, then_, else_) => {
+            collect(then_, fun, sites);
+            collect(else_, fun, sites);
+        }
+        IR::App(_, f, args, _, _) => {
+            in_atoms(std::slice::from_ref(f), sites);
+            in_atoms(args, sites);
+        }
+        IR::Fix(label, vars, vals, body) => {
+            let mut inside = HashSet::new();
+            labels(ir, &mut inside);
+            let lams = vars
+                .iter()
+                .zip(vals)
+                .filter_map(|(var, val)| match val {
+                    Atom::Lam(lam, _, lam_body) => Some((var.as_str(), *lam, &**lam_body)),
+                    _ => None,
+                })
+                .collect();
+            sites.push(FixSite {
+                label: *label,
+                fun,
+                body: body.get_label(),
+                inside,
+                lams,
+            });
+            in_atoms(vals, sites);
+            collect(body, fun, sites);
+        }
+        IR::AppCont(_, _, args) => in_atoms(args, sites),
+        IR::Case(..) => (),
+    }
+}
+
+struct Planner<'a> {
+    chains: DefUse,
+    pool: NodePool<'a, Graph>,
+    // variable => number of binders
+    binders: HashMap<String, usize>,
+}
+
+impl<'a> Planner<'a> {
+    // whether the IR labeled `label` calls the function bound to `var` by the Fix
+    fn calls(&self, label: usize, f: &Atom, var: &str, fix: usize) -> bool {
+        matches!(f, Atom::Var(v) if v == var)
+            && self.chains.def_of(label, var) == Some(&Definition::new(var, fix))
+    }
+
+    // whether every path through the body of the Fix calls the function bound to `var`
+    fn always_called(&self, site: &FixSite, var: &str) -> bool {
+        let exit = match site.fun {
+            Some(lam) => self.pool.get_fun_exit(lam),
+            None => self.pool.get_prog_exit(),
+        };
+        let post_dom = self.pool.post_dominators(exit);
+        let body = self.pool.get_node(site.body);
+        (0..self.pool.node_count()).any(|n| match self.pool.get_info(n) {
+            NodeInfo::Common(label, IR::App(_, f, ..)) => {
+                self.calls(*label, f, var, site.label) && post_dom.dominates(n, body)
+            }
+            _ => false,
+        })
+    }
+
+    // the applications hoisted before the Fix, in order
+    fn plan(&self, site: &FixSite) -> Vec<Hoisted> {
+        let mut hoisted: Vec<Hoisted> = vec![];
+        for (var, lam, body) in &site.lams {
+            let mut nodes = vec![];
+            own_nodes(body, &mut nodes);
+            let recursive = nodes.iter().any(|ir| match ir {
+                IR::App(label, f, ..) => self.calls(*label, f, var, site.label),
+                _ => false,
+            });
+            if !recursive {
+                continue;
+            }
+            let dom = self.pool.dominators(self.pool.get_fun_entry(*lam));
+            let exit = self.pool.get_fun_exit(*lam);
+            let mut called = None;
+            for ir in nodes {
+                let IR::Let(label, x, op, args, _) = ir else {
+                    continue;
+                };
+                let invariant = |arg: &Atom| match arg {
+                    Atom::Var(v) => self.chains.def_of(*label, v).is_none_or(|def| {
+                        !site.inside.contains(&def.label)
+                            || hoisted.iter().any(|h| h.label == def.label)
+                    }),
+                    Atom::Lam(..) | Atom::Cont(_) => false,
+                    _ => true,
+                };
+                if !op.is_pure() || self.binders[x] != 1 || !args.iter().all(invariant) {
+                    continue;
+                }
+                if op.may_fail()
+                    && !(dom.dominates(self.pool.get_node(*label), exit)
+                        && *called.get_or_insert_with(|| self.always_called(site, var)))
+                {
+                    continue;
+                }
+                hoisted.push(Hoisted {
+                    label: *label,
+                    var: x.clone(),
+                    op: op.clone(),
+                    args: args.clone(),
+                });
+            }
+        }
+        hoisted
+    }
+}
+
+// Fix label => the applications hoisted before it
+fn plan(ir: &IR) -> HashMap<usize, Vec<Hoisted>> {
+    let chains = def_use(ir);
+    let mut binders = HashMap::new();
+    for def in chains.definitions() {
+        *binders.entry(def.var.clone()).or_default() += 1;
+    }
+    let mut pool = NodePool::new(true, Box::new(|_, _, l| l));
+    pool.construct_intra(ir);
+    let planner = Planner {
+        chains,
+        pool,
+        binders,
+    };
+    let mut sites = vec![];
+    collect(ir, None, &mut sites);
+    sites
+        .iter()
+        .map(|site| (site.label, planner.plan(site)))
+        .filter(|(_, hoisted)| !hoisted.is_empty())
+        .collect()
+}
+
+struct Rewriter {
+    plans: HashMap<usize, Vec<Hoisted>>,
+    moved: HashSet<usize>,
+}
+
+impl Rewriter {
+    fn rewrite_atom(&mut self, atom: Atom) -> Atom {
+        match atom {
+            Atom::Lam(label, params, body) => {
+                Atom::Lam(label, params, Box::new(self.rewrite(*body)))
+            }
+            atom => atom,
+        }
+    }
+
+    fn rewrite_atoms(&mut self, atoms: Vec<Atom>) -> Vec<Atom> {
+        atoms
+            .into_iter()
+            .map(|atom| self.rewrite_atom(atom))
+            .collect()
+    }
+
+    fn rewrite(&mut self, ir: IR) -> IR {
+        match ir {
+            IR::Let(label, _, _, _, body) if self.moved.contains(&label) => self.rewrite(*body),
+            IR::Fix(label, vars, vals, body) => {
+                let vals = self.rewrite_atoms(vals);
+                let fix = IR::Fix(label, vars, vals, Box::new(self.rewrite(*body)));
+                let hoisted = self.plans.remove(&label).unwrap_or_default();
+                hoisted.into_iter().rev().fold(fix, |body, h| {
+                    IR::Let(h.label, h.var, h.op, h.args, Box::new(body))
+                })
+            }
+            IR::LetCont(label, name, params, cont_body, body) => {
+                let cont_body = self.rewrite(*cont_body);
+                IR::LetCont(
+                    label,
+                    name,
+                    params,
+                    Box::new(cont_body),
+                    Box::new(self.rewrite(*body)),
+                )
+            }
+            IR::Let(label, var, op, args, body) => {
+                let args = self.rewrite_atoms(args);
+                IR::Let(label, var, op, args, Box::new(self.rewrite(*body)))
+            }
+            IR::LetVal(label, var, val, body) => {
+                let val = self.rewrite_atom(val);
+                IR::LetVal(label, var, val, Box::new(self.rewrite(*body)))
+            }
+            IR::If(label, test, then_, else_) => {
+                let then_ = self.rewrite(*then_);
+                IR::If(label, test, Box::new(then_), Box::new(self.rewrite(*else_)))
+            }
+            IR::App(label, f, args, cont, handler) => {
+                let f = self.rewrite_atom(f);
+                IR::App(label, f, self.rewrite_atoms(args), cont, handler)
+            }
+            IR::AppCont(label, cont, args) => IR::AppCont(label, cont, self.rewrite_atoms(args)),
+            case @ IR::Case(..) => case,
+        }
+    }
+}
+
+pub fn licm(ir: IR) -> IR {
+    let mut ir = ir;
+    loop {
+        let plans = plan(&ir);
+        if plans.is_empty() {
+            break ir;
+        }
+        let moved = plans.values().flatten().map(|h| h.label).collect();
+        let mut rewriter = Rewriter { plans, moved };
+        ir = rewriter.rewrite(ir);
+    }
+}
@@ -0,0 +1,70 @@
+pub mod contify;
+pub mod eta;
+pub mod inline;
+pub mod licm;
+pub mod shrink;
+pub mod uncurry;
+
+use super::{Atom, Cont, GenTable, IR};
+use std::{cell::RefCell, rc::Rc};
+
+fn replace(cont: Cont, k: &Cont, h: &Cont) -> Cont {
+    match cont {
+        Cont::Return => k.clone(),
+        Cont::Handler => h.clone(),
+        named => named,
+    }
+}
+
+// the body of a function moved into its caller, returning to `k` and raising to `h`
+fn reroute(ir: IR, k: &Cont, h: &Cont) -> IR {
+    match ir {
+        IR::LetCont(label, name, params, cont_body, body) => IR::LetCont(
+            label,
+            name,
+            params,
+            Box::new(reroute(*cont_body, k, h)),
+            Box::new(reroute(*body, k, h)),
+        ),
+        IR::Let(label, var, op, args, body) => {
+            IR::Let(label, var, op, args, Box::new(reroute(*body, k, h)))
+        }
+        IR::LetVal(label, var, val, body) => {
+            IR::LetVal(label, var, val, Box::new(reroute(*body, k, h)))
+        }
+        IR::If(label, test, then_, else_) => IR::If(
+            label,
+            test,
+            Box::new(reroute(*then_, k, h)),
+            Box::new(reroute(*else_, k, h)),
+        ),
+        IR::App(label, f, args, cont, handler) => {
+            IR::App(label, f, args, replace(cont, k, h), replace(handler, k, h))
+        }
+        IR::Fix(label, vars, vals, body) => {
+            IR::Fix(label, vars, vals, Box::new(reroute(*body, k, h)))
+        }
+        IR::AppCont(label, cont, args) => IR::AppCont(label, replace(cont, k, h), args),
+        IR::Case(label, scrutinee, branches, default) => IR::Case(
+            label,
+            scrutinee,
+            branches
+                .into_iter()
+                .map(|(tag, cont)| (tag, replace(cont, k, h)))
+                .collect(),
+            default.map(|cont| replace(cont, k, h)),
+        ),
+    }
+}
+
+// `body` with `params` bound to `args` by LetVals
+fn bind(table: &Rc<RefCell<GenTable>>, params: Vec<String>, args: Vec<Atom>, body: IR) -> IR {
+    params
+        .into_iter()
+        .zip(args)
+        .rev()
+        .fold(body, |body, (param, arg)| {
+            let label = table.borrow_mut().alloc_label();
+            IR::LetVal(label, param, arg, Box::new(body))
+        })
+}
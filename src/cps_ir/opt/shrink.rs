@@ -0,0 +1,450 @@
+// Shrinking reductions, after Appel and Jim, "Shrinking Lambda Expressions in Linear Time",
+// and Kennedy, "Compiling with Continuations, Continued".
+// The reductions never grow the IR, so the pass can run between heavier ones:
+// - a LetVal of a variable or a literal is propagated to the uses of its variable,
+// - a function or continuation used once, by a call or a jump, is inlined there, as
+//   is a lambda applied directly,
+// - an unused binding is removed, a Let only when its operation is pure,
+// - an If on a literal is replaced by the branch it takes.
+// They are driven by a census of the occurrences of each variable and continuation.
+// Removing code subtracts its occurrences and a propagated variable hands its occurrences
+// over, so the census stays exact during the pass: a binding is looked at before its
+// scope to propagate or inline it, and after it to remove it once the reductions in its
+// scope are accounted for.
+// Inlining moves code under other binders, so the names involved must be bound once.
+
+use super::{bind, replace, reroute};
+use crate::cps_ir::{Atom, Cont, GenTable, IR};
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+#[derive(Default)]
+struct Census {
+    // variable => occurrences, and occurrences as the operator of an App
+    uses: HashMap<String, usize>,
+    calls: HashMap<String, usize>,
+    // continuation => occurrences, and occurrences as the target of an AppCont
+    cont_uses: HashMap<String, usize>,
+    jumps: HashMap<String, usize>,
+    // variable => number of binders
+    binders: HashMap<String, usize>,
+}
+
+fn count(map: &mut HashMap<String, usize>, key: &str, delta: isize) {
+    let n = map.entry(key.to_string()).or_default();
+    *n = n.saturating_add_signed(delta);
+}
+
+fn get(map: &HashMap<String, usize>, key: &str) -> usize {
+    map.get(key).copied().unwrap_or(0)
+}
+
+// the occurrences of the return and handler continuations in a function body,
+// and whether they are jumps
+fn exits(ir: &IR) -> Vec<(&Cont, bool)> {
+    match ir {
+        IR::LetCont(_, _, _, cont_body, body) => {
+            let mut found = exits(cont_body);
+            found.extend(exits(body));
+            found
+        }
+        IR::Let(_, _, _, _, body) | IR::LetVal(_, _, _, body) | IR::Fix(_, _, _, body) => {
+            exits(body)
+        }
+        IR::If(_, _, then_, else_) => {
+            let mut found = exits(then_);
+            found.extend(exits(else_));
+            found
+        }
+        IR::App(_, _, _, k, h) => vec![(k, false), (h, false)],
+        IR::AppCont(_, k, _) => vec![(k, true)],
+        IR::Case(_, _, branches, default) => {
+            let conts = branches.iter().map(|(_, k)| k).chain(default);
+            conts.map(|k| (k, true)).collect()
+        }
+    }
+    .into_iter()
+    .filter(|(k, _)| !matches!(k, Cont::Named(_)))
+    .collect()
+}
+
+fn copyable(atom: &Atom) -> bool {
+    !matches!(atom, Atom::Lam(..) | Atom::Cont(_))
+}
+
+// a function or continuation waiting to be inlined at its single call or jump,
+// with the function a continuation belongs to
+struct Pending {
+    label: usize,
+    params: Vec<String>,
+    body: IR,
+    fun: Option<usize>,
+}
+
+struct Shrinker {
+    census: Census,
+    // variable => the atom propagated to its uses
+    subst: HashMap<String, Atom>,
+    funs: HashMap<String, Pending>,
+    conts: HashMap<String, Pending>,
+    table: Rc<RefCell<GenTable>>,
+}
+
+impl Shrinker {
+    fn resolve(&self, atom: Atom) -> Atom {
+        let mut atom = atom;
+        while let Atom::Var(var) = &atom
+            && let Some(next) = self.subst.get(var)
+        {
+            atom = next.clone();
+        }
+        atom
+    }
+
+    fn unique(&self, var: &str) -> bool {
+        get(&self.census.binders, var) <= 1
+    }
+
+    // add `delta` to the census of the occurrences and binders in `ir`
+    fn census(&mut self, ir: &IR, delta: isize) {
+        match ir {
+            IR::LetCont(_, _, params, cont_body, body) => {
+                self.census_binders(params, delta);
+                self.census(cont_body, delta);
+                self.census(body, delta);
+            }
+            IR::Let(_, var, _, args, body) => {
+                self.census_binders(std::slice::from_ref(var), delta);
+                args.iter().for_each(|arg| self.census_atom(arg, delta));
+                self.census(body, delta);
+            }
+            IR::LetVal(_, var, val, body) => {
+                self.census_binders(std::slice::from_ref(var), delta);
+                self.census_atom(val, delta);
+                self.census(body, delta);
+            }
+            IR::If(_, test, then_, else_) => {
+                self.census_atom(test, delta);
+                self.census(then_, delta);
+                self.census(else_, delta);
+            }
+            IR::App(_, f, args, k, h) => {
+                if let Atom::Var(var) = self.resolve(f.clone()) {
+                    count(&mut self.census.calls, &var, delta);
+                }
+                self.census_atom(f, delta);
+                args.iter().for_each(|arg| self.census_atom(arg, delta));
+                self.census_cont(k, delta);
+                self.census_cont(h, delta);
+            }
+            IR::Fix(_, vars, vals, body) => {
+                self.census_binders(vars, delta);
+                vals.iter().for_each(|val| self.census_atom(val, delta));
+                self.census(body, delta);
+            }
+            IR::AppCont(_, k, args) => {
+                if let Cont::Named(name) = k {
+                    count(&mut self.census.jumps, name, delta);
+                }
+                self.census_cont(k, delta);
+                args.iter().for_each(|arg| self.census_atom(arg, delta));
+            }
+            IR::Case(_, scrutinee, branches, default) => {
+                self.census_atom(scrutinee, delta);
+                for k in branches.iter().map(|(_, k)| k).chain(default) {
+                    self.census_cont(k, delta);
+                }
+            }
+        }
+    }
+
+    fn census_atom(&mut self, atom: &Atom, delta: isize) {
+        match atom {
+            Atom::Var(_) => {
+                if let Atom::Var(var) = self.resolve(atom.clone()) {
+                    count(&mut self.census.uses, &var, delta);
+                }
+            }
+            Atom::Cont(name) => count(&mut self.census.cont_uses, name, delta),
+            Atom::Lam(_, params, body) => {
+                self.census_binders(params, delta);
+                self.census(body, delta);
+            }
+            _ => (),
+        }
+    }
+
+    fn census_cont(&mut self, cont: &Cont, delta: isize) {
+        if let Cont::Named(name) = cont {
+            count(&mut self.census.cont_uses, name, delta);
+        }
+    }
+
+    fn census_binders(&mut self, vars: &[String], delta: isize) {
+        for var in vars {
+            count(&mut self.census.binders, var, delta);
+        }
+    }
+
+    // whether the body of a lambda can be moved to a call
+    fn movable(&self, lam: &Atom) -> bool {
+        match lam {
+            Atom::Lam(_, params, _) => {
+                params.iter().all(|param| self.unique(param))
+                    && lam.free_vars().iter().all(|v| self.unique(v))
+            }
+            _ => false,
+        }
+    }
+
+    // whether the lambda bound to `var` is only called once and can be moved there
+    fn inlinable(&self, var: &str, lam: &Atom) -> bool {
+        get(&self.census.uses, var) == 1
+            && get(&self.census.calls, var) == 1
+            && self.unique(var)
+            && self.movable(lam)
+    }
+
+    // the body of a lambda in place of a call
+    fn inline(&mut self, params: Vec<String>, body: IR, args: Vec<Atom>, k: Cont, h: Cont) -> IR {
+        // the call passes `k` and `h` once, the body may use them any times
+        for (cont, jump) in exits(&body) {
+            if let Cont::Named(name) = replace(cont.clone(), &k, &h) {
+                count(&mut self.census.cont_uses, &name, 1);
+                count(&mut self.census.jumps, &name, jump as isize);
+            }
+        }
+        self.census_cont(&k, -1);
+        self.census_cont(&h, -1);
+        let body = reroute(body, &k, &h);
+        bind(&self.table, params, args, body)
+    }
+
+    fn pend(&mut self, var: &str, lam: Atom) {
+        let Atom::Lam(label, params, body) = lam else {
+            unreachable!()
+        };
+        let pending = Pending {
+            label,
+            params,
+            body: *body,
+            fun: None,
+        };
+        self.funs.insert(var.to_string(), pending);
+    }
+
+    // the lambda bound to `var` if it was not inlined, None if it is unused
+    fn unused_fun(&mut self, var: &str) -> Option<Atom> {
+        let Pending {
+            label,
+            params,
+            body,
+            ..
+        } = self.funs.remove(var)?;
+        if get(&self.census.uses, var) == 0 {
+            self.census_binders(&params, -1);
+            self.census(&body, -1);
+            return None;
+        }
+        Some(Atom::Lam(
+            label,
+            params,
+            Box::new(self.shrink(body, Some(label))),
+        ))
+    }
+
+    fn shrink_atom(&mut self, atom: Atom) -> Atom {
+        match self.resolve(atom) {
+            Atom::Lam(label, params, body) => {
+                Atom::Lam(label, params, Box::new(self.shrink(*body, Some(label))))
+            }
+            atom => atom,
+        }
+    }
+
+    fn shrink_atoms(&mut self, atoms: Vec<Atom>) -> Vec<Atom> {
+        atoms
+            .into_iter()
+            .map(|atom| self.shrink_atom(atom))
+            .collect()
+    }
+
+    // `fun` is the label of the lambda the IR belongs to, None at the top level
+    fn shrink(&mut self, ir: IR, fun: Option<usize>) -> IR {
+        match ir {
+            IR::LetCont(label, name, params, cont_body, body) => {
+                let inlinable = get(&self.census.cont_uses, &name) == 1
+                    && get(&self.census.jumps, &name) == 1
+                    && params.iter().all(|param| self.unique(param))
+                    && cont_body.free_vars().iter().all(|v| self.unique(v));
+                let (params, cont_body, body) = if inlinable {
+                    let pending = Pending {
+                        label,
+                        params,
+                        body: *cont_body,
+                        fun,
+                    };
+                    self.conts.insert(name.clone(), pending);
+                    let body = self.shrink(*body, fun);
+                    match self.conts.remove(&name) {
+                        Some(pending) => (pending.params, pending.body, body),
+                        None => return body,
+                    }
+                } else {
+                    let body = self.shrink(*body, fun);
+                    (params, *cont_body, body)
+                };
+                if get(&self.census.cont_uses, &name) == 0 {
+                    self.census_binders(&params, -1);
+                    self.census(&cont_body, -1);
+                    return body;
+                }
+                let cont_body = self.shrink(cont_body, fun);
+                IR::LetCont(label, name, params, Box::new(cont_body), Box::new(body))
+            }
+            IR::Let(label, var, op, args, body) => {
+                let args: Vec<Atom> = args.into_iter().map(|arg| self.resolve(arg)).collect();
+                let body = self.shrink(*body, fun);
+                if op.is_pure() && get(&self.census.uses, &var) == 0 {
+                    self.census_binders(std::slice::from_ref(&var), -1);
+                    args.iter().for_each(|arg| self.census_atom(arg, -1));
+                    return body;
+                }
+                let args = self.shrink_atoms(args);
+                IR::Let(label, var, op, args, Box::new(body))
+            }
+            IR::LetVal(label, var, val, body) => {
+                let val = self.resolve(val);
+                let propagated = match &val {
+                    Atom::Var(source) => self.unique(source),
+                    val => copyable(val),
+                };
+                if propagated && self.unique(&var) {
+                    // the uses of `var` become uses of its source
+                    if let Atom::Var(source) = &val {
+                        let uses = get(&self.census.uses, &var) as isize;
+                        let calls = get(&self.census.calls, &var) as isize;
+                        count(&mut self.census.uses, source, uses - 1);
+                        count(&mut self.census.calls, source, calls);
+                    }
+                    self.subst.insert(var, val);
+                    return self.shrink(*body, fun);
+                }
+                if self.inlinable(&var, &val) {
+                    self.pend(&var, val);
+                    let body = self.shrink(*body, fun);
+                    return match self.unused_fun(&var) {
+                        Some(val) => IR::LetVal(label, var, val, Box::new(body)),
+                        None => body,
+                    };
+                }
+                let body = self.shrink(*body, fun);
+                if get(&self.census.uses, &var) == 0 {
+                    self.census_binders(std::slice::from_ref(&var), -1);
+                    self.census_atom(&val, -1);
+                    return body;
+                }
+                let val = self.shrink_atom(val);
+                IR::LetVal(label, var, val, Box::new(body))
+            }
+            IR::If(label, test, then_, else_) => match self.resolve(test) {
+                Atom::Bool(b) => {
+                    let (taken, dropped) = if b { (then_, else_) } else { (else_, then_) };
+                    self.census(&dropped, -1);
+                    self.shrink(*taken, fun)
+                }
+                test => {
+                    let then_ = self.shrink(*then_, fun);
+                    IR::If(
+                        label,
+                        test,
+                        Box::new(then_),
+                        Box::new(self.shrink(*else_, fun)),
+                    )
+                }
+            },
+            IR::App(label, f, args, k, h) => match self.resolve(f) {
+                Atom::Var(var) if self.funs.contains_key(&var) => {
+                    let pending = self.funs.remove(&var).unwrap();
+                    count(&mut self.census.uses, &var, -1);
+                    count(&mut self.census.calls, &var, -1);
+                    let ir = self.inline(pending.params, pending.body, args, k, h);
+                    self.shrink(ir, fun)
+                }
+                lam @ Atom::Lam(..) if self.movable(&lam) => {
+                    let Atom::Lam(_, params, body) = lam else {
+                        unreachable!()
+                    };
+                    let ir = self.inline(params, *body, args, k, h);
+                    self.shrink(ir, fun)
+                }
+                f => {
+                    let f = self.shrink_atom(f);
+                    IR::App(label, f, self.shrink_atoms(args), k, h)
+                }
+            },
+            IR::Fix(label, vars, vals, body) => {
+                // None for the functions waiting to be inlined
+                let mut bundle = vec![];
+                for (var, val) in vars.into_iter().zip(vals) {
+                    if self.inlinable(&var, &val) {
+                        self.pend(&var, val);
+                        bundle.push((var, None));
+                    } else {
+                        bundle.push((var, Some(val)));
+                    }
+                }
+                let body = self.shrink(*body, fun);
+                // removing a function can leave its siblings unused
+                while let Some(i) = bundle
+                    .iter()
+                    .position(|(var, val)| val.is_some() && get(&self.census.uses, var) == 0)
+                {
+                    let (var, val) = bundle.remove(i);
+                    self.census_binders(std::slice::from_ref(&var), -1);
+                    self.census_atom(&val.unwrap(), -1);
+                }
+                let bundle: Vec<(String, Option<Atom>)> = bundle
+                    .into_iter()
+                    .map(|(var, val)| (var, val.map(|val| self.shrink_atom(val))))
+                    .collect();
+                let (mut vars, mut vals) = (vec![], vec![]);
+                for (var, val) in bundle {
+                    if let Some(val) = val.or_else(|| self.unused_fun(&var)) {
+                        vars.push(var);
+                        vals.push(val);
+                    }
+                }
+                if vars.is_empty() {
+                    body
+                } else {
+                    IR::Fix(label, vars, vals, Box::new(body))
+                }
+            }
+            IR::AppCont(label, k, args) => match &k {
+                Cont::Named(name) if self.conts.get(name).is_some_and(|p| p.fun == fun) => {
+                    let pending = self.conts.remove(name).unwrap();
+                    count(&mut self.census.cont_uses, name, -1);
+                    count(&mut self.census.jumps, name, -1);
+                    let ir = bind(&self.table, pending.params, args, pending.body);
+                    self.shrink(ir, fun)
+                }
+                _ => IR::AppCont(label, k, self.shrink_atoms(args)),
+            },
+            IR::Case(label, scrutinee, branches, default) => {
+                IR::Case(label, self.resolve(scrutinee), branches, default)
+            }
+        }
+    }
+}
+
+pub fn shrink(ir: IR) -> IR {
+    let mut shrinker = Shrinker {
+        census: Census::default(),
+        subst: HashMap::new(),
+        funs: HashMap::new(),
+        conts: HashMap::new(),
+        table: GenTable::after(&ir),
+    };
+    shrinker.census(&ir, 1);
+    shrinker.shrink(ir, None)
+}
@@ -0,0 +1,494 @@
+// Uncurrying and arity raising of functions bound by Fix, whose calls are all known.
+// A curried function λx. λy. body, only returning a lambda, becomes λ(x, y). body when
+// all its calls are saturated: the continuation of the call is used once and applies the
+// function it receives, after a chain of Let, LetVal and LetCont computing the arguments.
+// The chain is moved to the call, which passes the arguments of both calls and the
+// continuations of the second one. Returning the lambda has no effect, so computing the
+// second arguments first does not change the behavior, and the call has to be in the
+// function of the continuation for the continuations of the chain to keep their meaning.
+// A parameter which is only projected from is flattened into one parameter per field when
+// every call passes a variable bound to a Tuple application of variables or constants,
+// the calls pass the fields instead and the projections become copies of the new
+// parameters.
+// Code moves under other binders, so the variables in it must be bound once. Merging
+// lambdas creates new calls with all their arguments, so the pass repeats until nothing
+// changes.
+
+use crate::cps_ir::{Atom, Cont, GenTable, IR, builtin_call::BuiltinOp};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+enum Use {
+    // the operator of a call in the lambda, with these arguments and continuation
+    Call(Option<usize>, Vec<Atom>, Cont),
+    // the argument of a projection of the field
+    Proj(usize),
+    Other,
+}
+
+// the lambda, arguments and continuation of a call
+type Call<'c> = (Option<usize>, &'c [Atom], &'c Cont);
+
+// a continuation applying the function it receives, after a chain of Let, LetVal and
+// LetCont
+struct Apply {
+    param: String,
+    arity: usize,
+    // the free variables of the continuation, without its parameter
+    free: HashSet<String>,
+    // the variables and continuations bound by the chain
+    bound: Vec<String>,
+    conts: [Cont; 2],
+}
+
+// the parameters of a lambda bound by Fix, and those of the lambda it only returns
+struct Fun {
+    params: Vec<String>,
+    inner: Option<usize>,
+}
+
+#[derive(Default)]
+struct Census {
+    // variable or continuation => number of binders
+    binders: HashMap<String, usize>,
+    uses: HashMap<String, Vec<Use>>,
+    cont_uses: HashMap<String, usize>,
+    // continuation => the lambda it belongs to
+    owners: HashMap<String, Option<usize>>,
+    applies: HashMap<String, Apply>,
+    funs: HashMap<String, Fun>,
+    tuples: HashMap<String, Vec<Atom>>,
+}
+
+fn apply_of(ir: &IR, param: &str, bound: &mut Vec<String>) -> Option<(usize, [Cont; 2])> {
+    match ir {
+        IR::LetCont(_, name, _, _, body) => {
+            bound.push(name.clone());
+            apply_of(body, param, bound)
+        }
+        IR::Let(_, var, _, _, body) | IR::LetVal(_, var, _, body) => {
+            bound.push(var.clone());
+            apply_of(body, param, bound)
+        }
+        IR::App(_, Atom::Var(g), args, k, h) if g == param => {
+            Some((args.len(), [k.clone(), h.clone()]))
+        }
+        _ => None,
+    }
+}
+
+impl Census {
+    fn bind(&mut self, names: &[String]) {
+        for name in names {
+            *self.binders.entry(name.clone()).or_default() += 1;
+        }
+    }
+
+    fn unique(&self, name: &str) -> bool {
+        self.binders.get(name).is_none_or(|n| *n <= 1)
+    }
+
+    fn use_var(&mut self, var: &str, use_: Use) {
+        self.uses.entry(var.to_string()).or_default().push(use_);
+    }
+
+    fn use_cont(&mut self, cont: &Cont) {
+        if let Cont::Named(name) = cont {
+            *self.cont_uses.entry(name.clone()).or_default() += 1;
+        }
+    }
+
+    fn atom(&mut self, atom: &Atom) {
+        match atom {
+            Atom::Var(var) => self.use_var(var, Use::Other),
+            Atom::Cont(name) => self.use_cont(&Cont::Named(name.clone())),
+            Atom::Lam(label, params, body) => {
+                self.bind(params);
+                self.collect(body, Some(*label));
+            }
+            _ => (),
+        }
+    }
+
+    fn collect(&mut self, ir: &IR, fun: Option<usize>) {
+        match ir {
+            IR::LetCont(_, name, params, cont_body, body) => {
+                self.bind(std::slice::from_ref(name));
+                self.bind(params);
+                self.owners.insert(name.clone(), fun);
+                let mut bound = vec![];
+                if let [param] = &params[..]
+                    && let Some((arity, conts)) = apply_of(cont_body, param, &mut bound)
+                {
+                    let mut free = cont_body.free_vars();
+                    free.remove(param);
+                    let apply = Apply {
+                        param: param.clone(),
+                        arity,
+                        free,
+                        bound,
+                        conts,
+                    };
+                    self.applies.insert(name.clone(), apply);
+                }
+                self.collect(cont_body, fun);
+                self.collect(body, fun);
+            }
+            IR::Let(_, var, op, args, body) => {
+                self.bind(std::slice::from_ref(var));
+                match (op, &args[..]) {
+                    (BuiltinOp::Proj(i), [Atom::Var(tuple)]) => self.use_var(tuple, Use::Proj(*i)),
+                    _ => args.iter().for_each(|arg| self.atom(arg)),
+                }
+                if *op == BuiltinOp::Tuple {
+                    self.tuples.insert(var.clone(), args.clone());
+                }
+                self.collect(body, fun);
+            }
+            IR::LetVal(_, var, val, body) => {
+                self.bind(std::slice::from_ref(var));
+                self.atom(val);
+                self.collect(body, fun);
+            }
+            IR::If(_, test, then_, else_) => {
+                self.atom(test);
+                self.collect(then_, fun);
+                self.collect(else_, fun);
+            }
+            IR::App(_, f, args, k, h) => {
+                match f {
+                    Atom::Var(var) => self.use_var(var, Use::Call(fun, args.clone(), k.clone())),
+                    _ => self.atom(f),
+                }
+                args.iter().for_each(|arg| self.atom(arg));
+                self.use_cont(k);
+                self.use_cont(h);
+            }
+            IR::Fix(_, vars, vals, body) => {
+                self.bind(vars);
+                for (var, val) in vars.iter().zip(vals) {
+                    if let Atom::Lam(_, params, lam_body) = val {
+                        let inner = match &**lam_body {
+                            IR::AppCont(_, Cont::Return, results) => match &results[..] {
+                                [Atom::Lam(_, inner, _)] => Some(inner.len()),
+                                _ => None,
+                            },
+                            _ => None,
+                        };
+                        let params = params.clone();
+                        self.funs.insert(var.clone(), Fun { params, inner });
+                    }
+                    self.atom(val);
+                }
+                self.collect(body, fun);
+            }
+            IR::AppCont(_, k, args) => {
+                self.use_cont(k);
+                args.iter().for_each(|arg| self.atom(arg));
+            }
+            IR::Case(_, scrutinee, branches, default) => {
+                self.atom(scrutinee);
+                for k in branches.iter().map(|(_, k)| k).chain(default) {
+                    self.use_cont(k);
+                }
+            }
+        }
+    }
+
+    // an atom which can be copied under other binders, a lambda would be copied with
+    // its labels and binders
+    fn movable(&self, atom: &Atom) -> bool {
+        match atom {
+            Atom::Var(var) => self.unique(var),
+            Atom::Lam(..) | Atom::Cont(_) => false,
+            _ => true,
+        }
+    }
+
+    // the calls of the function bound to `var`, if it has no other use
+    fn calls(&self, var: &str, arity: usize) -> Option<Vec<Call<'_>>> {
+        if !self.unique(var) {
+            return None;
+        }
+        self.uses
+            .get(var)
+            .map_or(&[][..], |uses| uses.as_slice())
+            .iter()
+            .map(|use_| match use_ {
+                Use::Call(fun, args, k) if args.len() == arity => Some((*fun, args.as_slice(), k)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // the continuations removed when the calls of `var` are merged with the calls of the
+    // lambda it returns
+    fn uncurried(&self, var: &str, fun: &Fun) -> Option<Vec<String>> {
+        let inner = fun.inner?;
+        let mut conts = vec![];
+        for (caller, _, k) in self.calls(var, fun.params.len())? {
+            let Cont::Named(k) = k else {
+                return None;
+            };
+            let apply = self.applies.get(k)?;
+            let saturated = apply.arity == inner
+                && self.owners[k] == caller
+                && self.unique(k)
+                && self.cont_uses[k] == 1
+                && self.uses[&apply.param].len() == 1
+                && !apply.conts.contains(&Cont::Named(k.clone()))
+                && apply.free.iter().all(|var| self.unique(var))
+                && apply.bound.iter().all(|var| self.unique(var));
+            if !saturated {
+                return None;
+            }
+            conts.push(k.clone());
+        }
+        Some(conts)
+    }
+
+    // the number of fields of each parameter of `var` which is flattened
+    fn flattened(&self, var: &str, fun: &Fun) -> Option<Vec<Option<usize>>> {
+        let calls = self.calls(var, fun.params.len())?;
+        let widths: Vec<Option<usize>> = fun
+            .params
+            .iter()
+            .enumerate()
+            .map(|(i, param)| {
+                let uses = self.uses.get(param).filter(|uses| !uses.is_empty())?;
+                let mut width = None;
+                for (_, args, _) in &calls {
+                    let Atom::Var(tuple) = &args[i] else {
+                        return None;
+                    };
+                    let fields = self.tuples.get(tuple).filter(|_| self.unique(tuple))?;
+                    if width.is_some_and(|width| width != fields.len())
+                        || !fields.iter().all(|field| self.movable(field))
+                    {
+                        return None;
+                    }
+                    width = Some(fields.len());
+                }
+                let width = width?;
+                let projected = uses
+                    .iter()
+                    .all(|use_| matches!(use_, Use::Proj(j) if *j < width));
+                (self.unique(param) && projected).then_some(width)
+            })
+            .collect();
+        widths.iter().any(|width| width.is_some()).then_some(widths)
+    }
+
+    fn plan(&self, table: &Rc<RefCell<GenTable>>) -> Plan {
+        let mut plan = Plan::default();
+        let mut vars: Vec<&String> = self.funs.keys().collect();
+        vars.sort();
+        for var in vars {
+            let fun = &self.funs[var];
+            if let Some(conts) = self.uncurried(var, fun) {
+                plan.merged.insert(var.clone());
+                plan.removed.extend(conts);
+            } else if let Some(widths) = self.flattened(var, fun) {
+                for (param, width) in fun.params.iter().zip(&widths) {
+                    if let Some(width) = width {
+                        let fields = (0..*width)
+                            .map(|_| table.borrow_mut().alloc_var())
+                            .collect();
+                        plan.fields.insert(param.clone(), fields);
+                    }
+                }
+                plan.flattened.insert(var.clone(), widths);
+            }
+        }
+        plan
+    }
+}
+
+#[derive(Default)]
+struct Plan {
+    // the curried functions merged with the lambdas they return
+    merged: HashSet<String>,
+    // the continuations of their calls
+    removed: HashSet<String>,
+    // function => the number of fields of each flattened parameter
+    flattened: HashMap<String, Vec<Option<usize>>>,
+    // flattened parameter => the parameters of its fields
+    fields: HashMap<String, Vec<String>>,
+}
+
+impl Plan {
+    fn is_empty(&self) -> bool {
+        self.merged.is_empty() && self.flattened.is_empty()
+    }
+}
+
+// the chain of a removed continuation, ending with the call built from the arguments and
+// continuations of its call of the returned function
+fn splice(ir: IR, call: impl FnOnce(Vec<Atom>, Cont, Cont) -> IR) -> IR {
+    match ir {
+        IR::Let(label, var, op, args, body) => {
+            IR::Let(label, var, op, args, Box::new(splice(*body, call)))
+        }
+        IR::LetVal(label, var, val, body) => {
+            IR::LetVal(label, var, val, Box::new(splice(*body, call)))
+        }
+        IR::LetCont(label, name, params, cont_body, body) => IR::LetCont(
+            label,
+            name,
+            params,
+            cont_body,
+            Box::new(splice(*body, call)),
+        ),
+        IR::App(_, _, args, k, h) => call(args, k, h),
+        _ => unreachable!("not the chain of a continuation applying its parameter"),
+    }
+}
+
+struct Rewriter<'c> {
+    plan: Plan,
+    tuples: &'c HashMap<String, Vec<Atom>>,
+    // the bodies of the removed continuations, until their call is reached
+    pending: HashMap<String, IR>,
+}
+
+impl<'c> Rewriter<'c> {
+    fn rewrite_atom(&mut self, atom: Atom) -> Atom {
+        match atom {
+            Atom::Lam(label, params, body) => {
+                Atom::Lam(label, params, Box::new(self.rewrite(*body)))
+            }
+            atom => atom,
+        }
+    }
+
+    fn rewrite_atoms(&mut self, atoms: Vec<Atom>) -> Vec<Atom> {
+        atoms
+            .into_iter()
+            .map(|atom| self.rewrite_atom(atom))
+            .collect()
+    }
+
+    fn rewrite_fun(&mut self, var: &str, val: Atom) -> Atom {
+        let Atom::Lam(label, params, body) = val else {
+            return val;
+        };
+        if self.plan.merged.contains(var) {
+            let IR::AppCont(_, _, mut results) = *body else {
+                unreachable!("a curried function returns a lambda");
+            };
+            let Some(Atom::Lam(_, inner, inner_body)) = results.pop() else {
+                unreachable!("a curried function returns a lambda");
+            };
+            let params = params.into_iter().chain(inner).collect();
+            return Atom::Lam(label, params, Box::new(self.rewrite(*inner_body)));
+        }
+        let params = params
+            .into_iter()
+            .flat_map(|param| match self.plan.fields.get(&param) {
+                Some(fields) => fields.clone(),
+                None => vec![param],
+            })
+            .collect();
+        Atom::Lam(label, params, Box::new(self.rewrite(*body)))
+    }
+
+    fn rewrite(&mut self, ir: IR) -> IR {
+        match ir {
+            IR::LetCont(label, name, params, cont_body, body) => {
+                let cont_body = self.rewrite(*cont_body);
+                if self.plan.removed.contains(&name) {
+                    self.pending.insert(name, cont_body);
+                    return self.rewrite(*body);
+                }
+                IR::LetCont(
+                    label,
+                    name,
+                    params,
+                    Box::new(cont_body),
+                    Box::new(self.rewrite(*body)),
+                )
+            }
+            IR::Let(label, var, BuiltinOp::Proj(i), args, body) if matches!(&args[..], [Atom::Var(p)] if self.plan.fields.contains_key(p)) =>
+            {
+                let [Atom::Var(p)] = &args[..] else {
+                    unreachable!()
+                };
+                let field = Atom::Var(self.plan.fields[p][i].clone());
+                IR::LetVal(label, var, field, Box::new(self.rewrite(*body)))
+            }
+            IR::Let(label, var, op, args, body) => {
+                let args = self.rewrite_atoms(args);
+                IR::Let(label, var, op, args, Box::new(self.rewrite(*body)))
+            }
+            IR::LetVal(label, var, val, body) => {
+                let val = self.rewrite_atom(val);
+                IR::LetVal(label, var, val, Box::new(self.rewrite(*body)))
+            }
+            IR::If(label, test, then_, else_) => {
+                let then_ = self.rewrite(*then_);
+                IR::If(label, test, Box::new(then_), Box::new(self.rewrite(*else_)))
+            }
+            IR::App(label, Atom::Var(f), args, Cont::Named(k), _)
+                if self.plan.merged.contains(&f) =>
+            {
+                let args = self.rewrite_atoms(args);
+                let chain = self
+                    .pending
+                    .remove(&k)
+                    .expect("uncurry: continuation not found");
+                splice(chain, |inner_args, k, h| {
+                    let args = args.into_iter().chain(inner_args).collect();
+                    IR::App(label, Atom::Var(f), args, k, h)
+                })
+            }
+            IR::App(label, Atom::Var(f), args, k, h) if self.plan.flattened.contains_key(&f) => {
+                let widths = &self.plan.flattened[&f];
+                let args = args
+                    .into_iter()
+                    .zip(widths)
+                    .flat_map(|(arg, width)| match (arg, width) {
+                        (Atom::Var(tuple), Some(_)) => self.tuples[&tuple].clone(),
+                        (arg, _) => vec![arg],
+                    })
+                    .collect();
+                IR::App(label, Atom::Var(f), self.rewrite_atoms(args), k, h)
+            }
+            IR::App(label, f, args, k, h) => {
+                let f = self.rewrite_atom(f);
+                IR::App(label, f, self.rewrite_atoms(args), k, h)
+            }
+            IR::Fix(label, vars, vals, body) => {
+                let vals = vars
+                    .iter()
+                    .zip(vals)
+                    .map(|(var, val)| self.rewrite_fun(var, val))
+                    .collect();
+                IR::Fix(label, vars, vals, Box::new(self.rewrite(*body)))
+            }
+            IR::AppCont(label, k, args) => IR::AppCont(label, k, self.rewrite_atoms(args)),
+            case @ IR::Case(..) => case,
+        }
+    }
+}
+
+pub fn uncurry(ir: IR) -> IR {
+    let table = GenTable::after(&ir);
+    let mut ir = ir;
+    loop {
+        let mut census = Census::default();
+        census.collect(&ir, None);
+        let plan = census.plan(&table);
+        if plan.is_empty() {
+            break ir;
+        }
+        let mut rewriter = Rewriter {
+            plan,
+            tuples: &census.tuples,
+            pending: HashMap::new(),
+        };
+        ir = rewriter.rewrite(ir);
+    }
+}
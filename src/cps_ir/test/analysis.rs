@@ -0,0 +1,1208 @@
+use crate::cps_ir::analysis::control_flow::{self, AbstractValue};
+use crate::cps_ir::analysis::escape;
+use crate::cps_ir::analysis::interval::{self, Interval, NEG_INF, POS_INF};
+use crate::cps_ir::analysis::liveness;
+use crate::cps_ir::analysis::reaching_definitions::{self, Definition};
+use crate::cps_ir::analysis::sccp;
+use crate::cps_ir::builtin_call::BuiltinOp;
+use crate::cps_ir::cfg::NodePool;
+
+use super::super::{Atom, BuilderExpr as E, CaseTag, Cont, IR, quick_cps};
+use std::collections::HashSet;
+
+// the label of the first App whose operator is the variable `f`
+fn find_app(ir: &IR, f: &str) -> Option<usize> {
+    let in_atom = |atom: &Atom| match atom {
+        Atom::Lam(_, _, body) => find_app(body, f),
+        _ => None,
+    };
+    match ir {
+        IR::App(label, Atom::Var(v), _, _, _) if v == f => Some(*label),
+        IR::App(_, g, args, _, _) => in_atom(g).or_else(|| args.iter().find_map(in_atom)),
+        IR::LetCont(_, _, _, cont_body, body) => {
+            find_app(body, f).or_else(|| find_app(cont_body, f))
+        }
+        IR::Let(_, _, _, args, body) => args.iter().find_map(in_atom).or_else(|| find_app(body, f)),
+        IR::LetVal(_, _, val, body) => in_atom(val).or_else(|| find_app(body, f)),
+        IR::Fix(_, _, vals, body) => vals.iter().find_map(in_atom).or_else(|| find_app(body, f)),
+        IR::If(_, _, then_, else_) => find_app(then_, f).or_else(|| find_app(else_, f)),
+        IR::AppCont(_, _, args) => args.iter().find_map(in_atom),
+        IR::Case(..) => None,
+    }
+}
+
+// the label of the lambda bound to `var` by a LetVal
+fn lam_label(ir: &IR, var: &str) -> usize {
+    match ir {
+        IR::LetVal(_, v, Atom::Lam(label, _, _), _) if v == var => *label,
+        IR::LetVal(_, _, _, body)
+        | IR::Let(_, _, _, _, body)
+        | IR::Fix(_, _, _, body)
+        | IR::LetCont(_, _, _, _, body) => lam_label(body, var),
+        _ => panic!("lambda {var} not found"),
+    }
+}
+
+fn inc_dbl(body: E) -> IR {
+    let inc = E::lam(
+        &["x"],
+        E::papp(BuiltinOp::I32Add, vec![E::v("x"), E::i32(1)]),
+    );
+    let dbl = E::lam(
+        &["x"],
+        E::papp(BuiltinOp::I32Mul, vec![E::v("x"), E::i32(2)]),
+    );
+    quick_cps(E::let_("inc", inc, E::let_("dbl", dbl, body)))
+}
+
+#[test]
+pub fn test_cfa_call_strings() {
+    // id is called with both functions, only a call-string context separates the results
+    let ir = inc_dbl(E::let_(
+        "id",
+        E::lam(&["y"], E::v("y")),
+        E::let_(
+            "f1",
+            E::app(E::v("id"), vec![E::v("inc")]),
+            E::let_(
+                "f2",
+                E::app(E::v("id"), vec![E::v("dbl")]),
+                E::app(E::v("f1"), vec![E::i32(1)]),
+            ),
+        ),
+    ));
+    let inc = lam_label(&ir, "inc");
+    let dbl = lam_label(&ir, "dbl");
+    let call = find_app(&ir, "f1").unwrap();
+
+    let cfa0 = control_flow::analyze(&ir, 0);
+    assert_eq!(cfa0.callees(call), HashSet::from([inc, dbl]));
+    assert_eq!(
+        cfa0.values("y"),
+        HashSet::from([AbstractValue::Lam(inc), AbstractValue::Lam(dbl)])
+    );
+
+    let cfa1 = control_flow::analyze(&ir, 1);
+    assert_eq!(cfa1.callees(call), HashSet::from([inc]));
+    assert_eq!(cfa1.values("f1"), HashSet::from([AbstractValue::Lam(inc)]));
+}
+
+#[test]
+pub fn test_cfa_call_graph() {
+    // apply f x = f x, called with inc and dbl
+    let ir = inc_dbl(E::let_(
+        "apply",
+        E::lam(&["f", "x"], E::app(E::v("f"), vec![E::v("x")])),
+        E::papp(
+            BuiltinOp::I32Add,
+            vec![
+                E::app(E::v("apply"), vec![E::v("inc"), E::i32(1)]),
+                E::app(E::v("apply"), vec![E::v("dbl"), E::i32(2)]),
+            ],
+        ),
+    ));
+    let inc = lam_label(&ir, "inc");
+    let dbl = lam_label(&ir, "dbl");
+    let call = find_app(&ir, "f").unwrap();
+    let cfa = control_flow::analyze(&ir, 0);
+    assert!(!cfa.may_call_cont(call));
+
+    let mut pool: NodePool<'_, Trivial> = NodePool::new(true, Box::new(|_, _, l| l));
+    pool.construct_inter_with(&ir, &cfa.call_graph());
+    assert_eq!(pool.get_callees(call), &[inc, dbl]);
+
+    // syntactically the callee is unknown, every function is a candidate
+    let mut pool: NodePool<'_, Trivial> = NodePool::new(true, Box::new(|_, _, l| l));
+    pool.construct_inter(&ir);
+    assert_eq!(pool.get_callees(call).len(), 3);
+}
+
+#[test]
+pub fn test_cfa_escaping_cont() {
+    let ir = quick_cps(E::callcc(E::lam(
+        &["k"],
+        E::app(E::v("k"), vec![E::i32(1)]),
+    )));
+    let call = find_app(&ir, "k").unwrap();
+    let cfa = control_flow::analyze(&ir, 0);
+    assert!(cfa.may_call_cont(call));
+    assert!(cfa.callees(call).is_empty());
+    assert!(!cfa.call_graph().contains_key(&call));
+}
+
+// a continuation jumping to itself, passing a closure to its next iteration
+fn self_jumping_cont() -> IR {
+    // letcont k(f) = (g = λx. x; if b then k(g) else f(1)) in k(λy. y)
+    let id = |label, var: &str| {
+        Atom::lam(
+            label,
+            &[var],
+            IR::AppCont(label + 1, Cont::Return, vec![Atom::v(var)]),
+        )
+    };
+    IR::LetCont(
+        0,
+        "k".to_string(),
+        vec!["f".to_string()],
+        Box::new(IR::LetVal(
+            1,
+            "g".to_string(),
+            id(100, "x"),
+            Box::new(IR::If(
+                2,
+                Atom::v("b"),
+                Box::new(IR::AppCont(
+                    3,
+                    Cont::Named("k".to_string()),
+                    vec![Atom::v("g")],
+                )),
+                Box::new(IR::App(
+                    4,
+                    Atom::v("f"),
+                    vec![Atom::I32(1)],
+                    Cont::Return,
+                    Cont::Handler,
+                )),
+            )),
+        )),
+        Box::new(IR::AppCont(
+            5,
+            Cont::Named("k".to_string()),
+            vec![id(200, "y")],
+        )),
+    )
+}
+
+#[test]
+pub fn test_cfa_recursive_cont() {
+    let ir = self_jumping_cont();
+    // the jump from k to itself also reaches the call
+    let cfa = control_flow::analyze(&ir, 0);
+    assert_eq!(cfa.callees(4), HashSet::from([100, 200]));
+}
+
+#[test]
+pub fn test_escape_recursive_cont() {
+    let ir = self_jumping_cont();
+    // g only flows through the jump back into k, then is called
+    let escape = escape::analyze(&ir);
+    assert!(!escape.escapes(100));
+    assert!(!escape.escapes(0));
+}
+
+// n = 10; letcont loop(i) = (c = i < n; if c then (j = i + 1; loop(j)) else return i)
+// in loop(0)
+fn cont_loop() -> IR {
+    IR::LetVal(
+        0,
+        "n".to_string(),
+        Atom::I32(10),
+        Box::new(IR::LetCont(
+            1,
+            "loop".to_string(),
+            vec!["i".to_string()],
+            Box::new(IR::Let(
+                2,
+                "c".to_string(),
+                BuiltinOp::I32Lt,
+                vec![Atom::v("i"), Atom::v("n")],
+                Box::new(IR::If(
+                    3,
+                    Atom::v("c"),
+                    Box::new(IR::Let(
+                        4,
+                        "j".to_string(),
+                        BuiltinOp::I32Add,
+                        vec![Atom::v("i"), Atom::I32(1)],
+                        Box::new(IR::AppCont(
+                            5,
+                            Cont::Named("loop".to_string()),
+                            vec![Atom::v("j")],
+                        )),
+                    )),
+                    Box::new(IR::AppCont(6, Cont::Return, vec![Atom::v("i")])),
+                )),
+            )),
+            Box::new(IR::AppCont(
+                7,
+                Cont::Named("loop".to_string()),
+                vec![Atom::I32(0)],
+            )),
+        )),
+    )
+}
+
+#[test]
+pub fn test_dataflow_recursive_cont() {
+    let ir = cont_loop();
+    // the jump back into the loop keeps n live
+    let live = liveness::analyze(&ir);
+    assert_eq!(live.live_at(5), &vars(&["j", "n"]));
+    assert_eq!(live.live_at(2), &vars(&["i", "n"]));
+
+    // the parameter of the loop is defined on entry to its body, also by the back jump
+    let reaching = reaching_definitions::analyze(&ir);
+    assert_eq!(reaching.reaching_defs_of(2, "i"), defs(&[("i", 1)]));
+    assert_eq!(reaching.reaching_defs_of(2, "n"), defs(&[("n", 0)]));
+
+    // 0 and j meet at the loop, n stays constant around it
+    let result = sccp::analyze(&ir);
+    assert_eq!(result.constant_at(2, "i"), None);
+    assert_eq!(result.constant_at(5, "n"), Some(&Atom::I32(10)));
+    assert!(result.executable(5) && result.executable(6));
+
+    let result = interval::analyze(&ir);
+    assert_eq!(result.interval_at(2, "i"), Some(Interval::new(0, 10)));
+    assert_eq!(result.interval_at(6, "i"), Some(Interval::constant(10)));
+}
+
+#[derive(Clone, PartialEq, Eq)]
+struct Trivial;
+
+impl crate::cps_ir::cfg::Lattice for Trivial {
+    fn join(_: &Self, _: &Self) -> Self {
+        Trivial
+    }
+    fn bottom() -> Self {
+        Trivial
+    }
+}
+
+fn vars(names: &[&str]) -> HashSet<String> {
+    names.iter().map(|s| s.to_string()).collect()
+}
+
+#[test]
+pub fn test_liveness_straight_line() {
+    let ir = IR::LetVal(
+        0,
+        "a".to_string(),
+        Atom::I32(1),
+        Box::new(IR::Let(
+            1,
+            "b".to_string(),
+            BuiltinOp::I32Add,
+            vec![Atom::v("a"), Atom::v("a")],
+            Box::new(IR::Let(
+                2,
+                "c".to_string(),
+                BuiltinOp::I32Mul,
+                vec![Atom::v("b"), Atom::I32(2)],
+                Box::new(IR::AppCont(3, Cont::Return, vec![Atom::v("b")])),
+            )),
+        )),
+    );
+    let live = liveness::analyze(&ir);
+    assert_eq!(live.live_at(0), &vars(&[]));
+    assert_eq!(live.live_at(1), &vars(&["a"]));
+    assert_eq!(live.live_after(1), &vars(&["b"]));
+    // c is never used
+    assert_eq!(live.live_at(2), &vars(&["b"]));
+    assert_eq!(live.live_after(2), &vars(&["b"]));
+    assert_eq!(live.live_after(3), &vars(&[]));
+}
+
+#[test]
+pub fn test_liveness_join_point() {
+    // k(x) = let y = x + z in return y
+    // if t then k(a) else k(b)
+    let ir = IR::LetCont(
+        0,
+        "k".to_string(),
+        vec!["x".to_string()],
+        Box::new(IR::Let(
+            1,
+            "y".to_string(),
+            BuiltinOp::I32Add,
+            vec![Atom::v("x"), Atom::v("z")],
+            Box::new(IR::AppCont(2, Cont::Return, vec![Atom::v("y")])),
+        )),
+        Box::new(IR::If(
+            3,
+            Atom::v("t"),
+            Box::new(IR::AppCont(
+                4,
+                Cont::Named("k".to_string()),
+                vec![Atom::v("a")],
+            )),
+            Box::new(IR::AppCont(
+                5,
+                Cont::Named("k".to_string()),
+                vec![Atom::v("b")],
+            )),
+        )),
+    );
+    let live = liveness::analyze(&ir);
+    assert_eq!(live.live_at(1), &vars(&["x", "z"]));
+    // the jump defines the parameter x
+    assert_eq!(live.live_at(4), &vars(&["a", "z"]));
+    assert_eq!(live.live_at(5), &vars(&["b", "z"]));
+    assert_eq!(live.live_at(3), &vars(&["t", "a", "b", "z"]));
+    assert_eq!(live.live_at(0), &vars(&["t", "a", "b", "z"]));
+}
+
+#[test]
+pub fn test_liveness_fix() {
+    // fix loop(i) = if i then loop(n) else return acc in loop(start)
+    let body = IR::If(
+        11,
+        Atom::v("i"),
+        Box::new(IR::App(
+            12,
+            Atom::v("loop"),
+            vec![Atom::v("n")],
+            Cont::Return,
+            Cont::Handler,
+        )),
+        Box::new(IR::AppCont(13, Cont::Return, vec![Atom::v("acc")])),
+    );
+    let ir = IR::Fix(
+        0,
+        vec!["loop".to_string()],
+        vec![Atom::lam(10, &["i"], body)],
+        Box::new(IR::App(
+            1,
+            Atom::v("loop"),
+            vec![Atom::v("start")],
+            Cont::Return,
+            Cont::Handler,
+        )),
+    );
+    let live = liveness::analyze(&ir);
+    assert_eq!(live.live_at(0), &vars(&["n", "acc", "start"]));
+    assert_eq!(live.live_at(1), &vars(&["loop", "start"]));
+    assert_eq!(live.live_at(11), &vars(&["i", "loop", "n", "acc"]));
+    assert_eq!(live.live_at(12), &vars(&["loop", "n"]));
+    assert_eq!(live.live_at(13), &vars(&["acc"]));
+}
+
+#[test]
+pub fn test_liveness_across_call() {
+    let fact = E::lam(
+        &["x"],
+        E::if_(
+            E::papp(BuiltinOp::I32Leq, vec![E::v("x"), E::i32(1)]),
+            E::i32(1),
+            E::papp(
+                BuiltinOp::I32Mul,
+                vec![
+                    E::app(
+                        E::v("fact"),
+                        vec![E::papp(BuiltinOp::I32Sub, vec![E::v("x"), E::i32(1)])],
+                    ),
+                    E::v("x"),
+                ],
+            ),
+        ),
+    );
+    let ir = quick_cps(E::fix(
+        &["fact"],
+        vec![fact],
+        E::app(E::v("fact"), vec![E::i32(5)]),
+    ));
+    let call = find_app(&ir, "fact").unwrap();
+    let live = liveness::analyze(&ir);
+    // x is used by the multiplication after the recursive call returns
+    assert!(live.live_at(call).contains("x"));
+    assert!(live.live_after(call).contains("x"));
+    assert!(live.live_at(ir.get_label()).is_empty());
+}
+
+fn defs(defs: &[(&str, usize)]) -> HashSet<Definition> {
+    defs.iter()
+        .map(|(var, label)| Definition::new(var, *label))
+        .collect()
+}
+
+// x = 1; k(y) = let z = x + y in return z
+// if t then (x = 2; k(x)) else k(x)
+fn shadowed_join() -> IR {
+    IR::LetVal(
+        0,
+        "x".to_string(),
+        Atom::I32(1),
+        Box::new(IR::LetCont(
+            1,
+            "k".to_string(),
+            vec!["y".to_string()],
+            Box::new(IR::Let(
+                2,
+                "z".to_string(),
+                BuiltinOp::I32Add,
+                vec![Atom::v("x"), Atom::v("y")],
+                Box::new(IR::AppCont(3, Cont::Return, vec![Atom::v("z")])),
+            )),
+            Box::new(IR::If(
+                4,
+                Atom::v("t"),
+                Box::new(IR::LetVal(
+                    5,
+                    "x".to_string(),
+                    Atom::I32(2),
+                    Box::new(IR::AppCont(
+                        6,
+                        Cont::Named("k".to_string()),
+                        vec![Atom::v("x")],
+                    )),
+                )),
+                Box::new(IR::AppCont(
+                    7,
+                    Cont::Named("k".to_string()),
+                    vec![Atom::v("x")],
+                )),
+            )),
+        )),
+    )
+}
+
+#[test]
+pub fn test_reaching_definitions_join_point() {
+    let ir = shadowed_join();
+    let reaching = reaching_definitions::analyze(&ir);
+    assert_eq!(reaching.reaching_at(0), defs(&[]));
+    assert_eq!(reaching.reaching_at(6), defs(&[("x", 5)]));
+    assert_eq!(reaching.reaching_at(7), defs(&[("x", 0)]));
+    // both definitions of x flow into the continuation, y is defined on entry
+    assert_eq!(
+        reaching.reaching_at(2),
+        defs(&[("x", 0), ("x", 5), ("y", 1)])
+    );
+    assert_eq!(reaching.reaching_defs_of(3, "z"), defs(&[("z", 2)]));
+}
+
+#[test]
+pub fn test_reaching_definitions_call() {
+    // fix loop(i) = if i then loop(n) else return i in loop(start)
+    let body = IR::If(
+        11,
+        Atom::v("i"),
+        Box::new(IR::App(
+            12,
+            Atom::v("loop"),
+            vec![Atom::v("n")],
+            Cont::Return,
+            Cont::Handler,
+        )),
+        Box::new(IR::AppCont(13, Cont::Return, vec![Atom::v("i")])),
+    );
+    let ir = IR::LetVal(
+        0,
+        "n".to_string(),
+        Atom::I32(0),
+        Box::new(IR::Fix(
+            1,
+            vec!["loop".to_string()],
+            vec![Atom::lam(10, &["i"], body)],
+            Box::new(IR::App(
+                2,
+                Atom::v("loop"),
+                vec![Atom::v("n")],
+                Cont::Return,
+                Cont::Handler,
+            )),
+        )),
+    );
+    let reaching = reaching_definitions::analyze(&ir);
+    assert_eq!(reaching.reaching_at(2), defs(&[("n", 0), ("loop", 1)]));
+    // the definitions at the call site flow into the callee
+    assert_eq!(
+        reaching.reaching_at(11),
+        defs(&[("n", 0), ("loop", 1), ("i", 10)])
+    );
+}
+
+#[test]
+pub fn test_reaching_definitions_shadowing_callee() {
+    // x = 1; f = λx. return x; letcont k(r) = return x in f(5) -> k
+    let ir = IR::LetVal(
+        0,
+        "x".to_string(),
+        Atom::I32(1),
+        Box::new(IR::LetVal(
+            1,
+            "f".to_string(),
+            Atom::lam(
+                10,
+                &["x"],
+                IR::AppCont(11, Cont::Return, vec![Atom::v("x")]),
+            ),
+            Box::new(IR::LetCont(
+                2,
+                "k".to_string(),
+                vec!["r".to_string()],
+                Box::new(IR::AppCont(3, Cont::Return, vec![Atom::v("x")])),
+                Box::new(IR::App(
+                    4,
+                    Atom::v("f"),
+                    vec![Atom::I32(5)],
+                    Cont::Named("k".to_string()),
+                    Cont::Handler,
+                )),
+            )),
+        )),
+    );
+    let reaching = reaching_definitions::analyze(&ir);
+    assert_eq!(reaching.reaching_defs_of(11, "x"), defs(&[("x", 10)]));
+    // the parameter of the callee does not flow back to the caller
+    assert_eq!(reaching.reaching_defs_of(3, "x"), defs(&[("x", 0)]));
+    assert_eq!(reaching.reaching_defs_of(3, "r"), defs(&[("r", 2)]));
+}
+
+#[test]
+pub fn test_def_use_chains() {
+    let ir = shadowed_join();
+    let chains = reaching_definitions::def_use(&ir);
+    // the body of k is scoped under the first definition of x only
+    assert_eq!(chains.def_of(2, "x"), Some(&Definition::new("x", 0)));
+    assert_eq!(chains.def_of(2, "y"), Some(&Definition::new("y", 1)));
+    assert_eq!(chains.def_of(6, "x"), Some(&Definition::new("x", 5)));
+    assert_eq!(chains.def_of(4, "t"), None);
+    assert_eq!(chains.uses_of(&Definition::new("x", 0)), &[2, 7]);
+    assert_eq!(chains.uses_of(&Definition::new("x", 5)), &[6]);
+    assert_eq!(chains.uses_of(&Definition::new("z", 2)), &[3]);
+    assert_eq!(chains.definitions().count(), 4);
+
+    // lambda parameters and Fix bindings
+    let ir = quick_cps(E::fix(
+        &["f"],
+        vec![E::lam(&["a"], E::app(E::v("f"), vec![E::v("a")]))],
+        E::app(E::v("f"), vec![E::i32(1)]),
+    ));
+    let chains = reaching_definitions::def_use(&ir);
+    let f = chains.definitions().find(|def| def.var == "f").unwrap();
+    assert_eq!(chains.uses_of(f).len(), 2);
+    let a = chains.definitions().find(|def| def.var == "a").unwrap();
+    let call = find_app(&ir, "f").unwrap();
+    assert_eq!(chains.def_of(call, "a"), Some(a));
+}
+
+fn k(args: Vec<Atom>, label: usize) -> IR {
+    IR::AppCont(label, Cont::Named("k".to_string()), args)
+}
+
+// k(y) = let z = y + 1 in return z
+fn join_k(body: IR) -> IR {
+    IR::LetCont(
+        1,
+        "k".to_string(),
+        vec!["y".to_string()],
+        Box::new(IR::Let(
+            2,
+            "z".to_string(),
+            BuiltinOp::I32Add,
+            vec![Atom::v("y"), Atom::I32(1)],
+            Box::new(IR::AppCont(3, Cont::Return, vec![Atom::v("z")])),
+        )),
+        Box::new(body),
+    )
+}
+
+#[test]
+pub fn test_sccp_branch() {
+    // x = 1; c = x > 0; if c then k(x) else k(5)
+    let ir = IR::LetVal(
+        0,
+        "x".to_string(),
+        Atom::I32(1),
+        Box::new(join_k(IR::Let(
+            4,
+            "c".to_string(),
+            BuiltinOp::I32Gt,
+            vec![Atom::v("x"), Atom::I32(0)],
+            Box::new(IR::If(
+                5,
+                Atom::v("c"),
+                Box::new(k(vec![Atom::v("x")], 6)),
+                Box::new(k(vec![Atom::I32(5)], 7)),
+            )),
+        ))),
+    );
+    let result = sccp::analyze(&ir);
+    assert_eq!(result.constant_at(5, "c"), Some(&Atom::Bool(true)));
+    assert!(result.executable(6));
+    assert!(!result.executable(7));
+    // only the executable jump reaches the join point
+    assert_eq!(result.constant_at(2, "y"), Some(&Atom::I32(1)));
+    assert_eq!(result.constant_at(3, "z"), Some(&Atom::I32(2)));
+}
+
+#[test]
+pub fn test_sccp_join_point() {
+    let branches = |a: i32, b: i32| {
+        join_k(IR::If(
+            5,
+            Atom::v("t"),
+            Box::new(k(vec![Atom::I32(a)], 6)),
+            Box::new(k(vec![Atom::I32(b)], 7)),
+        ))
+    };
+    // the test is unknown, both jumps are executable
+    let ir = branches(4, 4);
+    let result = sccp::analyze(&ir);
+    assert!(result.executable(6) && result.executable(7));
+    assert_eq!(result.constant_at(3, "z"), Some(&Atom::I32(5)));
+    let ir = branches(4, 5);
+    let result = sccp::analyze(&ir);
+    assert_eq!(result.constant_at(2, "y"), None);
+    assert_eq!(result.constant_at(3, "z"), None);
+}
+
+#[test]
+pub fn test_sccp_case_and_overflow() {
+    // x = i32::MAX + 1 is not folded; case 2 of 1 => k(10) | 2 => k(20) | _ => k(30)
+    let case = IR::LetCont(
+        10,
+        "one".to_string(),
+        vec![],
+        Box::new(k(vec![Atom::I32(10)], 11)),
+        Box::new(IR::LetCont(
+            12,
+            "two".to_string(),
+            vec![],
+            Box::new(k(vec![Atom::I32(20)], 13)),
+            Box::new(IR::LetCont(
+                14,
+                "other".to_string(),
+                vec![],
+                Box::new(k(vec![Atom::I32(30)], 15)),
+                Box::new(IR::Case(
+                    16,
+                    Atom::I32(2),
+                    vec![
+                        (CaseTag::I32(1), Cont::Named("one".to_string())),
+                        (CaseTag::I32(2), Cont::Named("two".to_string())),
+                    ],
+                    Some(Cont::Named("other".to_string())),
+                )),
+            )),
+        )),
+    );
+    let ir = IR::Let(
+        0,
+        "x".to_string(),
+        BuiltinOp::I32Add,
+        vec![Atom::I32(i32::MAX), Atom::I32(1)],
+        Box::new(join_k(case)),
+    );
+    let result = sccp::analyze(&ir);
+    assert_eq!(result.constant_at(1, "x"), None);
+    assert!(result.executable(13));
+    assert!(!result.executable(11) && !result.executable(15));
+    assert_eq!(result.constant_at(3, "z"), Some(&Atom::I32(21)));
+}
+
+#[test]
+pub fn test_sccp_shadowed_parameter() {
+    // x = 1; f = λx. (y = x + 1; return y); f(5)
+    let ir = IR::LetVal(
+        0,
+        "x".to_string(),
+        Atom::I32(1),
+        Box::new(IR::LetVal(
+            1,
+            "f".to_string(),
+            Atom::lam(
+                10,
+                &["x"],
+                IR::Let(
+                    2,
+                    "y".to_string(),
+                    BuiltinOp::I32Add,
+                    vec![Atom::v("x"), Atom::I32(1)],
+                    Box::new(IR::AppCont(3, Cont::Return, vec![Atom::v("y")])),
+                ),
+            ),
+            Box::new(IR::App(
+                4,
+                Atom::v("f"),
+                vec![Atom::I32(5)],
+                Cont::Return,
+                Cont::Handler,
+            )),
+        )),
+    );
+    let result = sccp::analyze(&ir);
+    assert_eq!(result.constant_at(4, "x"), Some(&Atom::I32(1)));
+    // the parameter is not the outer constant
+    assert!(result.executable(2));
+    assert_eq!(result.constant_at(2, "x"), None);
+    assert_eq!(result.constant_at(3, "y"), None);
+}
+
+#[test]
+pub fn test_interval_operations() {
+    let a = Interval::new(0, 10);
+    let b = Interval::new(-2, 3);
+    assert_eq!(a.add(&b), Interval::new(-2, 13));
+    assert_eq!(a.sub(&b), Interval::new(-3, 12));
+    assert_eq!(a.mul(&b), Interval::new(-20, 30));
+    assert_eq!(a.widen(&Interval::new(0, 11)), Interval::new(0, POS_INF));
+    assert_eq!(a.widen(&Interval::new(-1, 5)), Interval::new(NEG_INF, 10));
+    assert_eq!(
+        Interval::new(0, POS_INF).narrow(&Interval::new(0, 100)),
+        Interval::new(0, 100)
+    );
+    assert_eq!(Interval::TOP.add(&a), Interval::TOP);
+}
+
+// fix loop(i) = if t then (let j = i op 1 in loop(j)) else return i in loop(start)
+fn counting_loop(op: BuiltinOp, start: Atom) -> IR {
+    let body = IR::If(
+        11,
+        Atom::v("t"),
+        Box::new(IR::Let(
+            12,
+            "j".to_string(),
+            op,
+            vec![Atom::v("i"), Atom::I32(1)],
+            Box::new(IR::App(
+                13,
+                Atom::v("loop"),
+                vec![Atom::v("j")],
+                Cont::Return,
+                Cont::Handler,
+            )),
+        )),
+        Box::new(IR::AppCont(14, Cont::Return, vec![Atom::v("i")])),
+    );
+    IR::Fix(
+        0,
+        vec!["loop".to_string()],
+        vec![Atom::lam(10, &["i"], body)],
+        Box::new(IR::App(
+            1,
+            Atom::v("loop"),
+            vec![start],
+            Cont::Return,
+            Cont::Handler,
+        )),
+    )
+}
+
+#[test]
+pub fn test_interval_widening() {
+    // i * 1 is stable after one iteration
+    let ir = counting_loop(BuiltinOp::I32Mul, Atom::I32(5));
+    let result = interval::analyze(&ir);
+    assert_eq!(result.interval_at(11, "i"), Some(Interval::constant(5)));
+    assert_eq!(result.interval_at(13, "j"), Some(Interval::constant(5)));
+
+    // i + 1 grows at every iteration, the widening gives up on the bounds and
+    // the narrowing recovers the range of I32, as the addition may wrap around
+    let ir = counting_loop(BuiltinOp::I32Add, Atom::I32(0));
+    let result = interval::analyze(&ir);
+    let i32_range = Interval::new(i32::MIN as i128, i32::MAX as i128);
+    assert_eq!(result.interval_at(11, "i"), Some(i32_range));
+    assert_eq!(result.interval_at(14, "i"), Some(i32_range));
+}
+
+#[test]
+pub fn test_range_overflow() {
+    let ir = IR::Let(
+        0,
+        "a".to_string(),
+        BuiltinOp::U32Add,
+        vec![Atom::U32(3), Atom::U32(5)],
+        Box::new(IR::Let(
+            1,
+            "b".to_string(),
+            BuiltinOp::U32Sub,
+            vec![Atom::U32(3), Atom::v("a")],
+            Box::new(IR::Let(
+                2,
+                "c".to_string(),
+                BuiltinOp::U64Div,
+                vec![Atom::U64(100), Atom::v("t")],
+                Box::new(IR::AppCont(3, Cont::Return, vec![Atom::v("b")])),
+            )),
+        )),
+    );
+    let result = interval::analyze(&ir);
+    assert!(!result.may_overflow(0));
+    assert_eq!(result.interval_at(1, "a"), Some(Interval::constant(8)));
+    // 3 - 8 wraps around
+    assert!(result.may_overflow(1));
+    assert_eq!(
+        result.interval_at(2, "b"),
+        Some(Interval::new(0, u32::MAX as i128))
+    );
+    // the divisor may be zero
+    assert!(result.may_overflow(2));
+}
+
+#[test]
+pub fn test_range_bounds_check() {
+    // arr = array(10, 0)
+    // fix loop(i) = if i < 10 then (arr[i]; j = i + 1; arr[j]; loop(j)) else return i
+    // in loop(0)
+    let access = |label, var: &str, index: &str, body| {
+        IR::Let(
+            label,
+            var.to_string(),
+            BuiltinOp::ArrayGet,
+            vec![Atom::v("arr"), Atom::v(index)],
+            Box::new(body),
+        )
+    };
+    let then_ = access(
+        13,
+        "x",
+        "i",
+        IR::Let(
+            14,
+            "j".to_string(),
+            BuiltinOp::I32Add,
+            vec![Atom::v("i"), Atom::I32(1)],
+            Box::new(access(
+                15,
+                "y",
+                "j",
+                IR::App(
+                    16,
+                    Atom::v("loop"),
+                    vec![Atom::v("j")],
+                    Cont::Return,
+                    Cont::Handler,
+                ),
+            )),
+        ),
+    );
+    let body = IR::Let(
+        11,
+        "c".to_string(),
+        BuiltinOp::I32Lt,
+        vec![Atom::v("i"), Atom::I32(10)],
+        Box::new(IR::If(
+            12,
+            Atom::v("c"),
+            Box::new(then_),
+            Box::new(IR::AppCont(17, Cont::Return, vec![Atom::v("i")])),
+        )),
+    );
+    let ir = IR::Let(
+        0,
+        "arr".to_string(),
+        BuiltinOp::ArrayAlloc,
+        vec![Atom::I32(10), Atom::I32(0)],
+        Box::new(IR::Fix(
+            1,
+            vec!["loop".to_string()],
+            vec![Atom::lam(10, &["i"], body)],
+            Box::new(IR::App(
+                2,
+                Atom::v("loop"),
+                vec![Atom::I32(0)],
+                Cont::Return,
+                Cont::Handler,
+            )),
+        )),
+    );
+    let result = interval::analyze(&ir);
+    assert_eq!(result.interval_at(11, "i"), Some(Interval::new(0, 10)));
+    assert_eq!(result.interval_at(13, "i"), Some(Interval::new(0, 9)));
+    assert_eq!(result.interval_at(17, "i"), Some(Interval::constant(10)));
+    assert_eq!(result.interval_at(13, "arr"), Some(Interval::constant(10)));
+    assert!(!result.may_overflow(14));
+    assert!(result.index_in_bounds(13));
+    assert!(!result.index_in_bounds(15));
+}
+
+// arr = array(10, 0)
+// fix f(i) = if i == 3 then return 0 else letcont k(r) = cont_body in f(3) -> k
+// in f(100)
+fn non_tail_recursion(cont_body: IR) -> IR {
+    IR::Let(
+        0,
+        "arr".to_string(),
+        BuiltinOp::ArrayAlloc,
+        vec![Atom::I32(10), Atom::I32(0)],
+        Box::new(IR::Fix(
+            1,
+            vec!["f".to_string()],
+            vec![Atom::lam(
+                10,
+                &["i"],
+                IR::Let(
+                    11,
+                    "c".to_string(),
+                    BuiltinOp::I32Eq,
+                    vec![Atom::v("i"), Atom::I32(3)],
+                    Box::new(IR::If(
+                        12,
+                        Atom::v("c"),
+                        Box::new(IR::AppCont(13, Cont::Return, vec![Atom::I32(0)])),
+                        Box::new(IR::LetCont(
+                            14,
+                            "k".to_string(),
+                            vec!["r".to_string()],
+                            Box::new(cont_body),
+                            Box::new(IR::App(
+                                17,
+                                Atom::v("f"),
+                                vec![Atom::I32(3)],
+                                Cont::Named("k".to_string()),
+                                Cont::Handler,
+                            )),
+                        )),
+                    )),
+                ),
+            )],
+            Box::new(IR::App(
+                2,
+                Atom::v("f"),
+                vec![Atom::I32(100)],
+                Cont::Return,
+                Cont::Handler,
+            )),
+        )),
+    )
+}
+
+#[test]
+pub fn test_range_non_tail_recursion() {
+    // arr[i]; return r
+    let ir = non_tail_recursion(IR::Let(
+        15,
+        "x".to_string(),
+        BuiltinOp::ArrayGet,
+        vec![Atom::v("arr"), Atom::v("i")],
+        Box::new(IR::AppCont(16, Cont::Return, vec![Atom::v("r")])),
+    ));
+    let result = interval::analyze(&ir);
+    assert_eq!(result.interval_at(13, "i"), Some(Interval::constant(3)));
+    // the callee binds i to 3 in its own frame, the caller resumes with its own i
+    assert_eq!(result.interval_at(15, "i"), Some(Interval::new(3, 100)));
+    assert_eq!(result.interval_at(15, "r"), Some(Interval::TOP));
+    assert!(!result.index_in_bounds(15));
+}
+
+#[test]
+pub fn test_range_refinement_across_calls() {
+    // x = i * 100000000; arr[i]; return x
+    let ir = non_tail_recursion(IR::Let(
+        15,
+        "x".to_string(),
+        BuiltinOp::I32Mul,
+        vec![Atom::v("i"), Atom::I32(100000000)],
+        Box::new(IR::Let(
+            16,
+            "y".to_string(),
+            BuiltinOp::ArrayGet,
+            vec![Atom::v("arr"), Atom::v("i")],
+            Box::new(IR::AppCont(18, Cont::Return, vec![Atom::v("x")])),
+        )),
+    ));
+    let result = interval::analyze(&ir);
+    // i == 3 holds in the callee only, the refinement does not reach the return
+    assert_eq!(result.interval_at(15, "i"), Some(Interval::new(3, 100)));
+    assert!(result.may_overflow(15));
+    assert!(!result.index_in_bounds(16));
+}
+
+#[test]
+pub fn test_range_entries() {
+    // x = 1; f = λy. (z = x + y; return z); x = 100; f(5)
+    let ir = IR::LetVal(
+        0,
+        "x".to_string(),
+        Atom::I32(1),
+        Box::new(IR::LetVal(
+            1,
+            "f".to_string(),
+            Atom::lam(
+                10,
+                &["y"],
+                IR::Let(
+                    11,
+                    "z".to_string(),
+                    BuiltinOp::I32Add,
+                    vec![Atom::v("x"), Atom::v("y")],
+                    Box::new(IR::AppCont(12, Cont::Return, vec![Atom::v("z")])),
+                ),
+            ),
+            Box::new(IR::LetVal(
+                2,
+                "x".to_string(),
+                Atom::I32(100),
+                Box::new(IR::App(
+                    3,
+                    Atom::v("f"),
+                    vec![Atom::I32(5)],
+                    Cont::Return,
+                    Cont::Handler,
+                )),
+            )),
+        )),
+    );
+    let result = interval::analyze(&ir);
+    // the program entry is reachable, with nothing bound
+    assert_eq!(result.interval_at(0, "x"), Some(Interval::TOP));
+    assert_eq!(result.interval_at(3, "x"), Some(Interval::constant(100)));
+    assert_eq!(result.interval_at(11, "y"), Some(Interval::constant(5)));
+    // the x of the caller is not the one in the scope of the callee
+    assert_eq!(result.interval_at(11, "x"), Some(Interval::TOP));
+}
+
+#[test]
+pub fn test_range_rebound_operand() {
+    // x = 5; c = x < 10; x = 20; if c then return x + 1 else return 0
+    let ir = IR::LetVal(
+        0,
+        "x".to_string(),
+        Atom::I32(5),
+        Box::new(IR::Let(
+            1,
+            "c".to_string(),
+            BuiltinOp::I32Lt,
+            vec![Atom::v("x"), Atom::I32(10)],
+            Box::new(IR::LetVal(
+                2,
+                "x".to_string(),
+                Atom::I32(20),
+                Box::new(IR::If(
+                    3,
+                    Atom::v("c"),
+                    Box::new(IR::Let(
+                        4,
+                        "y".to_string(),
+                        BuiltinOp::I32Add,
+                        vec![Atom::v("x"), Atom::I32(1)],
+                        Box::new(IR::AppCont(5, Cont::Return, vec![Atom::v("y")])),
+                    )),
+                    Box::new(IR::AppCont(6, Cont::Return, vec![Atom::I32(0)])),
+                )),
+            )),
+        )),
+    );
+    let result = interval::analyze(&ir);
+    // the comparison is on the first x, the branch does not refine the second one
+    assert_eq!(result.interval_at(4, "x"), Some(Interval::constant(20)));
+    assert_eq!(result.interval_at(6, "x"), Some(Interval::constant(20)));
+}
+
+#[test]
+pub fn test_escape_closures() {
+    let id = || E::lam(&["x"], E::v("x"));
+    // called directly, also through a copy
+    let ir = quick_cps(E::let_(
+        "f",
+        id(),
+        E::let_("g", E::v("f"), E::app(E::v("g"), vec![E::i32(1)])),
+    ));
+    let escape = escape::analyze(&ir);
+    assert!(!escape.escapes(lam_label(&ir, "f")));
+
+    // passed to a function, and captured by a closure which is passed to one
+    let ir = quick_cps(E::let_(
+        "f",
+        id(),
+        E::let_(
+            "g",
+            E::lam(&["y"], E::app(E::v("f"), vec![E::v("y")])),
+            E::let_("h", id(), E::app(E::v("h"), vec![E::v("g")])),
+        ),
+    ));
+    let escape = escape::analyze(&ir);
+    assert!(escape.escapes(lam_label(&ir, "g")));
+    assert!(escape.escapes(lam_label(&ir, "f")));
+    assert!(!escape.escapes(lam_label(&ir, "h")));
+    assert_eq!(escape.closures().len(), 3);
+
+    // returned
+    let ir = quick_cps(E::let_("f", id(), E::v("f")));
+    assert!(escape::analyze(&ir).escapes(lam_label(&ir, "f")));
+}
+
+#[test]
+pub fn test_escape_continuations() {
+    // j(x) = return x; r(y) = j(y); f(1) -> r
+    let ir = IR::LetCont(
+        0,
+        "j".to_string(),
+        vec!["x".to_string()],
+        Box::new(IR::AppCont(1, Cont::Return, vec![Atom::v("x")])),
+        Box::new(IR::LetCont(
+            2,
+            "r".to_string(),
+            vec!["y".to_string()],
+            Box::new(IR::AppCont(
+                3,
+                Cont::Named("j".to_string()),
+                vec![Atom::v("y")],
+            )),
+            Box::new(IR::App(
+                4,
+                Atom::v("f"),
+                vec![Atom::I32(1)],
+                Cont::Named("r".to_string()),
+                Cont::Handler,
+            )),
+        )),
+    );
+    let escape = escape::analyze(&ir);
+    assert!(!escape.escapes(0));
+    assert!(escape.escapes(2));
+    assert_eq!(escape.continuations(), &HashSet::from([0, 2]));
+
+    // j(x) = return x; g = λ(). j(1); g()
+    let ir = IR::LetCont(
+        0,
+        "j".to_string(),
+        vec!["x".to_string()],
+        Box::new(IR::AppCont(1, Cont::Return, vec![Atom::v("x")])),
+        Box::new(IR::LetVal(
+            2,
+            "g".to_string(),
+            Atom::lam(
+                10,
+                &[],
+                IR::AppCont(11, Cont::Named("j".to_string()), vec![Atom::I32(1)]),
+            ),
+            Box::new(IR::App(
+                3,
+                Atom::v("g"),
+                vec![],
+                Cont::Return,
+                Cont::Handler,
+            )),
+        )),
+    );
+    let escape = escape::analyze(&ir);
+    // the jump leaves the function
+    assert!(escape.escapes(0));
+    assert!(!escape.escapes(10));
+
+    // captured as a value
+    let ir = IR::LetCont(
+        0,
+        "k".to_string(),
+        vec!["x".to_string()],
+        Box::new(IR::AppCont(1, Cont::Return, vec![Atom::v("x")])),
+        Box::new(IR::LetVal(
+            2,
+            "c".to_string(),
+            Atom::Cont("k".to_string()),
+            Box::new(IR::AppCont(3, Cont::Return, vec![Atom::I32(1)])),
+        )),
+    );
+    assert!(escape::analyze(&ir).escapes(0));
+
+    // the continuation of callcc escapes, and the closure called in its body with it
+    let ir = quick_cps(E::let_(
+        "f",
+        E::lam(&["x"], E::v("x")),
+        E::let_(
+            "y",
+            E::callcc(E::lam(&["k"], E::app(E::v("k"), vec![E::i32(1)]))),
+            E::app(E::v("f"), vec![E::v("y")]),
+        ),
+    ));
+    let escape = escape::analyze(&ir);
+    assert!(
+        escape
+            .continuations()
+            .iter()
+            .any(|label| escape.escapes(*label))
+    );
+    assert!(escape.escapes(lam_label(&ir, "f")));
+}
@@ -0,0 +1,349 @@
+use crate::cps_ir::builtin_call::BuiltinOp;
+use crate::cps_ir::cfg::{Lattice, NodePool};
+
+use super::super::{Atom, BuilderExpr as E, Cont, IR, quick_cps};
+use std::collections::HashSet;
+
+// the set of labels on some path from the program entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Trace(HashSet<usize>);
+
+impl Lattice for Trace {
+    fn join(a: &Self, b: &Self) -> Self {
+        Trace(a.0.union(&b.0).cloned().collect())
+    }
+    fn bottom() -> Self {
+        Trace(HashSet::new())
+    }
+}
+
+fn trace_analysis<'a>() -> NodePool<'a, Trace> {
+    NodePool::new(
+        true,
+        Box::new(|label, _ir, mut trace: Trace| {
+            trace.0.insert(label);
+            trace
+        }),
+    )
+}
+
+// double x = x + x; double (double 1)
+fn double_program() -> IR {
+    let double = E::lam(
+        &["x"],
+        E::papp(BuiltinOp::I32Add, vec![E::v("x"), E::v("x")]),
+    );
+    quick_cps(E::let_(
+        "double",
+        double,
+        E::app(
+            E::v("double"),
+            vec![E::app(E::v("double"), vec![E::i32(1)])],
+        ),
+    ))
+}
+
+fn lambda_of(ir: &IR) -> (usize, usize) {
+    let IR::LetVal(_, _, Atom::Lam(label, _, body), _) = ir else {
+        panic!("expect a LetVal of a lambda")
+    };
+    (*label, body.get_label())
+}
+
+fn call_labels(ir: &IR, labels: &mut Vec<usize>) {
+    match ir {
+        IR::App(label, ..) => labels.push(*label),
+        IR::LetCont(_, _, _, cont_body, body) => {
+            call_labels(body, labels);
+            call_labels(cont_body, labels);
+        }
+        IR::LetVal(_, _, _, body) | IR::Let(_, _, _, _, body) | IR::Fix(_, _, _, body) => {
+            call_labels(body, labels)
+        }
+        _ => (),
+    }
+}
+
+#[test]
+pub fn test_intra_cfg() {
+    let ir = double_program();
+    let (_, body_label) = lambda_of(&ir);
+    let mut analysis = trace_analysis();
+    analysis.construct_intra(&ir);
+    analysis.run_worklist();
+    let at_exit = analysis.get_result_in(analysis.get_prog_exit());
+    assert!(at_exit.0.contains(&ir.get_label()));
+    assert!(!at_exit.0.contains(&body_label));
+}
+
+#[test]
+pub fn test_inter_cfg() {
+    let ir = double_program();
+    let (fun_label, body_label) = lambda_of(&ir);
+    let mut analysis = trace_analysis();
+    analysis.construct_inter(&ir);
+    analysis.run_worklist();
+
+    let mut calls = vec![];
+    call_labels(&ir, &mut calls);
+    assert_eq!(calls.len(), 2);
+    for call in calls {
+        assert_eq!(analysis.get_callees(call), &[fun_label]);
+    }
+    let at_exit = analysis.get_result_in(analysis.get_prog_exit());
+    assert!(at_exit.0.contains(&body_label));
+    let at_fun_exit = analysis.get_result_in(analysis.get_fun_exit(fun_label));
+    assert!(at_fun_exit.0.contains(&body_label));
+}
+
+#[test]
+pub fn test_local_cfg() {
+    let ir = double_program();
+    let (fun_label, body_label) = lambda_of(&ir);
+    let mut analysis = trace_analysis();
+    analysis.construct_local(&ir);
+    analysis.run_worklist();
+
+    let mut calls = vec![];
+    call_labels(&ir, &mut calls);
+    for call in calls {
+        assert_eq!(analysis.get_callees(call), &[fun_label]);
+    }
+    // the callee is entered, but the caller continues from the call site
+    let at_fun_exit = analysis.get_result_in(analysis.get_fun_exit(fun_label));
+    assert!(at_fun_exit.0.contains(&body_label));
+    let at_exit = analysis.get_result_in(analysis.get_prog_exit());
+    assert!(at_exit.0.contains(&ir.get_label()));
+    assert!(!at_exit.0.contains(&body_label));
+}
+
+#[test]
+pub fn test_inter_cfg_unknown_callee() {
+    // apply f x = f x, the callee of `f x` is not known syntactically
+    let apply = E::lam(&["f", "x"], E::app(E::v("f"), vec![E::v("x")]));
+    let id = E::lam(&["y"], E::v("y"));
+    let ir = quick_cps(E::let_(
+        "apply",
+        apply,
+        E::app(E::v("apply"), vec![id, E::i32(1)]),
+    ));
+    let mut analysis = trace_analysis();
+    analysis.construct_inter(&ir);
+    analysis.run_worklist();
+
+    let IR::LetVal(_, _, Atom::Lam(apply_label, _, apply_body), body) = &ir else {
+        panic!("expect a LetVal of a lambda")
+    };
+    let mut inner_calls = vec![];
+    call_labels(apply_body, &mut inner_calls);
+    let mut outer_calls = vec![];
+    call_labels(body, &mut outer_calls);
+    assert_eq!(analysis.get_callees(outer_calls[0]), &[*apply_label]);
+    // conservatively every function, including `apply` itself and the identity
+    assert_eq!(analysis.get_callees(inner_calls[0]).len(), 2);
+}
+
+#[test]
+pub fn test_dominators() {
+    // k(y) = return y in if t then k(1) else k(2)
+    let ir = IR::LetCont(
+        0,
+        "k".to_string(),
+        vec!["y".to_string()],
+        Box::new(IR::AppCont(1, Cont::Return, vec![Atom::v("y")])),
+        Box::new(IR::If(
+            2,
+            Atom::v("t"),
+            Box::new(IR::AppCont(
+                3,
+                Cont::Named("k".to_string()),
+                vec![Atom::I32(1)],
+            )),
+            Box::new(IR::AppCont(
+                4,
+                Cont::Named("k".to_string()),
+                vec![Atom::I32(2)],
+            )),
+        )),
+    );
+    let mut pool = trace_analysis();
+    pool.construct_intra(&ir);
+    let [join, branch, then_, else_] = [1, 2, 3, 4].map(|label| pool.get_node(label));
+    let dom = pool.dominators(pool.get_prog_entry());
+    assert_eq!(dom.idom(join), Some(branch));
+    assert_eq!(dom.idom(then_), Some(branch));
+    assert!(dom.dominates(pool.get_node(0), join));
+    assert!(!dom.dominates(then_, join));
+    assert_eq!(dom.idom(pool.get_prog_entry()), None);
+    assert_eq!(dom.frontier(then_), &HashSet::from([join]));
+    assert_eq!(dom.frontier(else_), &HashSet::from([join]));
+    assert!(dom.frontier(branch).is_empty());
+
+    let post_dom = pool.post_dominators(pool.get_prog_exit());
+    assert_eq!(post_dom.idom(then_), Some(join));
+    assert_eq!(post_dom.idom(branch), Some(join));
+    // the branches are control dependent on the If
+    assert_eq!(post_dom.frontier(then_), &HashSet::from([branch]));
+    assert!(pool.natural_loops(pool.get_prog_entry()).is_empty());
+}
+
+#[test]
+pub fn test_natural_loops() {
+    // fix loop(i) = if i then loop(n) else return i in loop(start)
+    let body = IR::If(
+        11,
+        Atom::v("i"),
+        Box::new(IR::App(
+            12,
+            Atom::v("loop"),
+            vec![Atom::v("n")],
+            Cont::Return,
+            Cont::Handler,
+        )),
+        Box::new(IR::AppCont(13, Cont::Return, vec![Atom::v("i")])),
+    );
+    let ir = IR::Fix(
+        0,
+        vec!["loop".to_string()],
+        vec![Atom::lam(10, &["i"], body)],
+        Box::new(IR::App(
+            1,
+            Atom::v("loop"),
+            vec![Atom::v("start")],
+            Cont::Return,
+            Cont::Handler,
+        )),
+    );
+    let mut pool = trace_analysis();
+    pool.construct_intra(&ir);
+    assert!(pool.natural_loops(pool.get_prog_entry()).is_empty());
+
+    let mut pool = trace_analysis();
+    pool.construct_inter(&ir);
+    let entry = pool.get_fun_entry(10);
+    let loops = pool.natural_loops(pool.get_prog_entry());
+    let fix_loop = loops.iter().find(|l| l.header == entry).unwrap();
+    assert_eq!(fix_loop.latches, vec![pool.get_node(12)]);
+    assert_eq!(
+        fix_loop.body,
+        HashSet::from([entry, pool.get_node(11), pool.get_node(12)])
+    );
+    let dom = pool.dominators(pool.get_prog_entry());
+    assert!(dom.dominates(entry, pool.get_node(13)));
+}
+
+#[test]
+pub fn test_dot_export() {
+    let ir = double_program();
+    let (fun, body) = lambda_of(&ir);
+    let mut pool = trace_analysis();
+    pool.construct_inter(&ir);
+    pool.run_worklist();
+
+    let dot = pool.to_dot(None);
+    assert!(dot.starts_with("digraph cfg {"));
+    let entry = pool.get_prog_entry();
+    let start = pool.get_node(ir.get_label());
+    assert!(dot.contains(&format!("n{entry} [label=\"program entry\"];")));
+    assert!(dot.contains(&format!("n{entry} -> n{start};")));
+    let fun_entry = pool.get_fun_entry(fun);
+    assert!(dot.contains(&format!("n{fun_entry} [label=\"entry lam{fun}\"];")));
+    assert!(dot.contains(&format!("n{fun_entry} -> n{};", pool.get_node(body))));
+    assert!(dot.contains(&format!(
+        "[label=\"{}: letval double = lam{fun}(",
+        ir.get_label()
+    )));
+    assert!(!dot.contains("in: "));
+
+    let format = |trace: &Trace| {
+        let mut labels: Vec<usize> = trace.0.iter().copied().collect();
+        labels.sort();
+        format!("{labels:?}")
+    };
+    let dot = pool.to_dot(Some(&format));
+    assert!(dot.contains(&format!(
+        "n{start} [label=\"{}: letval double = lam{fun}(x)\\nin: []\\nout: [{}]\"];",
+        ir.get_label(),
+        ir.get_label()
+    )));
+
+    let ir = IR::LetVal(
+        0,
+        "s".to_string(),
+        Atom::StringLiteral("a \"quote\"".to_string()),
+        Box::new(IR::AppCont(1, Cont::Return, vec![Atom::v("s")])),
+    );
+    let mut pool = trace_analysis();
+    pool.construct_intra(&ir);
+    assert!(
+        pool.to_dot(None)
+            .contains(r#"[label="0: letval s = \"a \\\"quote\\\"\""];"#)
+    );
+}
+
+// the length of the longest path from the program entry, infinite in a loop
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Depth(usize);
+
+impl Lattice for Depth {
+    fn join(a: &Self, b: &Self) -> Self {
+        Depth(a.0.max(b.0))
+    }
+    fn bottom() -> Self {
+        Depth(0)
+    }
+}
+
+#[test]
+pub fn test_worklist_stats() {
+    // without loops every node is visited once in reverse postorder
+    let ir = double_program();
+    let mut forward = trace_analysis();
+    forward.construct_intra(&ir);
+    let stats = forward.run_worklist();
+    assert_eq!(stats.visits, forward.node_count());
+    assert!(stats.updates <= stats.visits);
+
+    let mut backward = NodePool::new(
+        false,
+        Box::new(|label, _ir, mut trace: Trace| {
+            trace.0.insert(label);
+            trace
+        }),
+    );
+    backward.construct_intra(&ir);
+    let stats = backward.run_worklist();
+    assert_eq!(stats.visits, backward.node_count());
+    let at_entry = backward.get_result_in(backward.get_prog_entry());
+    assert!(at_entry.0.contains(&ir.get_label()));
+}
+
+#[test]
+pub fn test_worklist_iteration_limit() {
+    let fact = E::lam(
+        &["x"],
+        E::app(
+            E::v("fact"),
+            vec![E::papp(BuiltinOp::I32Sub, vec![E::v("x"), E::i32(1)])],
+        ),
+    );
+    let ir = quick_cps(E::fix(
+        &["fact"],
+        vec![fact],
+        E::app(E::v("fact"), vec![E::i32(5)]),
+    ));
+    let mut pool = NodePool::new(true, Box::new(|_, _, depth: Depth| Depth(depth.0 + 1)));
+    pool.construct_inter(&ir);
+    let error = pool.run_worklist_bounded(100).unwrap_err();
+    assert_eq!(error.limit, 100);
+    assert_eq!(error.stats.visits, 100);
+    assert_eq!(
+        error.to_string(),
+        "worklist did not converge within 100 visits"
+    );
+
+    let mut pool = trace_analysis();
+    pool.construct_inter(&ir);
+    let stats = pool.run_worklist_bounded(1000).unwrap();
+    assert!(stats.visits < 1000);
+}
@@ -1,14 +1,30 @@
 use crate::cps_ir::builtin_call::BuiltinOp;
 
-use super::{BuilderExpr as E, quick_cps};
-use super::{IR, Store, Value, interp};
+mod analysis;
+mod cfg;
+mod opt;
+
+use super::analysis::available_expression;
+use super::cfg::NodeInfo;
+use super::{Atom, Cont, IR, Store, Value, interp};
+use super::{BuilderExpr as E, CaseTag, GenTable, cps, quick_cps};
 use std::collections::HashMap;
 
 fn simple_interp<'a>(ir: &'a IR) -> Value<'a> {
     let env = HashMap::new();
     let mut store = Store::new();
-    let res = interp(&ir, env, &mut store);
-    res
+    interp(ir, env, &mut store)
+}
+
+// the IR returning the result of `prog`, translated by cps with a table of its own
+fn cps_return(prog: E) -> IR {
+    let ctx = GenTable::new();
+    let ctx1 = ctx.clone();
+    cps(
+        ctx,
+        prog,
+        Box::new(move |r| IR::AppCont(ctx1.borrow_mut().alloc_label(), Cont::Return, vec![r])),
+    )
 }
 #[test]
 pub fn test1() {
@@ -33,3 +49,824 @@ pub fn test1() {
     let ir = quick_cps(prog);
     assert_eq!(simple_interp(&ir), Value::I32(120))
 }
+
+#[test]
+pub fn test_recursive_cont() {
+    // letcont loop(i, s) = (c = 0 < i; if c then (j = i - 1; t = s + i; loop(j, t)) else return s)
+    // in loop(4, 0)
+    let ir = IR::LetCont(
+        0,
+        "loop".to_string(),
+        vec!["i".to_string(), "s".to_string()],
+        Box::new(IR::Let(
+            1,
+            "c".to_string(),
+            BuiltinOp::I32Lt,
+            vec![Atom::I32(0), Atom::v("i")],
+            Box::new(IR::If(
+                2,
+                Atom::v("c"),
+                Box::new(IR::Let(
+                    3,
+                    "j".to_string(),
+                    BuiltinOp::I32Sub,
+                    vec![Atom::v("i"), Atom::I32(1)],
+                    Box::new(IR::Let(
+                        4,
+                        "t".to_string(),
+                        BuiltinOp::I32Add,
+                        vec![Atom::v("s"), Atom::v("i")],
+                        Box::new(IR::AppCont(
+                            5,
+                            Cont::Named("loop".to_string()),
+                            vec![Atom::v("j"), Atom::v("t")],
+                        )),
+                    )),
+                )),
+                Box::new(IR::AppCont(6, Cont::Return, vec![Atom::v("s")])),
+            )),
+        )),
+        Box::new(IR::AppCont(
+            7,
+            Cont::Named("loop".to_string()),
+            vec![Atom::I32(4), Atom::I32(0)],
+        )),
+    );
+    // the body of the continuation sees its own binding
+    assert_eq!(simple_interp(&ir), Value::I32(10));
+}
+
+#[test]
+pub fn test_tuple() {
+    let swap = E::lam(
+        &["p"],
+        E::tuple(vec![E::proj(E::v("p"), 1), E::proj(E::v("p"), 0)]),
+    );
+    let prog = E::let_(
+        "swap",
+        swap,
+        E::app(
+            E::v("swap"),
+            vec![E::tuple(vec![E::i32(1), E::tuple(vec![E::bool(true)])])],
+        ),
+    );
+    let ir = quick_cps(prog);
+    assert_eq!(
+        simple_interp(&ir),
+        Value::Tuple(vec![Value::Tuple(vec![Value::Bool(true)]), Value::I32(1)])
+    )
+}
+
+fn list(xs: &[i32]) -> E {
+    xs.iter().rev().fold(E::construct(0, vec![]), |tail, x| {
+        E::construct(1, vec![E::i32(*x), tail])
+    })
+}
+
+// the number of edges of each Case node, checking they go to its branch continuations
+// and then to its default one, in order
+fn case_edges(ir: &IR) -> Vec<usize> {
+    let mut analysis = available_expression::make_analysis();
+    analysis.construct_intra(ir);
+    let mut edges = vec![];
+    for n in 0..analysis.node_count() {
+        let NodeInfo::Common(_, IR::Case(_, _, branches, default)) = analysis.get_info(n) else {
+            continue;
+        };
+        let targets: Vec<usize> = branches
+            .iter()
+            .map(|(_, k)| k)
+            .chain(default)
+            .map(|k| match k {
+                Cont::Named(name) => analysis.get_cont(name),
+                _ => panic!("a branch is a named continuation"),
+            })
+            .collect();
+        assert_eq!(analysis.get_successors(n), targets);
+        edges.push(targets.len());
+    }
+    edges
+}
+
+#[test]
+pub fn test_case_data() {
+    let sum = E::lam(
+        &["l"],
+        E::case(
+            E::v("l"),
+            vec![
+                (CaseTag::Tag(0), &[], E::i32(0)),
+                (
+                    CaseTag::Tag(1),
+                    &["h", "t"],
+                    E::papp(
+                        BuiltinOp::I32Add,
+                        vec![E::v("h"), E::app(E::v("sum"), vec![E::v("t")])],
+                    ),
+                ),
+            ],
+            None,
+        ),
+    );
+    let prog = E::fix(
+        &["sum"],
+        vec![sum],
+        E::app(E::v("sum"), vec![list(&[1, 2, 3, 4])]),
+    );
+    let ir = quick_cps(prog);
+    assert_eq!(simple_interp(&ir), Value::I32(10));
+
+    assert_eq!(case_edges(&ir), vec![2]);
+}
+
+#[test]
+pub fn test_case_literal() {
+    let classify = |n| {
+        E::case(
+            E::i32(n),
+            vec![
+                (CaseTag::I32(0), &[], E::str("zero")),
+                (CaseTag::I32(1), &[], E::str("one")),
+            ],
+            Some(E::str("many")),
+        )
+    };
+    let prog = E::tuple(vec![classify(0), classify(1), classify(7)]);
+    let ir = quick_cps(prog);
+    assert_eq!(
+        simple_interp(&ir),
+        Value::Tuple(vec![
+            Value::StringLiteral("zero".to_string()),
+            Value::StringLiteral("one".to_string()),
+            Value::StringLiteral("many".to_string()),
+        ])
+    );
+    assert_eq!(case_edges(&ir), vec![3, 3, 3]);
+}
+
+#[test]
+pub fn test_ref() {
+    let prog = E::let_(
+        "r",
+        E::ref_(E::i32(1)),
+        E::let_(
+            "_",
+            E::assign(
+                E::v("r"),
+                E::papp(BuiltinOp::I32Add, vec![E::deref(E::v("r")), E::i32(41)]),
+            ),
+            E::deref(E::v("r")),
+        ),
+    );
+    let ir = quick_cps(prog);
+    assert_eq!(simple_interp(&ir), Value::I32(42))
+}
+
+#[test]
+pub fn test_array() {
+    // fill a[i] = i * i, then sum the array
+    let fill = E::lam(
+        &["i"],
+        E::if_(
+            E::papp(
+                BuiltinOp::I32Lt,
+                vec![E::v("i"), E::array_length(E::v("a"))],
+            ),
+            E::let_(
+                "_",
+                E::array_set(
+                    E::v("a"),
+                    E::v("i"),
+                    E::papp(BuiltinOp::I32Mul, vec![E::v("i"), E::v("i")]),
+                ),
+                E::app(
+                    E::v("fill"),
+                    vec![E::papp(BuiltinOp::I32Add, vec![E::v("i"), E::i32(1)])],
+                ),
+            ),
+            E::i32(0),
+        ),
+    );
+    let sum = E::lam(
+        &["i"],
+        E::if_(
+            E::papp(BuiltinOp::I32Lt, vec![E::v("i"), E::i32(0)]),
+            E::i32(0),
+            E::papp(
+                BuiltinOp::I32Add,
+                vec![
+                    E::array_get(E::v("a"), E::v("i")),
+                    E::app(
+                        E::v("sum"),
+                        vec![E::papp(BuiltinOp::I32Sub, vec![E::v("i"), E::i32(1)])],
+                    ),
+                ],
+            ),
+        ),
+    );
+    let prog = E::let_(
+        "a",
+        E::array(E::i32(5), E::i32(0)),
+        E::fix(
+            &["fill", "sum"],
+            vec![fill, sum],
+            E::let_(
+                "_",
+                E::app(E::v("fill"), vec![E::i32(0)]),
+                E::app(E::v("sum"), vec![E::i32(4)]),
+            ),
+        ),
+    );
+    let ir = quick_cps(prog);
+    assert_eq!(simple_interp(&ir), Value::I32(30));
+}
+
+#[test]
+#[should_panic(expected = "out of bound")]
+pub fn test_array_bounds_check() {
+    let prog = E::array_get(E::array(E::i32(2), E::bool(true)), E::i32(2));
+    let ir = quick_cps(prog);
+    simple_interp(&ir);
+}
+
+#[test]
+#[should_panic(expected = "requires 2 arguments but receives 1")]
+pub fn test_cont_arity() {
+    // letcont k(x, y) = return x in k(1)
+    let ir = IR::LetCont(
+        0,
+        "k".to_string(),
+        vec!["x".to_string(), "y".to_string()],
+        Box::new(IR::AppCont(1, Cont::Return, vec![Atom::v("x")])),
+        Box::new(IR::AppCont(
+            2,
+            Cont::Named("k".to_string()),
+            vec![Atom::I32(1)],
+        )),
+    );
+    simple_interp(&ir);
+}
+
+#[test]
+pub fn test_callcc_escape() {
+    let prod = E::lam(
+        &["l", "exit"],
+        E::case(
+            E::v("l"),
+            vec![
+                (CaseTag::Tag(0), &[], E::i32(1)),
+                (
+                    CaseTag::Tag(1),
+                    &["h", "t"],
+                    E::if_(
+                        E::papp(BuiltinOp::I32Eq, vec![E::v("h"), E::i32(0)]),
+                        E::app(E::v("exit"), vec![E::str("zero")]),
+                        E::papp(
+                            BuiltinOp::I32Mul,
+                            vec![
+                                E::v("h"),
+                                E::app(E::v("prod"), vec![E::v("t"), E::v("exit")]),
+                            ],
+                        ),
+                    ),
+                ),
+            ],
+            None,
+        ),
+    );
+    let run = |xs: &[i32]| {
+        E::fix(
+            &["prod"],
+            vec![prod.clone()],
+            E::callcc(E::lam(
+                &["exit"],
+                E::app(E::v("prod"), vec![list(xs), E::v("exit")]),
+            )),
+        )
+    };
+    let ir = quick_cps(run(&[2, 3, 4]));
+    assert_eq!(simple_interp(&ir), Value::I32(24));
+    let ir = quick_cps(run(&[2, 0, 4]));
+    assert_eq!(simple_interp(&ir), Value::StringLiteral("zero".to_string()));
+}
+
+#[test]
+pub fn test_callcc_reenter() {
+    let prog = E::let_(
+        "count",
+        E::ref_(E::i32(0)),
+        E::let_(
+            "k",
+            E::callcc(E::lam(&["k"], E::v("k"))),
+            E::let_(
+                "_",
+                E::assign(
+                    E::v("count"),
+                    E::papp(BuiltinOp::I32Add, vec![E::deref(E::v("count")), E::i32(1)]),
+                ),
+                E::if_(
+                    E::papp(BuiltinOp::I32Lt, vec![E::deref(E::v("count")), E::i32(3)]),
+                    E::app(E::v("k"), vec![E::v("k")]),
+                    E::deref(E::v("count")),
+                ),
+            ),
+        ),
+    );
+    let ir = quick_cps(prog);
+    assert_eq!(simple_interp(&ir), Value::I32(3))
+}
+
+#[test]
+pub fn test_exception() {
+    let safe_div = E::lam(
+        &["x", "y"],
+        E::if_(
+            E::papp(BuiltinOp::I32Eq, vec![E::v("y"), E::i32(0)]),
+            E::raise(E::str("division by zero")),
+            E::papp(BuiltinOp::I32Div, vec![E::v("x"), E::v("y")]),
+        ),
+    );
+    let div = |x, y| E::app(E::v("div"), vec![E::i32(x), E::i32(y)]);
+    let prog = E::let_(
+        "div",
+        safe_div,
+        E::tuple(vec![
+            E::try_(div(10, 2), "e", E::v("e")),
+            E::try_(
+                E::papp(BuiltinOp::I32Add, vec![E::i32(1), div(10, 0)]),
+                "e",
+                E::v("e"),
+            ),
+            // the inner handler re-raises to the outer one
+            E::try_(
+                E::try_(div(1, 0), "e", E::raise(E::tuple(vec![E::v("e")]))),
+                "e",
+                E::v("e"),
+            ),
+        ]),
+    );
+    let ir = quick_cps(prog);
+    assert_eq!(
+        simple_interp(&ir),
+        Value::Tuple(vec![
+            Value::I32(5),
+            Value::StringLiteral("division by zero".to_string()),
+            Value::Tuple(vec![Value::StringLiteral("division by zero".to_string())]),
+        ])
+    );
+
+    // a call has an edge to its continuation then to its handler, a named handler
+    // continuation is its node and the Handler one goes to the exit, as a raise does
+    let mut analysis = available_expression::make_analysis();
+    analysis.construct_intra(&ir);
+    let is_exit = |n: usize| {
+        matches!(
+            analysis.get_info(n),
+            NodeInfo::FunExit(_) | NodeInfo::ProgramExit
+        )
+    };
+    let check = |cont: &Cont, target: usize| match cont {
+        Cont::Named(name) => assert_eq!(target, analysis.get_cont(name)),
+        Cont::Return | Cont::Handler => assert!(is_exit(target)),
+    };
+    let (mut named, mut raised) = (0, 0);
+    for n in 0..analysis.node_count() {
+        match analysis.get_info(n) {
+            NodeInfo::Common(_, IR::App(_, _, _, k, h)) => {
+                let succs = analysis.get_successors(n);
+                assert_eq!(succs.len(), 2);
+                check(k, succs[0]);
+                check(h, succs[1]);
+                named += matches!(h, Cont::Named(_)) as usize;
+            }
+            NodeInfo::Common(_, IR::AppCont(_, Cont::Handler, _)) => {
+                let succs = analysis.get_successors(n);
+                assert!(succs.len() == 1 && is_exit(succs[0]));
+                raised += 1;
+            }
+            _ => (),
+        }
+    }
+    assert_eq!((named, raised), (3, 1));
+}
+
+#[test]
+#[should_panic(expected = "uncaught exception")]
+pub fn test_uncaught_exception() {
+    let prog = E::let_(
+        "f",
+        E::lam(&["x"], E::raise(E::v("x"))),
+        E::app(E::v("f"), vec![E::i32(1)]),
+    );
+    let ir = quick_cps(prog);
+    simple_interp(&ir);
+}
+
+const YIELD: usize = 0;
+const GET: usize = 1;
+const PUT: usize = 2;
+const FLIP: usize = 3;
+
+#[test]
+pub fn test_effect_generator() {
+    // yields the squares of a list, the handler collects them into a list
+    let iter = E::lam(
+        &["l"],
+        E::case(
+            E::v("l"),
+            vec![
+                (CaseTag::Tag(0), &[], E::tuple(vec![])),
+                (
+                    CaseTag::Tag(1),
+                    &["h", "t"],
+                    E::let_(
+                        "_",
+                        E::perform(
+                            YIELD,
+                            vec![E::papp(BuiltinOp::I32Mul, vec![E::v("h"), E::v("h")])],
+                        ),
+                        E::app(E::v("iter"), vec![E::v("t")]),
+                    ),
+                ),
+            ],
+            None,
+        ),
+    );
+    let prog = E::fix(
+        &["iter"],
+        vec![iter],
+        E::handle(
+            E::app(E::v("iter"), vec![list(&[1, 2, 3])]),
+            ("_", E::construct(0, vec![])),
+            vec![(
+                YIELD,
+                &["x"],
+                "k",
+                E::construct(
+                    1,
+                    vec![E::v("x"), E::app(E::v("k"), vec![E::tuple(vec![])])],
+                ),
+            )],
+        ),
+    );
+    let ir = quick_cps(prog);
+    let expected = quick_cps(list(&[1, 4, 9]));
+    assert_eq!(simple_interp(&ir), simple_interp(&expected));
+}
+
+#[test]
+pub fn test_effect_state() {
+    let get = || E::perform(GET, vec![]);
+    let put = |v| E::perform(PUT, vec![v]);
+    let body = E::let_(
+        "_",
+        put(E::papp(BuiltinOp::I32Add, vec![get(), E::i32(1)])),
+        E::let_(
+            "_",
+            put(E::papp(BuiltinOp::I32Mul, vec![get(), E::i32(10)])),
+            // YIELD is not handled by the state handler and is forwarded outwards
+            E::perform(YIELD, vec![get()]),
+        ),
+    );
+    let state = E::handle(
+        body,
+        ("x", E::lam(&["s"], E::tuple(vec![E::v("x"), E::v("s")]))),
+        vec![
+            (
+                GET,
+                &[],
+                "k",
+                E::lam(
+                    &["s"],
+                    E::app(E::app(E::v("k"), vec![E::v("s")]), vec![E::v("s")]),
+                ),
+            ),
+            (
+                PUT,
+                &["s1"],
+                "k",
+                E::lam(
+                    &["s"],
+                    E::app(E::app(E::v("k"), vec![E::tuple(vec![])]), vec![E::v("s1")]),
+                ),
+            ),
+        ],
+    );
+    let prog = E::handle(
+        E::app(state, vec![E::i32(1)]),
+        ("x", E::v("x")),
+        vec![(
+            YIELD,
+            &["v"],
+            "k",
+            E::app(
+                E::v("k"),
+                vec![E::papp(BuiltinOp::I32Add, vec![E::v("v"), E::i32(2)])],
+            ),
+        )],
+    );
+    let ir = quick_cps(prog);
+    assert_eq!(
+        simple_interp(&ir),
+        Value::Tuple(vec![Value::I32(22), Value::I32(20)])
+    );
+}
+
+#[test]
+pub fn test_effect_nondeterminism() {
+    // every flip is resumed twice, the handler builds the tree of all outcomes
+    let flip = || E::perform(FLIP, vec![]);
+    let body = E::let_(
+        "a",
+        flip(),
+        E::let_(
+            "b",
+            flip(),
+            E::if_(
+                E::v("a"),
+                E::if_(E::v("b"), E::i32(1), E::i32(2)),
+                E::i32(3),
+            ),
+        ),
+    );
+    let prog = E::handle(
+        body,
+        ("x", E::v("x")),
+        vec![(
+            FLIP,
+            &[],
+            "k",
+            E::tuple(vec![
+                E::app(E::v("k"), vec![E::bool(true)]),
+                E::app(E::v("k"), vec![E::bool(false)]),
+            ]),
+        )],
+    );
+    let ir = quick_cps(prog);
+    let pair = |a, b| Value::Tuple(vec![Value::I32(a), Value::I32(b)]);
+    assert_eq!(
+        simple_interp(&ir),
+        Value::Tuple(vec![pair(1, 2), pair(3, 3)])
+    );
+}
+
+#[test]
+#[should_panic(expected = "unhandled effect")]
+pub fn test_effect_unhandled() {
+    let ir = quick_cps(E::perform(GET, vec![]));
+    simple_interp(&ir);
+}
+
+#[test]
+pub fn test_effect_cps() {
+    // the meta continuation cell has a generated name, a user variable does not capture it
+    let body = E::let_(
+        "meta_cont",
+        E::i32(5),
+        E::papp(
+            BuiltinOp::I32Add,
+            vec![E::perform(GET, vec![]), E::v("meta_cont")],
+        ),
+    );
+    let prog = E::handle(
+        body,
+        ("x", E::v("x")),
+        vec![(GET, &[], "k", E::app(E::v("k"), vec![E::i32(1)]))],
+    );
+    let ir = cps_return(prog);
+    // the program starts by allocating the cell
+    let mut cell = &ir;
+    let name = loop {
+        match cell {
+            IR::Let(_, var, BuiltinOp::Ref, ..) => break var,
+            IR::Let(.., body) | IR::LetVal(.., body) => cell = body,
+            _ => panic!("expect the meta continuation cell to be allocated first"),
+        }
+    };
+    assert!(name.starts_with("g_var_"));
+    assert_eq!(simple_interp(&ir), Value::I32(6));
+}
+
+#[test]
+pub fn test_shift_reset() {
+    // 1 + reset (2 * shift k. k (k 10))
+    let prog = E::papp(
+        BuiltinOp::I32Add,
+        vec![
+            E::i32(1),
+            E::reset(E::papp(
+                BuiltinOp::I32Mul,
+                vec![
+                    E::i32(2),
+                    E::shift(
+                        "k",
+                        E::app(E::v("k"), vec![E::app(E::v("k"), vec![E::i32(10)])]),
+                    ),
+                ],
+            )),
+        ],
+    );
+    let ir = quick_cps(prog);
+    assert_eq!(simple_interp(&ir), Value::I32(41))
+}
+
+#[test]
+pub fn test_shift_reset_cps() {
+    // reset (let x = 3 in x + shift k. k (k 10)), translated by cps directly
+    let body = E::let_(
+        "x",
+        E::i32(3),
+        E::papp(
+            BuiltinOp::I32Add,
+            vec![
+                E::v("x"),
+                E::shift(
+                    "k",
+                    E::app(E::v("k"), vec![E::app(E::v("k"), vec![E::i32(10)])]),
+                ),
+            ],
+        ),
+    );
+    let ir = cps_return(E::reset(body));
+    assert_eq!(simple_interp(&ir), Value::I32(16));
+}
+
+#[test]
+pub fn test_shift_fold_early_exit() {
+    // the product of a list, a zero aborts the fold by discarding the continuation
+    let fold = E::lam(
+        &["f", "acc", "l"],
+        E::case(
+            E::v("l"),
+            vec![
+                (CaseTag::Tag(0), &[], E::v("acc")),
+                (
+                    CaseTag::Tag(1),
+                    &["h", "t"],
+                    E::app(
+                        E::v("fold"),
+                        vec![
+                            E::v("f"),
+                            E::app(E::v("f"), vec![E::v("acc"), E::v("h")]),
+                            E::v("t"),
+                        ],
+                    ),
+                ),
+            ],
+            None,
+        ),
+    );
+    let mul = E::lam(
+        &["acc", "x"],
+        E::if_(
+            E::papp(BuiltinOp::I32Eq, vec![E::v("x"), E::i32(0)]),
+            E::shift("k", E::str("zero")),
+            E::papp(BuiltinOp::I32Mul, vec![E::v("acc"), E::v("x")]),
+        ),
+    );
+    let run = |xs: &[i32]| {
+        E::fix(
+            &["fold"],
+            vec![fold.clone()],
+            E::reset(E::app(E::v("fold"), vec![mul.clone(), E::i32(1), list(xs)])),
+        )
+    };
+    let ir = quick_cps(run(&[1, 2, 3, 4]));
+    assert_eq!(simple_interp(&ir), Value::I32(24));
+    let ir = quick_cps(run(&[1, 0, 3, 4]));
+    assert_eq!(simple_interp(&ir), Value::StringLiteral("zero".to_string()));
+}
+
+#[test]
+pub fn test_shift_coroutines() {
+    // two generators suspended by shift are resumed alternately and zipped
+    let walk = E::lam(
+        &["l"],
+        E::case(
+            E::v("l"),
+            vec![
+                (CaseTag::Tag(0), &[], E::construct(0, vec![])),
+                (
+                    CaseTag::Tag(1),
+                    &["h", "t"],
+                    E::let_(
+                        "_",
+                        E::shift("k", E::construct(1, vec![E::v("h"), E::v("k")])),
+                        E::app(E::v("walk"), vec![E::v("t")]),
+                    ),
+                ),
+            ],
+            None,
+        ),
+    );
+    let unit = || E::tuple(vec![]);
+    let zip = E::lam(
+        &["a", "b"],
+        E::case(
+            E::v("a"),
+            vec![
+                (CaseTag::Tag(0), &[], E::construct(0, vec![])),
+                (
+                    CaseTag::Tag(1),
+                    &["x", "ka"],
+                    E::case(
+                        E::v("b"),
+                        vec![
+                            (CaseTag::Tag(0), &[], E::construct(0, vec![])),
+                            (
+                                CaseTag::Tag(1),
+                                &["y", "kb"],
+                                E::construct(
+                                    1,
+                                    vec![
+                                        E::tuple(vec![E::v("x"), E::v("y")]),
+                                        E::app(
+                                            E::v("zip"),
+                                            vec![
+                                                E::app(E::v("ka"), vec![unit()]),
+                                                E::app(E::v("kb"), vec![unit()]),
+                                            ],
+                                        ),
+                                    ],
+                                ),
+                            ),
+                        ],
+                        None,
+                    ),
+                ),
+            ],
+            None,
+        ),
+    );
+    let start = |xs: &[i32]| E::reset(E::app(E::v("walk"), vec![list(xs)]));
+    let prog = E::fix(
+        &["walk", "zip"],
+        vec![walk, zip],
+        E::app(E::v("zip"), vec![start(&[1, 2, 3]), start(&[4, 5])]),
+    );
+    let ir = quick_cps(prog);
+    let pair = |a, b| E::tuple(vec![E::i32(a), E::i32(b)]);
+    let expected = quick_cps(E::construct(
+        1,
+        vec![
+            pair(1, 4),
+            E::construct(1, vec![pair(2, 5), E::construct(0, vec![])]),
+        ],
+    ));
+    assert_eq!(simple_interp(&ir), simple_interp(&expected));
+}
+
+#[test]
+pub fn test_cps_tail_calls() {
+    let parity = |other: &str, zero: bool| {
+        E::lam(
+            &["n"],
+            E::if_(
+                E::papp(BuiltinOp::I32Eq, vec![E::v("n"), E::i32(0)]),
+                E::bool(zero),
+                E::app(
+                    E::v(other),
+                    vec![E::papp(BuiltinOp::I32Sub, vec![E::v("n"), E::i32(1)])],
+                ),
+            ),
+        )
+    };
+    let prog = E::fix(
+        &["even", "odd"],
+        vec![parity("odd", true), parity("even", false)],
+        E::let_(
+            "r",
+            E::app(E::v("even"), vec![E::i32(10)]),
+            E::if_(E::v("r"), E::i32(1), E::i32(0)),
+        ),
+    );
+    let ir = quick_cps(prog);
+    assert_eq!(simple_interp(&ir), Value::I32(1));
+
+    let IR::Fix(_, _, vals, body) = &ir else {
+        panic!("expected a Fix, got {ir:?}");
+    };
+    // the branches of a function body return directly and its tail call passes the return
+    // continuation on, without a join point
+    let Atom::Lam(_, _, lam_body) = &vals[0] else {
+        panic!("expected a lambda");
+    };
+    let IR::Let(_, _, _, _, test) = &**lam_body else {
+        panic!("expected the test, got {lam_body:?}");
+    };
+    let IR::If(_, _, then_, else_) = &**test else {
+        panic!("expected an If, got {test:?}");
+    };
+    assert!(matches!(**then_, IR::AppCont(_, Cont::Return, _)));
+    assert!(matches!(
+        &**else_,
+        IR::Let(_, _, _, _, call)
+            if matches!(**call, IR::App(_, _, _, Cont::Return, Cont::Handler))
+    ));
+    // the continuation of a let-bound call binds the variable itself
+    let IR::LetCont(_, _, params, _, call) = &**body else {
+        panic!("expected a LetCont, got {body:?}");
+    };
+    assert_eq!(params, &["r".to_string()]);
+    assert!(matches!(**call, IR::App(..)));
+}
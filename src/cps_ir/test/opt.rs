@@ -0,0 +1,682 @@
+use crate::cps_ir::builtin_call::BuiltinOp;
+use crate::cps_ir::opt::contify::contify;
+use crate::cps_ir::opt::eta::eta;
+use crate::cps_ir::opt::inline::{InlineConfig, inline};
+use crate::cps_ir::opt::licm::licm;
+use crate::cps_ir::opt::shrink::shrink;
+use crate::cps_ir::opt::uncurry::uncurry;
+
+use super::super::{Atom, BuilderExpr as E, Cont, IR, Value, quick_cps};
+use super::simple_interp;
+use std::{cell::RefCell, collections::HashSet};
+
+// the number of IR nodes satisfying `pred`, lambda bodies included
+fn count(ir: &IR, pred: &dyn Fn(&IR) -> bool) -> usize {
+    let in_atoms = |atoms: &[Atom]| -> usize {
+        atoms
+            .iter()
+            .map(|atom| match atom {
+                Atom::Lam(_, _, body) => count(body, pred),
+                _ => 0,
+            })
+            .sum()
+    };
+    let nested = match ir {
+        IR::LetCont(_, _, _, cont_body, body) => count(cont_body, pred) + count(body, pred),
+        IR::Let(_, _, _, args, body) => in_atoms(args) + count(body, pred),
+        IR::LetVal(_, _, val, body) => in_atoms(std::slice::from_ref(val)) + count(body, pred),
+        IR::Fix(_, _, vals, body) => in_atoms(vals) + count(body, pred),
+        IR::If(_, _, then_, else_) => count(then_, pred) + count(else_, pred),
+        IR::App(_, f, args, _, _) => in_atoms(std::slice::from_ref(f)) + in_atoms(args),
+        IR::AppCont(_, _, args) => in_atoms(args),
+        IR::Case(..) => 0,
+    };
+    nested + pred(ir) as usize
+}
+
+fn fixes(ir: &IR) -> usize {
+    count(ir, &|ir| matches!(ir, IR::Fix(..)))
+}
+
+fn add(a: E, b: E) -> E {
+    E::papp(BuiltinOp::I32Add, vec![a, b])
+}
+
+// name(i, acc) = if i >= n then acc else name(i + 1, acc + i)
+fn sum_loop(name: &str, n: E) -> E {
+    let (i, acc) = (format!("{name}_i"), format!("{name}_acc"));
+    E::lam(
+        &[&i, &acc],
+        E::if_(
+            E::papp(BuiltinOp::I32Geq, vec![E::v(&i), n]),
+            E::v(&acc),
+            E::app(
+                E::v(name),
+                vec![add(E::v(&i), E::i32(1)), add(E::v(&acc), E::v(&i))],
+            ),
+        ),
+    )
+}
+
+#[test]
+pub fn test_contify_loops() {
+    // returning to the caller of the program
+    let prog = E::fix(
+        &["loop"],
+        vec![sum_loop("loop", E::i32(10))],
+        E::app(E::v("loop"), vec![E::i32(0), E::i32(0)]),
+    );
+    // returning to the continuation of the call
+    let prog2 = E::fix(
+        &["loop"],
+        vec![sum_loop("loop", E::i32(10))],
+        add(E::app(E::v("loop"), vec![E::i32(0), E::i32(0)]), E::i32(1)),
+    );
+    // the inner loop is only called from the body of the outer one
+    let outer = E::lam(
+        &["i", "acc"],
+        E::if_(
+            E::papp(BuiltinOp::I32Geq, vec![E::v("i"), E::i32(4)]),
+            E::v("acc"),
+            E::fix(
+                &["inner"],
+                vec![sum_loop("inner", E::v("i"))],
+                E::app(
+                    E::v("outer"),
+                    vec![
+                        add(E::v("i"), E::i32(1)),
+                        E::app(E::v("inner"), vec![E::i32(0), E::v("acc")]),
+                    ],
+                ),
+            ),
+        ),
+    );
+    let prog3 = E::fix(
+        &["outer"],
+        vec![outer],
+        E::app(E::v("outer"), vec![E::i32(0), E::i32(0)]),
+    );
+    for (prog, expected) in [(prog, 45), (prog2, 46), (prog3, 4)] {
+        let ir = quick_cps(prog);
+        assert_eq!(simple_interp(&ir), Value::I32(expected));
+        let contified = contify(ir.clone());
+        assert_eq!(fixes(&contified), 0);
+        assert_eq!(simple_interp(&contified), Value::I32(expected));
+    }
+}
+
+#[test]
+pub fn test_contify_rejected() {
+    let id = || E::lam(&["x"], E::v("x"));
+    let fact = E::lam(
+        &["x"],
+        E::if_(
+            E::papp(BuiltinOp::I32Leq, vec![E::v("x"), E::i32(1)]),
+            E::i32(1),
+            E::papp(
+                BuiltinOp::I32Mul,
+                vec![
+                    E::app(
+                        E::v("fact"),
+                        vec![E::papp(BuiltinOp::I32Sub, vec![E::v("x"), E::i32(1)])],
+                    ),
+                    E::v("x"),
+                ],
+            ),
+        ),
+    );
+    let progs = [
+        // not a tail call
+        E::fix(&["fact"], vec![fact], E::app(E::v("fact"), vec![E::i32(5)])),
+        // returns to two continuations
+        E::fix(
+            &["f"],
+            vec![id()],
+            add(
+                E::app(E::v("f"), vec![E::i32(1)]),
+                E::app(E::v("f"), vec![E::i32(2)]),
+            ),
+        ),
+        // escapes
+        E::fix(
+            &["f"],
+            vec![id()],
+            E::let_("g", E::v("f"), E::app(E::v("g"), vec![E::i32(1)])),
+        ),
+        // called from another function
+        E::fix(
+            &["f"],
+            vec![id()],
+            E::app(
+                E::lam(&["y"], E::app(E::v("f"), vec![E::v("y")])),
+                vec![E::i32(1)],
+            ),
+        ),
+    ];
+    for prog in progs {
+        let ir = quick_cps(prog);
+        let contified = contify(ir.clone());
+        assert_eq!(contified, ir);
+    }
+}
+
+#[test]
+pub fn test_shrink_inline() {
+    // let x = 1 in let y = x in let f = λz. z + y in letcont k(r) = return r in f(2) -> k
+    let ir = IR::LetVal(
+        0,
+        "x".to_string(),
+        Atom::I32(1),
+        Box::new(IR::LetVal(
+            1,
+            "y".to_string(),
+            Atom::v("x"),
+            Box::new(IR::LetVal(
+                2,
+                "f".to_string(),
+                Atom::lam(
+                    10,
+                    &["z"],
+                    IR::Let(
+                        11,
+                        "s".to_string(),
+                        BuiltinOp::I32Add,
+                        vec![Atom::v("z"), Atom::v("y")],
+                        Box::new(IR::AppCont(12, Cont::Return, vec![Atom::v("s")])),
+                    ),
+                ),
+                Box::new(IR::LetCont(
+                    3,
+                    "k".to_string(),
+                    vec!["r".to_string()],
+                    Box::new(IR::AppCont(4, Cont::Return, vec![Atom::v("r")])),
+                    Box::new(IR::App(
+                        5,
+                        Atom::v("f"),
+                        vec![Atom::I32(2)],
+                        Cont::Named("k".to_string()),
+                        Cont::Handler,
+                    )),
+                )),
+            )),
+        )),
+    );
+    // inlining `f` makes the continuation of the call used once, by a jump
+    let once = shrink(ir.clone());
+    assert_eq!(count(&once, &|ir| matches!(ir, IR::App(..))), 0);
+    assert_eq!(count(&once, &|ir| matches!(ir, IR::LetVal(..))), 0);
+    assert_eq!(count(&once, &|ir| matches!(ir, IR::LetCont(..))), 1);
+    let twice = shrink(once);
+    assert_eq!(count(&twice, &|ir| matches!(ir, IR::LetCont(..))), 0);
+    assert_eq!(simple_interp(&ir), Value::I32(3));
+    assert_eq!(simple_interp(&twice), Value::I32(3));
+
+    // the handler of the call receives the exceptions raised by the inlined body
+    let prog = E::let_(
+        "f",
+        E::lam(&["x"], E::raise(E::v("x"))),
+        E::try_(
+            E::app(E::v("f"), vec![E::i32(1)]),
+            "e",
+            add(E::v("e"), E::i32(10)),
+        ),
+    );
+    let ir = quick_cps(prog);
+    let shrunk = shrink(ir.clone());
+    assert_eq!(count(&shrunk, &|ir| matches!(ir, IR::App(..))), 0);
+    assert_eq!(simple_interp(&ir), Value::I32(11));
+    assert_eq!(simple_interp(&shrunk), Value::I32(11));
+
+    // a recursive function called from several places stays
+    let prog = E::fix(
+        &["loop"],
+        vec![sum_loop("loop", E::i32(10))],
+        E::app(E::v("loop"), vec![E::i32(0), E::i32(0)]),
+    );
+    let ir = quick_cps(prog);
+    let shrunk = shrink(ir.clone());
+    assert_eq!(fixes(&shrunk), 1);
+    assert_eq!(simple_interp(&shrunk), Value::I32(45));
+}
+
+#[test]
+pub fn test_shrink_dead_code() {
+    let var = |v: &str| v.to_string();
+    let ret = |atom| Box::new(IR::AppCont(10, Cont::Return, vec![atom]));
+    // let y = 1 + 2 in let z = y * y in let r = ref 0 in
+    // letcont k(a) = return a in if true then return 1 else k(2)
+    let ir = IR::Let(
+        0,
+        var("y"),
+        BuiltinOp::I32Add,
+        vec![Atom::I32(1), Atom::I32(2)],
+        Box::new(IR::Let(
+            1,
+            var("z"),
+            BuiltinOp::I32Mul,
+            vec![Atom::v("y"), Atom::v("y")],
+            Box::new(IR::Let(
+                2,
+                var("r"),
+                BuiltinOp::Ref,
+                vec![Atom::I32(0)],
+                Box::new(IR::LetCont(
+                    3,
+                    var("k"),
+                    vec![var("a")],
+                    ret(Atom::v("a")),
+                    Box::new(IR::If(
+                        4,
+                        Atom::Bool(true),
+                        ret(Atom::I32(1)),
+                        Box::new(IR::AppCont(5, Cont::Named(var("k")), vec![Atom::I32(2)])),
+                    )),
+                )),
+            )),
+        )),
+    );
+    // the reference is kept for its effect
+    let expected = IR::Let(
+        2,
+        var("r"),
+        BuiltinOp::Ref,
+        vec![Atom::I32(0)],
+        ret(Atom::I32(1)),
+    );
+    assert_eq!(shrink(ir), expected);
+}
+
+fn apps(ir: &IR) -> usize {
+    count(ir, &|ir| matches!(ir, IR::App(..)))
+}
+
+#[test]
+pub fn test_inline() {
+    // let sq = λx. x * x in sq(3) + sq(4)
+    let sq = |a| E::app(E::v("sq"), vec![E::i32(a)]);
+    let prog = E::let_(
+        "sq",
+        E::lam(
+            &["x"],
+            E::papp(BuiltinOp::I32Mul, vec![E::v("x"), E::v("x")]),
+        ),
+        add(sq(3), sq(4)),
+    );
+    let ir = quick_cps(prog);
+    assert_eq!(apps(&ir), 2);
+    let inlined = inline(ir.clone(), &InlineConfig::default());
+    assert_eq!(apps(&inlined), 0);
+    assert_eq!(simple_interp(&inlined), Value::I32(25));
+    // the copies are renamed apart, so no label is repeated
+    let labels = RefCell::new(HashSet::new());
+    let nodes = count(&inlined, &|ir| labels.borrow_mut().insert(ir.get_label()));
+    assert_eq!(nodes, count(&inlined, &|_| true));
+    // the definition is left for the shrinking pass
+    let shrunk = shrink(inlined);
+    assert_eq!(count(&shrunk, &|ir| matches!(ir, IR::LetVal(..))), 0);
+    assert_eq!(simple_interp(&shrunk), Value::I32(25));
+
+    // too large
+    let small = InlineConfig {
+        max_size: 1,
+        ..InlineConfig::default()
+    };
+    assert_eq!(inline(ir.clone(), &small), ir);
+    // no room to grow
+    let bounded = InlineConfig {
+        max_growth: 3,
+        ..InlineConfig::default()
+    };
+    assert_eq!(apps(&inline(ir, &bounded)), 1);
+
+    // a recursive function is unrolled once at its call
+    let prog = E::fix(
+        &["loop"],
+        vec![sum_loop("loop", E::i32(10))],
+        E::app(E::v("loop"), vec![E::i32(0), E::i32(0)]),
+    );
+    let ir = quick_cps(prog);
+    let inlined = inline(ir.clone(), &InlineConfig::default());
+    assert_eq!(apps(&inlined), 2);
+    assert_eq!(fixes(&inlined), 1);
+    assert_eq!(simple_interp(&inlined), Value::I32(45));
+}
+
+#[test]
+pub fn test_eta() {
+    // let f = λy. y + 1 in let g = λx. f(x) in g(1)
+    let prog = E::let_(
+        "f",
+        E::lam(&["y"], add(E::v("y"), E::i32(1))),
+        E::let_(
+            "g",
+            E::lam(&["x"], E::app(E::v("f"), vec![E::v("x")])),
+            E::app(E::v("g"), vec![E::i32(1)]),
+        ),
+    );
+    let reduced = eta(quick_cps(prog));
+    let g_is_f = |ir: &IR| matches!(ir, IR::LetVal(_, g, Atom::Var(f), _) if g == "g" && f == "f");
+    assert_eq!(count(&reduced, &g_is_f), 1);
+    assert_eq!(simple_interp(&reduced), Value::I32(2));
+
+    // letcont k(r) = return r in letcont h(e) = raise e in f(1) -> k, h
+    let ir = IR::LetCont(
+        0,
+        "k".to_string(),
+        vec!["r".to_string()],
+        Box::new(IR::AppCont(1, Cont::Return, vec![Atom::v("r")])),
+        Box::new(IR::LetCont(
+            2,
+            "h".to_string(),
+            vec!["e".to_string()],
+            Box::new(IR::AppCont(3, Cont::Handler, vec![Atom::v("e")])),
+            Box::new(IR::App(
+                4,
+                Atom::v("f"),
+                vec![Atom::I32(1)],
+                Cont::Named("k".to_string()),
+                Cont::Named("h".to_string()),
+            )),
+        )),
+    );
+    // both continuations forward to the ones of the function
+    let expected = IR::App(
+        4,
+        Atom::v("f"),
+        vec![Atom::I32(1)],
+        Cont::Return,
+        Cont::Handler,
+    );
+    assert_eq!(eta(ir), expected);
+
+    // fix f = λx. x + 1, g = λy. f(y) in g(2)
+    let prog = E::fix(
+        &["f", "g"],
+        vec![
+            E::lam(&["x"], add(E::v("x"), E::i32(1))),
+            E::lam(&["y"], E::app(E::v("f"), vec![E::v("y")])),
+        ],
+        E::app(E::v("g"), vec![E::i32(2)]),
+    );
+    let reduced = eta(quick_cps(prog));
+    let calls_f = |ir: &IR| matches!(ir, IR::App(_, Atom::Var(f), ..) if f == "f");
+    assert_eq!(count(&reduced, &calls_f), 1);
+    let bundle = |ir: &IR| matches!(ir, IR::Fix(_, vars, ..) if vars.len() == 1);
+    assert_eq!(count(&reduced, &bundle), 1);
+    assert_eq!(simple_interp(&reduced), Value::I32(3));
+}
+
+#[test]
+pub fn test_eta_captured_continuation() {
+    let var = |v: &str| v.to_string();
+    let named = |k: &str| Cont::Named(k.to_string());
+    // letcont k(x) = return x in letval c = k in k(1)
+    let ir = IR::LetCont(
+        0,
+        var("k"),
+        vec![var("x")],
+        Box::new(IR::AppCont(1, Cont::Return, vec![Atom::v("x")])),
+        Box::new(IR::LetVal(
+            2,
+            var("c"),
+            Atom::Cont(var("k")),
+            Box::new(IR::AppCont(3, named("k"), vec![Atom::I32(1)])),
+        )),
+    );
+    // a value has to name a continuation
+    assert_eq!(eta(ir.clone()), ir);
+
+    // letcont j(x) = return x + 1 in letcont k(y) = j(y) in letval c = k in k(1)
+    let ir = IR::LetCont(
+        0,
+        var("j"),
+        vec![var("x")],
+        Box::new(IR::Let(
+            6,
+            var("z"),
+            BuiltinOp::I32Add,
+            vec![Atom::v("x"), Atom::I32(1)],
+            Box::new(IR::AppCont(1, Cont::Return, vec![Atom::v("z")])),
+        )),
+        Box::new(IR::LetCont(
+            2,
+            var("k"),
+            vec![var("y")],
+            Box::new(IR::AppCont(3, named("j"), vec![Atom::v("y")])),
+            Box::new(IR::LetVal(
+                4,
+                var("c"),
+                Atom::Cont(var("k")),
+                Box::new(IR::AppCont(5, named("k"), vec![Atom::I32(1)])),
+            )),
+        )),
+    );
+    let expected = IR::LetCont(
+        0,
+        var("j"),
+        vec![var("x")],
+        Box::new(IR::Let(
+            6,
+            var("z"),
+            BuiltinOp::I32Add,
+            vec![Atom::v("x"), Atom::I32(1)],
+            Box::new(IR::AppCont(1, Cont::Return, vec![Atom::v("z")])),
+        )),
+        Box::new(IR::LetVal(
+            4,
+            var("c"),
+            Atom::Cont(var("j")),
+            Box::new(IR::AppCont(5, named("j"), vec![Atom::I32(1)])),
+        )),
+    );
+    assert_eq!(eta(ir), expected);
+}
+
+#[test]
+pub fn test_licm() {
+    let geq = |a, b| E::papp(BuiltinOp::I32Geq, vec![a, b]);
+    let step = |acc: E| E::app(E::v("loop"), vec![add(E::v("i"), E::i32(1)), acc]);
+    let run = |loop_: E, body: E| E::let_("n", E::i32(5), E::fix(&["loop"], vec![loop_], body));
+    let start = || E::app(E::v("loop"), vec![E::i32(0), E::i32(0)]);
+
+    // loop(i, acc) = let lim = n * 2 in if i >= lim then acc else loop(i + 1, acc + i)
+    let loop_ = E::lam(
+        &["i", "acc"],
+        E::let_(
+            "lim",
+            E::papp(BuiltinOp::I32Mul, vec![E::v("n"), E::i32(2)]),
+            E::if_(
+                geq(E::v("i"), E::v("lim")),
+                E::v("acc"),
+                step(add(E::v("acc"), E::v("i"))),
+            ),
+        ),
+    );
+    let ir = quick_cps(run(loop_.clone(), start()));
+    let hoisted = licm(ir.clone());
+    assert!(matches!(
+        &hoisted,
+        IR::LetVal(_, _, _, body)
+            if matches!(&**body, IR::Let(_, lim, BuiltinOp::I32Mul, _, fix)
+                if lim == "lim" && matches!(**fix, IR::Fix(..)))
+    ));
+    assert_eq!(simple_interp(&hoisted), Value::I32(45));
+
+    // the multiplication may overflow, it stays when the loop may not be called
+    let ir = quick_cps(run(loop_, E::if_(E::bool(true), start(), E::i32(0))));
+    assert_eq!(licm(ir.clone()), ir);
+
+    // loop(i, acc) = if i >= 10 then acc else loop(i + 1, acc + (n, n).0),
+    // the tuple is built on some iterations only, the projection is not hoisted with it
+    let loop_ = E::lam(
+        &["i", "acc"],
+        E::if_(
+            geq(E::v("i"), E::i32(10)),
+            E::v("acc"),
+            step(add(
+                E::v("acc"),
+                E::proj(E::tuple(vec![E::v("n"), E::v("n")]), 0),
+            )),
+        ),
+    );
+    let ir = quick_cps(run(loop_, start()));
+    let hoisted = licm(ir.clone());
+    let applies = |op: BuiltinOp| move |ir: &IR| matches!(ir, IR::Let(_, _, o, ..) if *o == op);
+    assert!(matches!(
+        &hoisted,
+        IR::LetVal(_, _, _, body)
+            if matches!(&**body, IR::Let(_, _, BuiltinOp::Tuple, _, fix)
+                if matches!(**fix, IR::Fix(..)))
+    ));
+    assert_eq!(count(&hoisted, &applies(BuiltinOp::Tuple)), 1);
+    assert_eq!(count(&hoisted, &applies(BuiltinOp::Proj(0))), 1);
+    assert_eq!(simple_interp(&hoisted), Value::I32(50));
+}
+
+// the parameters of the lambda bound to `var` by a Fix
+fn fix_params(ir: &IR, var: &str) -> Option<Vec<String>> {
+    let in_atoms = |atoms: &[Atom]| {
+        atoms.iter().find_map(|atom| match atom {
+            Atom::Lam(_, _, body) => fix_params(body, var),
+            _ => None,
+        })
+    };
+    match ir {
+        IR::Fix(_, vars, vals, body) => vars
+            .iter()
+            .zip(vals)
+            .find_map(|(v, val)| match val {
+                Atom::Lam(_, params, _) if v == var => Some(params.clone()),
+                _ => None,
+            })
+            .or_else(|| in_atoms(vals))
+            .or_else(|| fix_params(body, var)),
+        IR::LetCont(_, _, _, cont_body, body) => {
+            fix_params(cont_body, var).or_else(|| fix_params(body, var))
+        }
+        IR::Let(_, _, _, args, body) => in_atoms(args).or_else(|| fix_params(body, var)),
+        IR::LetVal(_, _, val, body) => {
+            in_atoms(std::slice::from_ref(val)).or_else(|| fix_params(body, var))
+        }
+        IR::If(_, _, then_, else_) => fix_params(then_, var).or_else(|| fix_params(else_, var)),
+        IR::App(_, f, args, _, _) => in_atoms(std::slice::from_ref(f)).or_else(|| in_atoms(args)),
+        IR::AppCont(_, _, args) => in_atoms(args),
+        IR::Case(..) => None,
+    }
+}
+
+#[test]
+pub fn test_uncurry() {
+    let curried = |params: &[&str], body: E| {
+        params
+            .iter()
+            .rev()
+            .fold(body, |body, param| E::lam(&[param], body))
+    };
+    let call = |f: &str, args: Vec<E>| {
+        args.into_iter()
+            .fold(E::v(f), |f, arg| E::app(f, vec![arg]))
+    };
+    let arity = |ir: &IR, var: &str| fix_params(ir, var).map(|params| params.len());
+
+    // add3 x y z = x + y + z, applied to all its arguments
+    let add3 = curried(&["x", "y", "z"], add(add(E::v("x"), E::v("y")), E::v("z")));
+    let prog = E::fix(
+        &["add3"],
+        vec![add3.clone()],
+        call("add3", vec![E::i32(1), E::i32(2), E::i32(3)]),
+    );
+    let ir = quick_cps(prog);
+    assert_eq!(arity(&ir, "add3"), Some(1));
+    let uncurried = uncurry(ir);
+    assert_eq!(arity(&uncurried, "add3"), Some(3));
+    assert_eq!(count(&uncurried, &|ir| matches!(ir, IR::LetCont(..))), 0);
+    assert_eq!(simple_interp(&uncurried), Value::I32(6));
+
+    // the function escapes when bound to another variable, it keeps its lambdas
+    let prog = E::fix(
+        &["add3"],
+        vec![add3],
+        E::let_(
+            "g",
+            E::v("add3"),
+            call("g", vec![E::i32(1), E::i32(2), E::i32(3)]),
+        ),
+    );
+    let ir = quick_cps(prog);
+    assert_eq!(uncurry(ir.clone()), ir);
+
+    // loop i acc = if i >= 10 then acc else loop (i + 1) (acc + i)
+    let geq = |a, b| E::papp(BuiltinOp::I32Geq, vec![a, b]);
+    let loop_ = curried(
+        &["i", "acc"],
+        E::if_(
+            geq(E::v("i"), E::i32(10)),
+            E::v("acc"),
+            call(
+                "loop",
+                vec![add(E::v("i"), E::i32(1)), add(E::v("acc"), E::v("i"))],
+            ),
+        ),
+    );
+    let prog = E::fix(
+        &["loop"],
+        vec![loop_],
+        call("loop", vec![E::i32(0), E::i32(0)]),
+    );
+    let uncurried = uncurry(quick_cps(prog));
+    assert_eq!(arity(&uncurried, "loop"), Some(2));
+    assert_eq!(simple_interp(&uncurried), Value::I32(45));
+
+    // loop p = if p.0 >= 10 then p.1 else loop (p.0 + 1, p.1 + p.0), the pair is flattened
+    let fst = || E::proj(E::v("p"), 0);
+    let snd = || E::proj(E::v("p"), 1);
+    let loop_ = E::lam(
+        &["p"],
+        E::if_(
+            geq(fst(), E::i32(10)),
+            snd(),
+            E::app(
+                E::v("loop"),
+                vec![E::tuple(vec![add(fst(), E::i32(1)), add(snd(), fst())])],
+            ),
+        ),
+    );
+    let prog = E::fix(
+        &["loop"],
+        vec![loop_],
+        E::app(E::v("loop"), vec![E::tuple(vec![E::i32(0), E::i32(0)])]),
+    );
+    let raised = uncurry(quick_cps(prog));
+    let projects = |ir: &IR| matches!(ir, IR::Let(_, _, BuiltinOp::Proj(_), ..));
+    assert_eq!(arity(&raised, "loop"), Some(2));
+    assert_eq!(count(&raised, &projects), 0);
+    assert_eq!(simple_interp(&raised), Value::I32(45));
+
+    // loop p = if p.0 >= 10 then p.1(p.0) else loop (p.0 + 1, p.1), applied to a pair
+    // holding a lambda
+    let loop_ = E::lam(
+        &["p"],
+        E::if_(
+            geq(fst(), E::i32(10)),
+            E::app(snd(), vec![fst()]),
+            E::app(
+                E::v("loop"),
+                vec![E::tuple(vec![add(fst(), E::i32(1)), snd()])],
+            ),
+        ),
+    );
+    let inc = E::lam(&["z"], add(E::v("z"), E::i32(1)));
+    let prog = E::fix(
+        &["loop"],
+        vec![loop_],
+        E::app(E::v("loop"), vec![E::tuple(vec![E::i32(0), inc])]),
+    );
+    let ir = quick_cps(prog);
+    let raised = uncurry(ir.clone());
+    // the lambda is not copied into the call, so no label is repeated
+    let labels = RefCell::new(HashSet::new());
+    let nodes = count(&raised, &|ir| labels.borrow_mut().insert(ir.get_label()));
+    assert_eq!(nodes, count(&raised, &|_| true));
+    assert_eq!(simple_interp(&raised), Value::I32(11));
+}